@@ -1,6 +1,21 @@
+use std::mem;
+
+use blip::BlipBuffer;
+use bus::Irq;
+use cpu::CPU_FREQUENCY;
+use filter::Filter;
+use mapper::expansion_channel;
 use Bus;
 
-const AUDIO_BUFFER_SIZE: u32 = 50 * 1024;
+/// Rate of the samples the APU outputs.
+pub const SAMPLE_RATE: u32 = 44_100;
+/// Mixer output of one pulse volume step in the linear approximation of the
+/// pulse mixer. Cartridge audio is reported in these steps and added to the
+/// mix linearly, which is close to how the boards mix it on the console.
+/// See https://wiki.nesdev.com/w/index.php/APU_Mixer
+const EXPANSION_STEP: f32 = 0.00752;
+/// Most channels a board's sound chip has (the N163's eight).
+const EXPANSION_CHANNELS: usize = 8;
 const LENGTH_TABLE: [u8; 32] = [
     10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
     12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
@@ -11,18 +26,150 @@ const DUTY_TABLE: [[u8; 8]; 4] = [
     [0, 1, 1, 1, 1, 0, 0, 0],
     [1, 0, 0, 1, 1, 1, 1, 1],
 ];
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+// Noise and DMC timer periods in CPU cycles (NTSC)
+const NOISE_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const DMC_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+// Cycles the CPU is halted for while the DMC fetches a sample byte.
+const DMC_STALL_CYCLES: u32 = 4;
+// CPU cycles between moving finished samples to the output buffer
+const BLIP_FRAME_CLOCKS: u32 = 2048;
+// Length of the frame counter sequences in CPU cycles
+const FOUR_STEP_CYCLES: u32 = 29830;
+const FIVE_STEP_CYCLES: u32 = 37282;
+
+/// Inputs of the mixer that can be muted, soloed or turned down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC,
+    // Cartridge audio, by its name in the board's `audio_channels`
+    Expansion(&'static str),
+}
+
+pub const CHANNELS: [Channel; 5] = [
+    Channel::Pulse1,
+    Channel::Pulse2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::DMC,
+];
+
+impl Channel {
+    /// Parse an APU channel name, or a cartridge channel like `vrc6.saw`.
+    pub fn from_name(name: &str) -> Option<Channel> {
+        match name {
+            "pulse1" => Some(Channel::Pulse1),
+            "pulse2" => Some(Channel::Pulse2),
+            "triangle" => Some(Channel::Triangle),
+            "noise" => Some(Channel::Noise),
+            "dmc" => Some(Channel::DMC),
+            _ => expansion_channel(name).map(Channel::Expansion),
+        }
+    }
+}
+
+/// The channels are mixed by a resistor network, which is not linear:
+/// louder channels add less to the output. `pulse` is the sum of the pulse
+/// outputs, `tnd` is 3 * triangle + 2 * noise + DMC.
+/// See https://wiki.nesdev.com/w/index.php/APU_Mixer
+fn pulse_mix(pulse: f32) -> f32 {
+    if pulse <= 0.0 {
+        0.0
+    } else {
+        95.52 / (8128.0 / pulse + 100.0)
+    }
+}
+
+fn tnd_mix(tnd: f32) -> f32 {
+    if tnd <= 0.0 {
+        0.0
+    } else {
+        163.67 / (24329.0 / tnd + 100.0)
+    }
+}
 
 pub struct APU {
     // Cycle counter
-    pub cycle: u32,
-    pub sample_rate: u32
+    pub cycle: u64,
+
+    // Channels
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: DMC,
+
+    // Frame counter
+    pub frame_cycle: u32,
+    pub frame_five_step: bool,
+    pub frame_irq_inhibit: bool,
+    pub frame_reset_delay: Option<u32>,
+    pub frame_reset_five_step: bool,
+
+    // Cartridge audio channels and their levels sampled on the last step
+    pub expansion_names: &'static [&'static str],
+    pub expansion_levels: [f32; EXPANSION_CHANNELS],
+
+    // Mixer controls. Channels without a volume play at 1.0.
+    pub volumes: Vec<(Channel, f32)>,
+    pub muted: Vec<Channel>,
+    pub solo: Option<Channel>,
+
+    // Output
+    pub blip: BlipBuffer,
+    pub blip_clock: u32,
+    pub last_output: f32,
+    pub filters: [Filter; 3],
+    pub buffer: Vec<i16>,
 }
 
 impl APU {
-    pub fn new(sample_rate: u32) -> APU {
+    pub fn new() -> APU {
+        let rate = SAMPLE_RATE as f32;
+
         APU {
             cycle: 0,
-            sample_rate: sample_rate
+            pulse1: Pulse {
+                channel: 1,
+                ..Pulse::default()
+            },
+            pulse2: Pulse {
+                channel: 2,
+                ..Pulse::default()
+            },
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: DMC::new(),
+            frame_cycle: 0,
+            frame_five_step: false,
+            frame_irq_inhibit: false,
+            frame_reset_delay: None,
+            frame_reset_five_step: false,
+            expansion_names: &[],
+            expansion_levels: [0.0; EXPANSION_CHANNELS],
+            volumes: Vec::new(),
+            muted: Vec::new(),
+            solo: None,
+            blip: BlipBuffer::new(CPU_FREQUENCY as f64, SAMPLE_RATE as f64),
+            blip_clock: 0,
+            last_output: 0.0,
+            filters: [
+                Filter::high_pass(rate, 90.0),
+                Filter::high_pass(rate, 440.0),
+                Filter::low_pass(rate, 14_000.0),
+            ],
+            buffer: Vec::new(),
         }
     }
 
@@ -30,34 +177,305 @@ impl APU {
         self.cycle += 1;
     }
 
-    pub fn square_wave(&mut self, bus: &mut Bus, tone_hz: u32) {
-        let period = self.sample_rate / tone_hz;
-        let mut samples = Vec::new();
+    /// Dynamic rate control: make `ratio` times as many samples per CPU
+    /// cycle, to fill or drain the host's audio queue without an audible
+    /// change in pitch.
+    pub fn set_rate_adjustment(&mut self, ratio: f64) {
+        self.blip.set_clock_rate(CPU_FREQUENCY as f64 / ratio);
+    }
+
+    /// Apply the register writes the CPU made since the last call. The
+    /// console calls this on the last cycle of each CPU step, which is the
+    /// cycle stores write on.
+    pub fn write_registers(&mut self, bus: &mut Bus) {
+        let writes = mem::take(&mut bus.apu_writes);
+        for (address, value) in writes {
+            self.write_register(bus, address, value);
+        }
+    }
 
-        for x in 0..AUDIO_BUFFER_SIZE as u32 {
-            samples.push(
-                if (x / period) % 2 == 0 {
-                    1_000
-                } else {
-                    -1_000
+    pub fn write_register(&mut self, bus: &mut Bus, address: u16, value: u8) {
+        match address {
+            0x4000 => self.pulse1.write_control(value),
+            0x4001 => self.pulse1.write_sweep(value),
+            0x4002 => self.pulse1.write_timer_low(value),
+            0x4003 => self.pulse1.write_timer_high(value),
+            0x4004 => self.pulse2.write_control(value),
+            0x4005 => self.pulse2.write_sweep(value),
+            0x4006 => self.pulse2.write_timer_low(value),
+            0x4007 => self.pulse2.write_timer_high(value),
+            0x4008 => self.triangle.write_control(value),
+            0x400A => self.triangle.write_timer_low(value),
+            0x400B => self.triangle.write_timer_high(value),
+            0x400C => self.noise.write_control(value),
+            0x400E => self.noise.write_period(value),
+            0x400F => self.noise.write_length(value),
+            0x4010 => {
+                self.dmc.write_control(value);
+                if !self.dmc.irq_enabled {
+                    bus.irq_line.remove(Irq::DMC);
                 }
-            );
+            }
+            0x4011 => self.dmc.write_value(value),
+            0x4012 => self.dmc.write_address(value),
+            0x4013 => self.dmc.write_length(value),
+            0x4015 => self.write_control(bus, value),
+            0x4017 => self.write_frame_counter(bus, value),
+            _ => {}
+        }
+    }
+
+    /// $4017: select the 4 or 5 step sequence and inhibit the frame IRQ.
+    /// Setting the inhibit flag acknowledges the IRQ right away, but the
+    /// sequencer only restarts 3 or 4 CPU cycles after the write cycle,
+    /// depending on whether the write lands on an APU cycle.
+    /// See https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    fn write_frame_counter(&mut self, bus: &mut Bus, value: u8) {
+        self.frame_irq_inhibit = value & 0x40 == 0x40;
+        if self.frame_irq_inhibit {
+            bus.irq_line.remove(Irq::FRAME_COUNTER);
+        }
+        self.frame_reset_five_step = value & 0x80 == 0x80;
+        // Registers are written before the write cycle is stepped.
+        let write_cycle = self.cycle + 1;
+        self.frame_reset_delay = Some(if write_cycle % 2 == 1 { 4 } else { 3 });
+    }
+
+    /// $4015: enable or disable the channels. Disabled channels have their
+    /// length counters cleared, the DMC restarts its sample if it had
+    /// finished. Also acknowledges the DMC IRQ.
+    fn write_control(&mut self, bus: &mut Bus, value: u8) {
+        self.pulse1.enabled = value & 0x01 == 0x01;
+        self.pulse2.enabled = value & 0x02 == 0x02;
+        self.triangle.enabled = value & 0x04 == 0x04;
+        self.noise.enabled = value & 0x08 == 0x08;
+        if !self.pulse1.enabled {
+            self.pulse1.length_value = 0;
+        }
+        if !self.pulse2.enabled {
+            self.pulse2.length_value = 0;
+        }
+        if !self.triangle.enabled {
+            self.triangle.length_value = 0;
         }
+        if !self.noise.enabled {
+            self.noise.length_value = 0;
+        }
+        if value & 0x10 == 0x10 {
+            if self.dmc.current_length == 0 {
+                self.dmc.restart();
+            }
+        } else {
+            self.dmc.current_length = 0;
+        }
+        bus.irq_line.remove(Irq::DMC);
+    }
 
-        // Push generated samples to the bus
-        // Note(m): Use a separate thread for audio and use Rust channels in the
-        // future.
-        bus.apu_buffer = samples;
+    /// Channel bits of the $4015 status read: set while the length counter
+    /// (or the DMC's remaining byte count) is non-zero. The IRQ bits are
+    /// read from the bus.
+    pub fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length_value > 0 {
+            status |= 0x01;
+        }
+        if self.pulse2.length_value > 0 {
+            status |= 0x02;
+        }
+        if self.triangle.length_value > 0 {
+            status |= 0x04;
+        }
+        if self.noise.length_value > 0 {
+            status |= 0x08;
+        }
+        if self.dmc.current_length > 0 {
+            status |= 0x10;
+        }
+        status
     }
 
     pub fn step(&mut self, bus: &mut Bus) {
         self.tick();
+        self.step_frame_counter(bus);
+
+        // Pulse timers run on every other CPU cycle, the others on every
+        // CPU cycle.
+        if self.cycle & 1 == 0 {
+            self.pulse1.step_timer();
+            self.pulse2.step_timer();
+        }
+        self.triangle.step_timer();
+        self.noise.step_timer();
+        self.dmc.step_reader(bus);
+        self.dmc.step_timer();
+
+        bus.apu_status = self.status();
+        self.expansion_names = bus.mapper.audio_channels();
+        if !self.expansion_names.is_empty() {
+            let count = self.expansion_names.len();
+            bus.mapper.audio_levels(&mut self.expansion_levels[..count]);
+        }
+
+        self.step_output();
+    }
+
+    /// Feed changes of the mixed output to the blip buffer, and move the
+    /// finished samples through the output filters into `buffer`.
+    fn step_output(&mut self) {
+        let output = self.output();
+        if output != self.last_output {
+            self.blip.add_delta(self.blip_clock, output - self.last_output);
+            self.last_output = output;
+        }
+        self.blip_clock += 1;
+        if self.blip_clock < BLIP_FRAME_CLOCKS {
+            return;
+        }
+        self.blip.end_frame(self.blip_clock);
+        self.blip_clock = 0;
+
+        let mut samples = Vec::new();
+        self.blip.read_samples(&mut samples);
+        for sample in samples {
+            let sample = self.filters.iter_mut().fold(sample, |x, filter| filter.step(x));
+            let sample = (sample * 32767.0).clamp(-32768.0, 32767.0);
+            self.buffer.push(sample as i16);
+        }
+    }
+
+    /// Envelopes and the triangle's linear counter.
+    fn step_quarter_frame(&mut self) {
+        self.pulse1.step_envelope();
+        self.pulse2.step_envelope();
+        self.triangle.step_counter();
+        self.noise.step_envelope();
+    }
+
+    /// Length counters and sweep units.
+    fn step_half_frame(&mut self) {
+        self.pulse1.step_length();
+        self.pulse2.step_length();
+        self.triangle.step_length();
+        self.noise.step_length();
+        self.pulse1.step_sweep();
+        self.pulse2.step_sweep();
+    }
+
+    fn set_frame_irq(&mut self, bus: &mut Bus) {
+        if !self.frame_irq_inhibit {
+            bus.irq_line.insert(Irq::FRAME_COUNTER);
+        }
+    }
+
+    /// The frame sequencer clocks the envelopes, length counters and sweeps
+    /// at fixed CPU cycle offsets. The 4 step sequence raises the frame IRQ
+    /// on its last three cycles, the 5 step sequence never does.
+    ///
+    /// ```text
+    /// mode 0:    mode 1:       function
+    /// ---------  -----------  -----------------------------
+    ///  - - - f    - - - - -    IRQ (if bit 6 is clear)
+    ///  - l - l    - l - - l    Length counter and sweep
+    ///  e e e e    e e e - e    Envelope and linear counter
+    /// ```
+    fn step_frame_counter(&mut self, bus: &mut Bus) {
+        if let Some(delay) = self.frame_reset_delay {
+            if delay > 0 {
+                self.frame_reset_delay = Some(delay - 1);
+            } else {
+                // Restarting in 5 step mode clocks everything immediately.
+                self.frame_reset_delay = None;
+                self.frame_five_step = self.frame_reset_five_step;
+                self.frame_cycle = 0;
+                if self.frame_five_step {
+                    self.step_quarter_frame();
+                    self.step_half_frame();
+                }
+            }
+        }
+
+        self.frame_cycle += 1;
+        match (self.frame_five_step, self.frame_cycle) {
+            (_, 7457) | (_, 22371) => self.step_quarter_frame(),
+            (_, 14913) | (true, 37281) => {
+                self.step_quarter_frame();
+                self.step_half_frame();
+            }
+            (false, 29828) => self.set_frame_irq(bus),
+            (false, 29829) => {
+                self.step_quarter_frame();
+                self.step_half_frame();
+                self.set_frame_irq(bus);
+            }
+            (false, FOUR_STEP_CYCLES) => {
+                self.set_frame_irq(bus);
+                self.frame_cycle = 0;
+            }
+            (true, FIVE_STEP_CYCLES) => self.frame_cycle = 0,
+            _ => {}
+        }
+    }
+
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        self.volumes.retain(|&(other, _)| other != channel);
+        self.volumes.push((channel, volume.max(0.0)));
+    }
+
+    pub fn set_mute(&mut self, channel: Channel, muted: bool) {
+        self.muted.retain(|&other| other != channel);
+        if muted {
+            self.muted.push(channel);
+        }
+    }
 
-        // NOTE(m): Comment this out to silence output!
-        // self.square_wave(bus, 440);
+    pub fn toggle_mute(&mut self, channel: Channel) {
+        let muted = self.muted.contains(&channel);
+        self.set_mute(channel, !muted);
+    }
+
+    /// Solo a channel, or go back to hearing all of them if it already was.
+    pub fn toggle_solo(&mut self, channel: Channel) {
+        self.solo = if self.solo == Some(channel) {
+            None
+        } else {
+            Some(channel)
+        };
+    }
+
+    /// Volume a channel is mixed at, taking mute and solo into account.
+    pub fn gain(&self, channel: Channel) -> f32 {
+        let silenced = match self.solo {
+            Some(solo) => solo != channel,
+            None => self.muted.contains(&channel),
+        };
+        if silenced {
+            return 0.0;
+        }
+        self.volumes
+            .iter()
+            .find(|&&(other, _)| other == channel)
+            .map_or(1.0, |&(_, volume)| volume)
+    }
+
+    /// Mixed output level, 0.0 is silence.
+    pub fn output(&self) -> f32 {
+        let pulse = self.pulse1.output() as f32 * self.gain(Channel::Pulse1)
+            + self.pulse2.output() as f32 * self.gain(Channel::Pulse2);
+        let tnd = 3.0 * self.triangle.output() as f32 * self.gain(Channel::Triangle)
+            + 2.0 * self.noise.output() as f32 * self.gain(Channel::Noise)
+            + self.dmc.output() as f32 * self.gain(Channel::DMC);
+        let expansion: f32 = self
+            .expansion_names
+            .iter()
+            .zip(self.expansion_levels.iter())
+            .map(|(&name, &level)| level * self.gain(Channel::Expansion(name)))
+            .sum();
+        pulse_mix(pulse) + tnd_mix(tnd) + expansion * EXPANSION_STEP
     }
 }
 
+/// See https://wiki.nesdev.com/w/index.php/APU_Pulse
+#[derive(Default)]
 pub struct Pulse {
     pub enabled: bool,
     pub channel: u8,
@@ -90,12 +508,11 @@ impl Pulse {
         self.envelope_enabled = (value >> 4) & 1 == 0;
         self.envelope_period = value & 15;
         self.constant_volume = value & 15;
-        self.envelope_start = true;
     }
 
     pub fn write_sweep(&mut self, value: u8) {
         self.sweep_enabled = (value >> 7) & 1 == 1;
-        self.sweep_period = (value >> 4) & 7 + 1;
+        self.sweep_period = (value >> 4) & 7;
         self.sweep_negate = (value >> 3) & 1 == 1;
         self.sweep_shift = value & 7;
         self.sweep_reload = true;
@@ -106,7 +523,9 @@ impl Pulse {
     }
 
     pub fn write_timer_high(&mut self, value: u8) {
-        self.length_value = LENGTH_TABLE[(value >> 3) as usize];
+        if self.enabled {
+            self.length_value = LENGTH_TABLE[(value >> 3) as usize];
+        }
         self.timer_period = (self.timer_period & 0x00FF) | (((value & 7) as u16) << 8);
         self.envelope_start = true;
         self.duty_value = 0;
@@ -117,7 +536,7 @@ impl Pulse {
             self.timer_value = self.timer_period;
             self.duty_value = (self.duty_value + 1) % 8;
         } else {
-            self.timer_value = self.timer_value - 1;
+            self.timer_value -= 1;
         }
     }
 
@@ -127,10 +546,10 @@ impl Pulse {
             self.envelope_value = self.envelope_period;
             self.envelope_start = false;
         } else if self.envelope_value > 0 {
-            self.envelope_value = self.envelope_value - 1;
+            self.envelope_value -= 1;
         } else {
             if self.envelope_volume > 0 {
-                self.envelope_volume = self.envelope_volume - 1;
+                self.envelope_volume -= 1;
             } else if self.envelope_loop {
                 self.envelope_volume = 15;
             }
@@ -138,42 +557,53 @@ impl Pulse {
         }
     }
 
+    /// The sweep divider adjusts the period when it reaches zero, then
+    /// reloads. Writing $4001/$4005 reloads it on the next half frame.
     pub fn step_sweep(&mut self) {
-        if self.sweep_reload {
-            if self.sweep_enabled && self.sweep_value == 0 {
-                self.sweep();
-            }
+        if self.sweep_value == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_value == 0 || self.sweep_reload {
             self.sweep_value = self.sweep_period;
             self.sweep_reload = false;
-        } else if self.sweep_value > 0 {
-            self.sweep_value = self.sweep_value - 1;
         } else {
-            if self.sweep_enabled {
-                self.sweep();
-            }
-            self.sweep_value = self.sweep_period;
+            self.sweep_value -= 1;
         }
     }
 
     pub fn step_length(&mut self) {
         if self.length_enabled && self.length_value > 0 {
-            self.length_value = self.length_value - 1;
+            self.length_value -= 1;
         }
     }
 
-    pub fn sweep(&mut self) {
+    /// The period the sweep unit would change to. Pulse 1 negates with
+    /// ones' complement, so it subtracts one more than pulse 2.
+    pub fn sweep_target(&self) -> u16 {
         let delta = self.timer_period >> self.sweep_shift;
         if self.sweep_negate {
-            self.timer_period = self.timer_period - delta;
+            let target = self.timer_period.saturating_sub(delta);
             if self.channel == 1 {
-                self.timer_period = self.timer_period - 1;
+                target.saturating_sub(1)
+            } else {
+                target
             }
         } else {
-            self.timer_period = self.timer_period + delta;
+            self.timer_period + delta
+        }
+    }
+
+    /// The sweep unit silences the channel when the period is too small or
+    /// its target overflows, even while sweeping is disabled. Channel 0 is
+    /// a pulse without a sweep unit (MMC5), which is never muted.
+    pub fn muted(&self) -> bool {
+        if self.channel == 0 {
+            return false;
         }
+        self.timer_period < 8 || (!self.sweep_negate && self.sweep_target() > 0x7FF)
     }
 
-    pub fn output(&mut self) -> u8 {
+    pub fn output(&self) -> u8 {
         if !self.enabled {
             return 0;
         }
@@ -183,7 +613,7 @@ impl Pulse {
         if DUTY_TABLE[self.duty_mode as usize][self.duty_value as usize] == 0 {
             return 0;
         }
-        if self.timer_period < 8 || self.timer_period > 0x7FF {
+        if self.muted() {
             return 0;
         }
         if self.envelope_enabled {
@@ -193,3 +623,598 @@ impl Pulse {
         }
     }
 }
+
+/// See https://wiki.nesdev.com/w/index.php/APU_Triangle
+#[derive(Default)]
+pub struct Triangle {
+    pub enabled: bool,
+    pub length_enabled: bool,
+    pub length_value: u8,
+    pub timer_period: u16,
+    pub timer_value: u16,
+    pub duty_value: u8,
+    pub counter_period: u8,
+    pub counter_value: u8,
+    pub counter_reload: bool,
+}
+
+impl Triangle {
+    /// Bit 7 both halts the length counter and keeps the linear counter
+    /// reloading.
+    pub fn write_control(&mut self, value: u8) {
+        self.length_enabled = (value >> 7) & 1 == 0;
+        self.counter_period = value & 0x7F;
+    }
+
+    pub fn write_timer_low(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0xFF00) | value as u16;
+    }
+
+    pub fn write_timer_high(&mut self, value: u8) {
+        if self.enabled {
+            self.length_value = LENGTH_TABLE[(value >> 3) as usize];
+        }
+        self.timer_period = (self.timer_period & 0x00FF) | (((value & 7) as u16) << 8);
+        self.counter_reload = true;
+    }
+
+    /// The sequencer only advances while both counters are non-zero, so a
+    /// silenced triangle holds its last output level instead of popping.
+    pub fn step_timer(&mut self) {
+        if self.timer_value == 0 {
+            self.timer_value = self.timer_period;
+            if self.length_value > 0 && self.counter_value > 0 {
+                self.duty_value = (self.duty_value + 1) % 32;
+            }
+        } else {
+            self.timer_value -= 1;
+        }
+    }
+
+    pub fn step_counter(&mut self) {
+        if self.counter_reload {
+            self.counter_value = self.counter_period;
+        } else if self.counter_value > 0 {
+            self.counter_value -= 1;
+        }
+        if self.length_enabled {
+            self.counter_reload = false;
+        }
+    }
+
+    pub fn step_length(&mut self) {
+        if self.length_enabled && self.length_value > 0 {
+            self.length_value -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.duty_value as usize]
+    }
+}
+
+/// See https://wiki.nesdev.com/w/index.php/APU_Noise
+pub struct Noise {
+    pub enabled: bool,
+    pub mode: bool,
+    pub shift_register: u16,
+    pub length_enabled: bool,
+    pub length_value: u8,
+    pub timer_period: u16,
+    pub timer_value: u16,
+    pub envelope_enabled: bool,
+    pub envelope_loop: bool,
+    pub envelope_start: bool,
+    pub envelope_period: u8,
+    pub envelope_value: u8,
+    pub envelope_volume: u8,
+    pub constant_volume: u8,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            enabled: false,
+            mode: false,
+            shift_register: 1,
+            length_enabled: false,
+            length_value: 0,
+            timer_period: NOISE_TABLE[0],
+            timer_value: 0,
+            envelope_enabled: false,
+            envelope_loop: false,
+            envelope_start: false,
+            envelope_period: 0,
+            envelope_value: 0,
+            envelope_volume: 0,
+            constant_volume: 0,
+        }
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.length_enabled = (value >> 5) & 1 == 0;
+        self.envelope_loop = (value >> 5) & 1 == 1;
+        self.envelope_enabled = (value >> 4) & 1 == 0;
+        self.envelope_period = value & 15;
+        self.constant_volume = value & 15;
+    }
+
+    pub fn write_period(&mut self, value: u8) {
+        self.mode = value & 0x80 == 0x80;
+        self.timer_period = NOISE_TABLE[(value & 0x0F) as usize];
+    }
+
+    pub fn write_length(&mut self, value: u8) {
+        if self.enabled {
+            self.length_value = LENGTH_TABLE[(value >> 3) as usize];
+        }
+        self.envelope_start = true;
+    }
+
+    /// Clocks the 15-bit shift register. Feedback comes from bit 1, or bit
+    /// 6 in mode 1, which gives a short 93 step sequence that sounds metallic.
+    pub fn step_timer(&mut self) {
+        if self.timer_value == 0 {
+            self.timer_value = self.timer_period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer_value -= 1;
+        }
+    }
+
+    pub fn step_envelope(&mut self) {
+        if self.envelope_start {
+            self.envelope_volume = 15;
+            self.envelope_value = self.envelope_period;
+            self.envelope_start = false;
+        } else if self.envelope_value > 0 {
+            self.envelope_value -= 1;
+        } else {
+            if self.envelope_volume > 0 {
+                self.envelope_volume -= 1;
+            } else if self.envelope_loop {
+                self.envelope_volume = 15;
+            }
+            self.envelope_value = self.envelope_period;
+        }
+    }
+
+    pub fn step_length(&mut self) {
+        if self.length_enabled && self.length_value > 0 {
+            self.length_value -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        if self.length_value == 0 {
+            return 0;
+        }
+        if self.shift_register & 1 == 1 {
+            return 0;
+        }
+        if self.envelope_enabled {
+            self.envelope_volume
+        } else {
+            self.constant_volume
+        }
+    }
+}
+
+/// Delta modulation channel: plays 1-bit delta encoded samples that it
+/// fetches from CPU memory on its own, stealing cycles from the CPU, and
+/// can raise an IRQ when a sample ends.
+///
+/// See https://wiki.nesdev.com/w/index.php/APU_DMC
+pub struct DMC {
+    pub irq_enabled: bool,
+    pub loop_flag: bool,
+    pub timer_period: u16,
+    pub timer_value: u16,
+    pub value: u8,
+
+    // Memory reader
+    pub sample_address: u16,
+    pub sample_length: u16,
+    pub current_address: u16,
+    pub current_length: u16,
+    pub sample_buffer: Option<u8>,
+
+    // Output unit
+    pub shift_register: u8,
+    pub bit_count: u8,
+    pub silence: bool,
+}
+
+impl DMC {
+    pub fn new() -> DMC {
+        DMC {
+            irq_enabled: false,
+            loop_flag: false,
+            timer_period: DMC_TABLE[0],
+            timer_value: 0,
+            value: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            current_length: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bit_count: 8,
+            silence: true,
+        }
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.irq_enabled = value & 0x80 == 0x80;
+        self.loop_flag = value & 0x40 == 0x40;
+        self.timer_period = DMC_TABLE[(value & 0x0F) as usize];
+    }
+
+    /// $4011: load the output level directly.
+    pub fn write_value(&mut self, value: u8) {
+        self.value = value & 0x7F;
+    }
+
+    /// $4012: sample address %11AAAAAA.AA000000
+    pub fn write_address(&mut self, value: u8) {
+        self.sample_address = 0xC000 | (value as u16) << 6;
+    }
+
+    /// $4013: sample length %LLLL.LLLL0001
+    pub fn write_length(&mut self, value: u8) {
+        self.sample_length = (value as u16) << 4 | 1;
+    }
+
+    pub fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.current_length = self.sample_length;
+    }
+
+    /// Fill the sample buffer when it is empty and bytes remain. The fetch
+    /// halts the CPU for a few cycles.
+    pub fn step_reader(&mut self, bus: &mut Bus) {
+        if self.sample_buffer.is_some() || self.current_length == 0 {
+            return;
+        }
+        bus.dmc_stall += DMC_STALL_CYCLES;
+        self.sample_buffer = Some(bus.read(self.current_address));
+        // The address wraps around to $8000.
+        self.current_address = self.current_address.wrapping_add(1) | 0x8000;
+        self.current_length -= 1;
+        if self.current_length == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                bus.irq_line.insert(Irq::DMC);
+            }
+        }
+    }
+
+    pub fn step_timer(&mut self) {
+        if self.timer_value == 0 {
+            self.timer_value = self.timer_period - 1;
+            self.step_shifter();
+        } else {
+            self.timer_value -= 1;
+        }
+    }
+
+    /// Move the output level up or down by two for each bit, then start
+    /// the next byte after eight bits.
+    fn step_shifter(&mut self) {
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.value <= 125 {
+                    self.value += 2;
+                }
+            } else if self.value >= 2 {
+                self.value -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bit_count -= 1;
+        if self.bit_count == 0 {
+            self.bit_count = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.shift_register = value;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::test_bus;
+
+    fn write(apu: &mut APU, bus: &mut Bus, address: u16, value: u8) {
+        bus.write(address, value);
+        apu.write_registers(bus);
+        apu.step(bus);
+    }
+
+    #[test]
+    fn it_reports_length_counters() {
+        let mut apu = APU::new();
+        let mut bus = test_bus();
+
+        // Length counters only load while the channel is enabled.
+        write(&mut apu, &mut bus, 0x4003, 0x08);
+        assert_eq!(bus.read(0x4015) & 0x1F, 0x00);
+
+        write(&mut apu, &mut bus, 0x4015, 0x0F);
+        write(&mut apu, &mut bus, 0x4003, 0x08);
+        write(&mut apu, &mut bus, 0x400B, 0x08);
+        assert_eq!(bus.read(0x4015) & 0x1F, 0x05);
+        assert_eq!(apu.pulse1.length_value, 254);
+
+        write(&mut apu, &mut bus, 0x4015, 0x04);
+        assert_eq!(bus.read(0x4015) & 0x1F, 0x04);
+    }
+
+    #[test]
+    fn it_fetches_dmc_samples() {
+        let mut apu = APU::new();
+        let mut bus = test_bus();
+
+        // A one byte sample at $C000 with the IRQ enabled.
+        write(&mut apu, &mut bus, 0x4010, 0x8F);
+        write(&mut apu, &mut bus, 0x4012, 0x00);
+        write(&mut apu, &mut bus, 0x4013, 0x00);
+        write(&mut apu, &mut bus, 0x4015, 0x10);
+        assert_eq!(apu.dmc.sample_buffer, Some(0x01));
+        assert_eq!(bus.dmc_stall, DMC_STALL_CYCLES);
+        assert_eq!(bus.read(0x4015), 0x80);
+
+        // Writing $4015 acknowledges the IRQ.
+        write(&mut apu, &mut bus, 0x4015, 0x00);
+        assert!(!bus.irq_asserted());
+    }
+
+    #[test]
+    fn it_mutes_and_solos_channels() {
+        let mut apu = APU::new();
+        let mut bus = test_bus();
+        // Pulse 1 at constant volume 15 in a high step of the 75% duty
+        // cycle, triangle with its sequencer at 15.
+        write(&mut apu, &mut bus, 0x4015, 0x05);
+        write(&mut apu, &mut bus, 0x4000, 0xDF);
+        write(&mut apu, &mut bus, 0x4002, 0xFF);
+        write(&mut apu, &mut bus, 0x4003, 0x08);
+        write(&mut apu, &mut bus, 0x400B, 0x08);
+        apu.pulse1.duty_value = 0;
+        let pulse = pulse_mix(15.0);
+        let triangle = tnd_mix(45.0);
+        assert_eq!(apu.output(), pulse + triangle);
+
+        apu.toggle_mute(Channel::Pulse1);
+        assert_eq!(apu.output(), triangle);
+        apu.toggle_mute(Channel::Pulse1);
+        apu.set_volume(Channel::Triangle, 0.5);
+        assert_eq!(apu.output(), pulse + tnd_mix(22.5));
+
+        // Soloing overrides mutes.
+        apu.toggle_mute(Channel::Pulse1);
+        apu.toggle_solo(Channel::Pulse1);
+        assert_eq!(apu.output(), pulse);
+        apu.toggle_solo(Channel::Pulse1);
+        assert_eq!(apu.output(), tnd_mix(22.5));
+
+        // Cartridge channels are controlled one by one.
+        apu.toggle_mute(Channel::Pulse1);
+        apu.set_volume(Channel::Triangle, 1.0);
+        let saw = Channel::from_name("vrc6.saw").unwrap();
+        assert_eq!(saw, Channel::Expansion("vrc6.saw"));
+        apu.expansion_names = &["vrc6.pulse1", "vrc6.pulse2", "vrc6.saw"];
+        apu.expansion_levels[..3].copy_from_slice(&[10.0, 0.0, 20.0]);
+        let base = pulse + triangle;
+        assert_eq!(apu.output(), base + 30.0 * EXPANSION_STEP);
+        apu.toggle_mute(saw);
+        assert_eq!(apu.output(), base + 10.0 * EXPANSION_STEP);
+        apu.toggle_mute(saw);
+        apu.set_volume(Channel::Expansion("vrc6.pulse1"), 0.5);
+        assert_eq!(apu.output(), base + 25.0 * EXPANSION_STEP);
+        apu.toggle_solo(saw);
+        assert_eq!(apu.output(), 20.0 * EXPANSION_STEP);
+
+        // Another chip's channel in the same position is a different one.
+        apu.toggle_solo(saw);
+        apu.set_mute(Channel::from_name("mmc5.pcm").unwrap(), true);
+        assert_eq!(apu.output(), base + 25.0 * EXPANSION_STEP);
+        // Muting is idempotent, toggling is not.
+        apu.set_mute(saw, true);
+        apu.set_mute(saw, true);
+        assert_eq!(apu.output(), base + 5.0 * EXPANSION_STEP);
+        apu.toggle_mute(saw);
+        assert_eq!(apu.output(), base + 25.0 * EXPANSION_STEP);
+    }
+
+    /// Steps until the frame IRQ is raised, at most one 4 step sequence.
+    fn cycles_until_frame_irq(apu: &mut APU, bus: &mut Bus) -> Option<u32> {
+        for cycle in 1..FOUR_STEP_CYCLES + 8 {
+            apu.step(bus);
+            if bus.irq_line.contains(Irq::FRAME_COUNTER) {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn it_raises_the_frame_irq() {
+        let mut apu = APU::new();
+        let mut bus = test_bus();
+
+        // Written on an odd cycle, the sequencer restarts 4 cycles later.
+        write(&mut apu, &mut bus, 0x4017, 0x00);
+        assert_eq!(cycles_until_frame_irq(&mut apu, &mut bus), Some(29831));
+
+        // Reading $4015 acknowledges the IRQ, but the flag is set again
+        // on the next two cycles.
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40);
+        assert_eq!(bus.read(0x4015) & 0x40, 0x00);
+        apu.step(&mut bus);
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40);
+        apu.step(&mut bus);
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40);
+        apu.step(&mut bus);
+        assert_eq!(bus.read(0x4015) & 0x40, 0x00);
+
+        // The inhibit flag clears the IRQ and keeps it from being set.
+        write(&mut apu, &mut bus, 0x4017, 0x40);
+        assert_eq!(cycles_until_frame_irq(&mut apu, &mut bus), None);
+
+        // 5 step mode never raises it.
+        write(&mut apu, &mut bus, 0x4017, 0x80);
+        assert_eq!(cycles_until_frame_irq(&mut apu, &mut bus), None);
+    }
+
+    /// Steps `cycles` cycles and lists the cycles, counted from 1, on which
+    /// the sequencer clocked the triangle's linear counter (quarter frames,
+    /// 'e') and pulse 1's length counter (half frames, 'l').
+    fn frame_sequence(apu: &mut APU, bus: &mut Bus, cycles: u32) -> Vec<(u32, char)> {
+        apu.pulse1.length_enabled = true;
+        apu.pulse1.length_value = 0xFF;
+        apu.triangle.counter_reload = false;
+        apu.triangle.counter_value = 0x7F;
+        let mut steps = Vec::new();
+        for cycle in 1..cycles + 1 {
+            let (counter, length) = (apu.triangle.counter_value, apu.pulse1.length_value);
+            apu.step(bus);
+            if apu.triangle.counter_value != counter {
+                steps.push((cycle, 'e'));
+            }
+            if apu.pulse1.length_value != length {
+                steps.push((cycle, 'l'));
+            }
+        }
+        steps
+    }
+
+    #[test]
+    fn it_steps_the_frame_sequences() {
+        let mut apu = APU::new();
+        let mut bus = test_bus();
+        write(&mut apu, &mut bus, 0x4015, 0x01);
+        apu.step(&mut bus);
+
+        // Written on an odd cycle, the 4 step sequence restarts on the 4th
+        // cycle after the write, which is cycle 1 of the sequence.
+        assert_eq!(apu.cycle % 2, 0);
+        write(&mut apu, &mut bus, 0x4017, 0x00);
+        let restart = 3;
+        assert_eq!(
+            frame_sequence(&mut apu, &mut bus, 29830 + 7457 + restart),
+            vec![
+                (restart + 7457, 'e'),
+                (restart + 14913, 'e'),
+                (restart + 14913, 'l'),
+                (restart + 22371, 'e'),
+                (restart + 29829, 'e'),
+                (restart + 29829, 'l'),
+                (restart + 29830 + 7457, 'e'),
+            ]
+        );
+
+        // Acknowledge the IRQ of the 4 step sequence.
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40);
+
+        // Written on an even cycle, the 5 step sequence restarts on the 3rd
+        // cycle after the write and clocks everything right away.
+        if apu.cycle & 1 == 0 {
+            apu.step(&mut bus);
+        }
+        write(&mut apu, &mut bus, 0x4017, 0x80);
+        let restart = 2;
+        assert_eq!(
+            frame_sequence(&mut apu, &mut bus, 37282 + 7457 + restart),
+            vec![
+                (restart + 1, 'e'),
+                (restart + 1, 'l'),
+                (restart + 7457, 'e'),
+                (restart + 14913, 'e'),
+                (restart + 14913, 'l'),
+                (restart + 22371, 'e'),
+                (restart + 37281, 'e'),
+                (restart + 37281, 'l'),
+                (restart + 37282 + 7457, 'e'),
+            ]
+        );
+        assert!(!bus.irq_line.contains(Irq::FRAME_COUNTER));
+    }
+
+    #[test]
+    fn it_inhibits_and_acknowledges_the_frame_irq() {
+        let mut apu = APU::new();
+        let mut bus = test_bus();
+        write(&mut apu, &mut bus, 0x4017, 0x00);
+        assert_eq!(cycles_until_frame_irq(&mut apu, &mut bus), Some(29831));
+        for _ in 0..2 {
+            apu.step(&mut bus);
+        }
+
+        // Writing $4015 only acknowledges the DMC IRQ, and restarting the
+        // sequence without the inhibit flag leaves the flag set.
+        write(&mut apu, &mut bus, 0x4015, 0x00);
+        write(&mut apu, &mut bus, 0x4017, 0x00);
+        assert!(bus.irq_asserted());
+        // Reading $4015 acknowledges it.
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40);
+        assert!(!bus.irq_asserted());
+        assert_eq!(bus.read(0x4015) & 0x40, 0x00);
+
+        // The restarted sequence raises it again. Setting the inhibit flag
+        // clears it on the write cycle, before the sequencer restarts.
+        assert_eq!(cycles_until_frame_irq(&mut apu, &mut bus), Some(29830));
+        bus.write(0x4017, 0x40);
+        apu.write_registers(&mut bus);
+        assert!(!bus.irq_asserted());
+        assert_eq!(cycles_until_frame_irq(&mut apu, &mut bus), None);
+
+        // Clearing it again lets the next sequence raise the IRQ.
+        write(&mut apu, &mut bus, 0x4017, 0x00);
+        assert!(cycles_until_frame_irq(&mut apu, &mut bus).is_some());
+    }
+
+    #[test]
+    fn it_clocks_length_counters() {
+        let mut apu = APU::new();
+        let mut bus = test_bus();
+        write(&mut apu, &mut bus, 0x4015, 0x01);
+        write(&mut apu, &mut bus, 0x4000, 0x00);
+        write(&mut apu, &mut bus, 0x4003, 0x18);
+        assert_eq!(apu.pulse1.length_value, 2);
+
+        // Switching to 5 step mode clocks the length counter when the
+        // sequencer restarts, 3 cycles after a write on an even cycle.
+        write(&mut apu, &mut bus, 0x4017, 0x80);
+        assert_eq!(apu.pulse1.length_value, 2);
+        for _ in 0..2 {
+            apu.step(&mut bus);
+        }
+        assert_eq!(apu.pulse1.length_value, 2);
+        apu.step(&mut bus);
+        assert_eq!(apu.pulse1.length_value, 1);
+
+        // The next half frame is 14913 cycles after the restart.
+        for _ in 0..14911 {
+            apu.step(&mut bus);
+        }
+        assert_eq!(bus.read(0x4015) & 0x01, 0x01);
+        apu.step(&mut bus);
+        assert_eq!(bus.read(0x4015) & 0x01, 0x00);
+    }
+}
@@ -0,0 +1,155 @@
+use std::f64::consts::PI;
+
+/// Sub-sample positions the step kernel is precomputed for.
+const PHASES: usize = 32;
+/// Output samples each step is spread over.
+const KERNEL_WIDTH: usize = 16;
+/// Kernel cutoff as a fraction of the output sample rate, a little below
+/// the Nyquist frequency.
+const CUTOFF: f64 = 0.45;
+
+/// Band-limited synthesis for signals that change in steps, after Shay
+/// Green's Blip_Buffer. Instead of sampling the signal, every change is
+/// added as a delta to the output samples around it, shaped by a windowed
+/// sinc kernel. Summing up the deltas gives a band-limited version of the
+/// signal, without the aliasing that plain decimation would cause.
+///
+/// Time is counted in clocks of the source (CPU cycles for the APU) since
+/// the end of the last frame.
+///
+/// See http://slack.net/~ant/bl-synth/
+pub struct BlipBuffer {
+    pub clock_rate: f64,
+    pub sample_rate: f64,
+    // Output samples per clock
+    pub factor: f64,
+    // Factor for the next frame, after a change of clock rate
+    pub next_factor: f64,
+    // Position of clock 0 in the buffer, in samples
+    pub offset: f64,
+    pub buffer: Vec<f32>,
+    pub integrator: f32,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> BlipBuffer {
+        BlipBuffer {
+            clock_rate,
+            sample_rate,
+            factor: sample_rate / clock_rate,
+            next_factor: sample_rate / clock_rate,
+            offset: 0.0,
+            buffer: Vec::new(),
+            integrator: 0.0,
+            kernel: step_kernel(),
+        }
+    }
+
+    /// Change the clock rate, which changes the number of samples made per
+    /// clock. Takes effect for the next frame.
+    pub fn set_clock_rate(&mut self, clock_rate: f64) {
+        self.clock_rate = clock_rate;
+        self.next_factor = self.sample_rate / clock_rate;
+    }
+
+    /// Add a change in amplitude at the given clock of the current frame.
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let time = self.offset + clock as f64 * self.factor;
+        let index = time as usize;
+        let phase = ((time - index as f64) * PHASES as f64) as usize;
+        if self.buffer.len() < index + KERNEL_WIDTH {
+            self.buffer.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (sample, weight) in self.buffer[index..].iter_mut().zip(self.kernel[phase].iter()) {
+            *sample += delta * weight;
+        }
+    }
+
+    /// End the current frame after the given number of clocks. Samples
+    /// before the end of the frame are complete and can be read.
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.offset += clocks as f64 * self.factor;
+        self.factor = self.next_factor;
+    }
+
+    pub fn samples_available(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Move the finished samples to `out`.
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.samples_available();
+        if self.buffer.len() < count {
+            self.buffer.resize(count, 0.0);
+        }
+        for delta in self.buffer.drain(..count) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.offset -= count as f64;
+    }
+}
+
+/// Blackman windowed sinc impulses, one per phase, each normalized so a
+/// delta adds up to exactly its size.
+fn step_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let half_width = (KERNEL_WIDTH / 2) as f64;
+    let mut kernel = vec![[0.0; KERNEL_WIDTH]; PHASES];
+    for (phase, impulse) in kernel.iter_mut().enumerate() {
+        let center = half_width - 1.0 + phase as f64 / PHASES as f64;
+        let mut weights = [0.0; KERNEL_WIDTH];
+        for (i, weight) in weights.iter_mut().enumerate() {
+            let t = i as f64 - center;
+            let x = 2.0 * CUTOFF * t;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            let window = if t.abs() >= half_width {
+                0.0
+            } else {
+                let w = PI * t / half_width;
+                0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos()
+            };
+            *weight = sinc * window;
+        }
+        let sum: f64 = weights.iter().sum();
+        for (value, weight) in impulse.iter_mut().zip(weights.iter()) {
+            *value = (weight / sum) as f32;
+        }
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_synthesizes_steps() {
+        let mut blip = BlipBuffer::new(1_789_773.0, 44_100.0);
+        blip.add_delta(100, 1.0);
+        blip.add_delta(20_000, -0.5);
+        blip.end_frame(29_830);
+
+        let mut samples = Vec::new();
+        blip.read_samples(&mut samples);
+        assert_eq!(samples.len(), 735);
+
+        // The steps ring a little around their position and settle on the
+        // new level.
+        assert!(samples[0].abs() < 0.01);
+        assert!((samples[100] - 1.0).abs() < 0.001);
+        assert!((samples[734] - 0.5).abs() < 0.001);
+        let peak = samples.iter().cloned().fold(0.0, f32::max);
+        assert!(peak > 1.0 && peak < 1.15);
+    }
+
+    #[test]
+    fn it_changes_the_clock_rate_between_frames() {
+        let mut blip = BlipBuffer::new(1_789_773.0, 44_100.0);
+        blip.set_clock_rate(1_662_607.0);
+        blip.end_frame(29_830);
+        assert_eq!(blip.samples_available(), 735);
+        blip.end_frame(33_247);
+        assert_eq!(blip.samples_available(), 735 + 881);
+    }
+}
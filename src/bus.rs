@@ -1,53 +1,193 @@
-use cartridge::Cartridge;
+use device::{new_device, Device, DeviceKind, Expansion, Input, Video};
+use mapper::Mapper;
+use ppu::{Control, Mask, Status};
 
 pub const BUFFER_WIDTH: usize = 256;
 pub const BUFFER_HEIGHT: usize = 240;
 
+bitflags! {
+    /// Devices that can pull the shared /IRQ line low.
+    #[derive(Default)]
+    pub struct Irq: u8 {
+        const FRAME_COUNTER = 1 << 0;
+        const DMC           = 1 << 1;
+    }
+}
+
 pub struct Bus {
-    pub cartridge: Cartridge,
+    pub mapper: Box<dyn Mapper>,
     pub ram: Vec<u8>,
     pub apu_registers: [u8; 22],
-    pub ppu_name_table: [u8; 2048],
+    pub ppu_name_table: [u8; 4096], // 2 KiB console VRAM + 2 KiB for four-screen boards
     pub ppu_palette: [u8; 32],
     pub ppu_oam: [u8; 256],
     pub ppu_pixels: Vec<u32>,
-    pub apu_buffer: Vec<i16>,
+
+    // APU register writes waiting for the APU to catch up, and the channel
+    // bits of $4015 as of the last APU step
+    pub apu_writes: Vec<(u16, u8)>,
+    pub apu_status: u8,
+
+    // Devices plugged into the controller ports
+    pub port1: Box<dyn Device>,
+    pub port2: Box<dyn Device>,
+    // Device on the Famicom expansion port
+    pub expansion: Option<Box<dyn Expansion>>,
+    // Last value driven on the CPU data bus. Bits that a read leaves
+    // undriven keep this value.
+    pub open_bus: u8,
+
+    // PPU registers
+    pub ppu_ctrl: Control,
+    pub ppu_mask: Mask,
+    pub ppu_status: Status,
+    pub ppu_oam_address: u8,
+    pub ppu_data_buffer: u8,
+    pub ppu_open_bus: u8,
+    // Beam position, for devices that watch the picture being drawn
+    pub ppu_scan_line: u32,
+    pub ppu_cycle: u32,
+
+    // Internal PPU scrolling registers ("loopy" registers)
+    // See https://wiki.nesdev.com/w/index.php/PPU_scrolling
+    pub ppu_v: u16, // Current VRAM address (15 bits)
+    pub ppu_t: u16, // Temporary VRAM address (15 bits)
+    pub ppu_x: u8,  // Fine X scroll (3 bits)
+    pub ppu_w: bool, // Write toggle shared by $2005 and $2006
+
+    // Page written to OAMDMA ($4014), waiting for the CPU to run the DMA
+    pub oam_dma_page: Option<u8>,
+    // Cycles the DMC's sample fetches have taken from the CPU
+    pub dmc_stall: u32,
+
+    // Interrupt lines
+    pub nmi_line: bool,
+    pub irq_line: Irq,
 }
 
 impl Bus {
-    pub fn new(cartridge: Cartridge, ram: Vec<u8>) -> Bus {
+    pub fn new(mapper: Box<dyn Mapper>, ram: Vec<u8>) -> Bus {
         Bus {
-            cartridge,
+            mapper,
             ram,
             apu_registers: [0; 22],
-            ppu_name_table: [0; 2048],
+            ppu_name_table: [0; 4096],
             ppu_palette: [0; 32],
             ppu_oam: [0; 256],
             ppu_pixels: vec![0; BUFFER_WIDTH * BUFFER_HEIGHT],
-            apu_buffer: Vec::new(),
+            apu_writes: Vec::new(),
+            apu_status: 0,
+            port1: new_device(DeviceKind::Controller, 0),
+            port2: new_device(DeviceKind::Controller, 1),
+            expansion: None,
+            open_bus: 0,
+            ppu_ctrl: Control::empty(),
+            ppu_mask: Mask::empty(),
+            ppu_status: Status::empty(),
+            ppu_oam_address: 0,
+            ppu_data_buffer: 0,
+            ppu_open_bus: 0,
+            ppu_scan_line: 0,
+            ppu_cycle: 0,
+            ppu_v: 0,
+            ppu_t: 0,
+            ppu_x: 0,
+            ppu_w: false,
+            oam_dma_page: None,
+            dmc_stall: 0,
+            nmi_line: false,
+            irq_line: Irq::empty(),
         }
     }
 
-    pub fn read(&self, address: u16) -> u8 {
-        match address {
-            0x0000...0x1FFF => self.ram[(address % 0x2000) as usize],
-            0x2000...0x3FFF => 0xCC, // TODO: self.ppu.read_register(0x2000 + address % 8)
-            0x4000...0x4013 => 0xFF, // TODO: read from APU registers
-            0x4014 => 0xCC,          // TODO: self.ppu.read_register(address)
-            0x4015 => 0xFF,          // TODO: self.apu.read_register(address)
-            0x4016 => 0xFF,          // TODO: self.controller1.read()
-            0x4017 => 0xFF,          // TODO: self.controller2.read()
-            0x4018...0x5FFF => 0xFF, // TODO: I/O registers
-            0x6000...0xFFFF => self.mapper_read(address),
+    /// Pass the host's input on to the devices in the controller ports.
+    pub fn set_input(&mut self, input: &Input) {
+        self.port1.set_input(input);
+        self.port2.set_input(input);
+        if let Some(ref mut expansion) = self.expansion {
+            expansion.set_input(input);
+        }
+    }
+
+    /// IRQ is level triggered: it stays asserted as long as any device
+    /// holds the line.
+    pub fn irq_asserted(&self) -> bool {
+        !self.irq_line.is_empty() || self.mapper.irq()
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        let value = match address {
+            0x0000..=0x1FFF => self.ram[(address % 0x800) as usize],
+            0x2000..=0x3FFF => self.read_ppu_register(0x2000 + address % 8),
+            // The APU registers and OAMDMA are write-only.
+            0x4000..=0x4014 => self.open_bus,
+            0x4015 => {
+                // Reading the status acknowledges the frame IRQ.
+                let value = self.apu_status
+                    | (self.irq_line.contains(Irq::FRAME_COUNTER) as u8) << 6
+                    | (self.irq_line.contains(Irq::DMC) as u8) << 7;
+                self.irq_line.remove(Irq::FRAME_COUNTER);
+                value
+            }
+            // The controller ports only drive the low bits, the upper three
+            // are open bus.
+            0x4016 | 0x4017 => {
+                let video = Video {
+                    pixels: &self.ppu_pixels,
+                    scan_line: self.ppu_scan_line,
+                    cycle: self.ppu_cycle,
+                };
+                let index = (address - 0x4016) as usize;
+                let mut value = if index == 0 {
+                    // The second controller's microphone
+                    self.port1.read(&video) | (self.port2.microphone() as u8) << 2
+                } else {
+                    self.port2.read(&video)
+                };
+                if let Some(ref mut expansion) = self.expansion {
+                    value |= expansion.read(index, &video) & 0x1E;
+                }
+                self.open_bus & 0xE0 | value & 0x1F
+            }
+            0x4018..=0x401F => self.open_bus, // APU and I/O test registers
+            0x4020..=0xFFFF => self.mapper.cpu_read(address),
             _ => panic!("Invalid bus memory read at address {:04X}", address),
+        };
+        self.open_bus = value;
+        value
+    }
+
+    /// Read a byte without side effects, for debug output. Memory mapped
+    /// registers that change state when read return open bus instead.
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.ram[(address % 0x800) as usize],
+            0x2000..=0x401F => 0xFF,
+            _ => self.mapper.read(address),
         }
     }
 
-    pub fn read_16(&self, address: u16) -> u16 {
-        (self.read(address + 1) as u16) << 8 | self.read(address) as u16
+    pub fn peek_16(&self, address: u16) -> u16 {
+        let lo = self.peek(address);
+        let hi = self.peek(address + 1);
+        (hi as u16) << 8 | lo as u16
+    }
+
+    /// Like `read_16_bug`, without side effects.
+    pub fn peek_16_bug(&self, address: u16) -> u16 {
+        let address_plus_one = (address & 0xFF00) | (address as u8).wrapping_add(1) as u16;
+        let lo = self.peek(address);
+        let hi = self.peek(address_plus_one);
+        (hi as u16) << 8 | lo as u16
     }
 
-    pub fn read_16_bug(&self, address: u16) -> u16 {
+    pub fn read_16(&mut self, address: u16) -> u16 {
+        let lo = self.read(address);
+        let hi = self.read(address + 1);
+        (hi as u16) << 8 | lo as u16
+    }
+
+    pub fn read_16_bug(&mut self, address: u16) -> u16 {
         let address_plus_one = (address & 0xFF00) | (address as u8).wrapping_add(1) as u16;
         let lo = self.read(address);
         let hi = self.read(address_plus_one);
@@ -56,35 +196,314 @@ impl Bus {
 
     pub fn write(&mut self, address: u16, value: u8) {
         match address {
-            0x0000...0x1FFF => self.ram[(address % 2048) as usize] = value,
-            0x4000...0x4013 | 0x4015 => {
+            0x0000..=0x1FFF => self.ram[(address % 2048) as usize] = value,
+            0x2000..=0x3FFF => {
+                let address = 0x2000 + address % 8;
+                self.write_ppu_register(address, value);
+                self.mapper.ppu_register_write(address, value);
+            }
+            0x4000..=0x4013 | 0x4015 => {
                 self.apu_registers[(address - 0x4000) as usize] = value;
+                self.apu_writes.push((address, value));
             }
+            0x4014 => self.oam_dma_page = Some(value),
+            0x4016 => {
+                self.port1.write(value);
+                self.port2.write(value);
+                if let Some(ref mut expansion) = self.expansion {
+                    expansion.write(value & 0x07);
+                }
+            }
+            0x4017 => self.apu_writes.push((address, value)),
+            0x4020..=0xFFFF => self.mapper.write(address, value),
             _ => {}
         }
+        self.open_bus = value;
     }
 
-    pub fn mapper_read(&self, address: u16) -> u8 {
-        match address {
-            0x0000...0x2000 => self.cartridge.chr[address as usize],
-            0x6000...0x7FFF => 0xCC, // TODO: self.cartridge.sram[address - 0x6000]
-            0x8000...0xBFFF => self.cartridge.prg[(address - 0x8000) as usize],
-            0xC000...0xFFFF => self.cartridge.prg[(address - 0xC000) as usize],
-            _ => panic!("Invalid bus mapper read at address {}", address),
+    pub fn ppu_read(&mut self, address: u16) -> u8 {
+        let address = address % 0x4000;
+        let value = match address {
+            0x0000..=0x1FFF => self.mapper.read(address),
+            0x2000..=0x3EFF => match self.mapper.read_name_table(0x2000 | address & 0x0FFF) {
+                Some(value) => value,
+                None => self.ppu_name_table[self.name_table_index(address)],
+            },
+            0x3F00..=0x3FFF => self.ppu_palette[palette_index(address)],
+            _ => panic!("Invalid bus PPU read at address {}", address),
+        };
+        // Palette accesses stay inside the PPU.
+        if address < 0x3F00 {
+            self.mapper.ppu_address(address);
         }
+        value
     }
 
-    pub fn ppu_read(&self, address: u16) -> u8 {
+    pub fn ppu_write(&mut self, address: u16, value: u8) {
         let address = address % 0x4000;
         match address {
-            0x0000...0x1FFF => self.mapper_read(address),
-            0x2000...0x3F00 => {
-                let mode = self.cartridge.mirror_mode;
-                // FIXME: this is wrong
-                self.ppu_name_table[address as usize]
+            0x0000..=0x1FFF => self.mapper.write(address, value),
+            0x2000..=0x3EFF => {
+                if !self.mapper.write_name_table(0x2000 | address & 0x0FFF, value) {
+                    let index = self.name_table_index(address);
+                    self.ppu_name_table[index] = value;
+                }
             }
-            0x3F00...0x4000 => self.ppu_palette[(address % 32) as usize],
-            _ => panic!("Invalid bus PPU read at address {}", address),
+            0x3F00..=0x3FFF => self.ppu_palette[palette_index(address)] = value,
+            _ => panic!("Invalid bus PPU write at address {}", address),
+        }
+        if address < 0x3F00 {
+            self.mapper.ppu_address(address);
+        }
+    }
+
+    /// Map a name table address ($2000-$3EFF) into VRAM according to the
+    /// cartridge's current mirroring. $3000-$3EFF mirrors $2000-$2EFF.
+    pub fn name_table_index(&self, address: u16) -> usize {
+        let address = (address - 0x2000) % 0x1000;
+        let table = address / 0x0400;
+        let offset = address % 0x0400;
+        (self.mapper.mirror_mode().page(table) * 0x0400 + offset) as usize
+    }
+
+    /// The PPU pulls /NMI low while it is in vertical blank and NMI
+    /// generation is enabled in PPUCTRL.
+    pub fn update_nmi_line(&mut self) {
+        self.nmi_line = self.ppu_ctrl.contains(Control::NMI_ENABLE)
+            && self.ppu_status.contains(Status::VERTICAL_BLANK);
+    }
+
+    /// Increment the VRAM address after a PPUDATA access.
+    fn increment_ppu_address(&mut self) {
+        let increment = if self.ppu_ctrl.contains(Control::INCREMENT_32) {
+            32
+        } else {
+            1
+        };
+        self.ppu_v = self.ppu_v.wrapping_add(increment) & 0x7FFF;
+    }
+
+    pub fn read_ppu_register(&mut self, address: u16) -> u8 {
+        let value = match address {
+            // PPUSTATUS
+            0x2002 => {
+                let value = self.ppu_status.bits() | (self.ppu_open_bus & 0x1F);
+                self.ppu_status.remove(Status::VERTICAL_BLANK);
+                self.ppu_w = false;
+                self.update_nmi_line();
+                value
+            }
+            // OAMDATA
+            0x2004 => {
+                let value = self.ppu_oam[self.ppu_oam_address as usize];
+                // Bits 2-4 of the sprite attribute byte don't exist.
+                if self.ppu_oam_address % 4 == 2 {
+                    value & 0xE3
+                } else {
+                    value
+                }
+            }
+            // PPUDATA
+            0x2007 => {
+                let address = self.ppu_v & 0x3FFF;
+                let value = if address < 0x3F00 {
+                    // Reads are delayed by one through the read buffer.
+                    let buffered = self.ppu_data_buffer;
+                    self.ppu_data_buffer = self.ppu_read(address);
+                    buffered
+                } else {
+                    // Palette reads are immediate, but the buffer is
+                    // filled with the name table byte "underneath".
+                    self.ppu_data_buffer = self.ppu_read(address - 0x1000);
+                    (self.ppu_read(address) & 0x3F) | (self.ppu_open_bus & 0xC0)
+                };
+                self.increment_ppu_address();
+                value
+            }
+            // Write-only registers return the last value on the PPU bus.
+            _ => self.ppu_open_bus,
+        };
+        self.ppu_open_bus = value;
+        value
+    }
+
+    pub fn write_ppu_register(&mut self, address: u16, value: u8) {
+        self.ppu_open_bus = value;
+        match address {
+            // PPUCTRL
+            0x2000 => {
+                self.ppu_ctrl = Control::from_bits_truncate(value);
+                // t: ...BA.. ........ = d: ......BA
+                self.ppu_t = (self.ppu_t & 0xF3FF) | ((value as u16 & 0x03) << 10);
+                self.update_nmi_line();
+            }
+            // PPUMASK
+            0x2001 => self.ppu_mask = Mask::from_bits_truncate(value),
+            // OAMADDR
+            0x2003 => self.ppu_oam_address = value,
+            // OAMDATA
+            0x2004 => {
+                self.ppu_oam[self.ppu_oam_address as usize] = value;
+                self.ppu_oam_address = self.ppu_oam_address.wrapping_add(1);
+            }
+            // PPUSCROLL
+            0x2005 => {
+                if !self.ppu_w {
+                    // t: ....... ...HGFED = d: HGFED...
+                    // x:              CBA = d: .....CBA
+                    self.ppu_t = (self.ppu_t & 0xFFE0) | (value as u16 >> 3);
+                    self.ppu_x = value & 0x07;
+                } else {
+                    // t: CBA..HG FED..... = d: HGFEDCBA
+                    self.ppu_t = (self.ppu_t & 0x8FFF) | ((value as u16 & 0x07) << 12);
+                    self.ppu_t = (self.ppu_t & 0xFC1F) | ((value as u16 & 0xF8) << 2);
+                }
+                self.ppu_w = !self.ppu_w;
+            }
+            // PPUADDR
+            0x2006 => {
+                if !self.ppu_w {
+                    // t: .FEDCBA ........ = d: ..FEDCBA
+                    // t: X...... ........ = 0
+                    self.ppu_t = (self.ppu_t & 0x80FF) | ((value as u16 & 0x3F) << 8);
+                    self.ppu_t &= 0x3FFF;
+                } else {
+                    // t: ....... HGFEDCBA = d: HGFEDCBA
+                    // v                   = t
+                    self.ppu_t = (self.ppu_t & 0xFF00) | value as u16;
+                    self.ppu_v = self.ppu_t;
+                }
+                self.ppu_w = !self.ppu_w;
+            }
+            // PPUDATA
+            0x2007 => {
+                let address = self.ppu_v & 0x3FFF;
+                self.ppu_write(address, value);
+                self.increment_ppu_address();
+            }
+            // PPUSTATUS is read-only.
+            _ => {}
         }
     }
 }
+
+/// Palette entries $3F10/$3F14/$3F18/$3F1C mirror $3F00/$3F04/$3F08/$3F0C.
+pub fn palette_index(address: u16) -> usize {
+    let index = address % 32;
+    if index >= 16 && index & 3 == 0 {
+        (index - 16) as usize
+    } else {
+        index as usize
+    }
+}
+
+/// Bus with an NROM cartridge and 32 KiB of PRG ROM, for tests. $8000-$BFFF
+/// reads 0 and $C000-$FFFF reads 1.
+#[cfg(test)]
+pub fn test_bus() -> Bus {
+    use cartridge::test_cartridge;
+    use mapper::new_mapper;
+    Bus::new(new_mapper(test_cartridge(0, 2, 1)).unwrap(), vec![0; 2048])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge::{test_cartridge, Mirror};
+    use mapper::new_mapper;
+
+    #[test]
+    fn it_reads_open_bus_from_write_only_registers() {
+        let mut bus = test_bus();
+        bus.write(0x0010, 0x42);
+        for &address in &[0x4000, 0x4013, 0x4014, 0x4018] {
+            bus.read(0x0010);
+            assert_eq!(bus.read(address), 0x42);
+        }
+    }
+
+    #[test]
+    fn it_updates_the_loopy_registers() {
+        // The worked example from the wiki.
+        // See https://wiki.nesdev.com/w/index.php/PPU_scrolling
+        let mut bus = test_bus();
+        bus.ppu_t = 0x7FFF;
+        bus.write(0x2000, 0x00);
+        assert_eq!(bus.ppu_t, 0x73FF);
+        bus.ppu_w = true;
+        bus.read(0x2002);
+        assert!(!bus.ppu_w);
+        bus.write(0x2005, 0x7D);
+        assert_eq!((bus.ppu_t, bus.ppu_x, bus.ppu_w), (0x73EF, 0x05, true));
+        bus.write(0x2005, 0x5E);
+        assert_eq!((bus.ppu_t, bus.ppu_x, bus.ppu_w), (0x616F, 0x05, false));
+        bus.write(0x2006, 0x3D);
+        assert_eq!((bus.ppu_t, bus.ppu_w), (0x3D6F, true));
+        bus.write(0x2006, 0xF0);
+        assert_eq!((bus.ppu_t, bus.ppu_v, bus.ppu_w), (0x3DF0, 0x3DF0, false));
+        assert_eq!(bus.ppu_x, 0x05);
+
+        // PPUCTRL sets the name table bits.
+        bus.write(0x2000, 0x03);
+        assert_eq!(bus.ppu_t, 0x3DF0 | 0x0C00);
+    }
+
+    #[test]
+    fn it_buffers_ppudata_reads() {
+        let mut bus = test_bus();
+        bus.write(0x2006, 0x20);
+        bus.write(0x2006, 0x00);
+        bus.write(0x2007, 0xAA);
+        bus.write(0x2007, 0xBB);
+        bus.write(0x2006, 0x20);
+        bus.write(0x2006, 0x00);
+        assert_eq!(bus.read(0x2007), 0x00);
+        assert_eq!(bus.read(0x2007), 0xAA);
+        assert_eq!(bus.read(0x2007), 0xBB);
+
+        // Increment by 32 down a column.
+        bus.write(0x2000, 0x04);
+        bus.write(0x2006, 0x20);
+        bus.write(0x2006, 0x00);
+        bus.read(0x2007);
+        assert_eq!(bus.ppu_v, 0x2020);
+    }
+
+    #[test]
+    fn it_reads_the_palette_directly() {
+        let mut bus = test_bus();
+        bus.write(0x2006, 0x2F);
+        bus.write(0x2006, 0x00);
+        bus.write(0x2007, 0x55);
+        bus.write(0x2006, 0x3F);
+        bus.write(0x2006, 0x10);
+        bus.write(0x2007, 0x2C);
+
+        // $3F10 mirrors $3F00. The read isn't delayed, its top bits are
+        // open bus, and the buffer gets the name table byte underneath.
+        bus.write(0x2006, 0x3F);
+        bus.write(0x2006, 0x00);
+        bus.ppu_open_bus = 0xC0;
+        assert_eq!(bus.read(0x2007), 0xEC);
+        assert_eq!(bus.ppu_data_buffer, 0x55);
+        assert_eq!(bus.ppu_palette[0], 0x2C);
+    }
+
+    #[test]
+    fn it_mirrors_name_tables() {
+        let pages = |mirror_mode| {
+            let mut cartridge = test_cartridge(0, 2, 1);
+            cartridge.mirror_mode = mirror_mode;
+            let bus = Bus::new(new_mapper(cartridge).unwrap(), vec![0; 2048]);
+            [0x2000, 0x2400, 0x2800, 0x2C00, 0x3C00]
+                .iter()
+                .map(|&address| bus.name_table_index(address + 0x12) / 0x400)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pages(Mirror::Horizontal), vec![0, 0, 1, 1, 1]);
+        assert_eq!(pages(Mirror::Vertical), vec![0, 1, 0, 1, 1]);
+        assert_eq!(pages(Mirror::SingleScreenA), vec![0, 0, 0, 0, 0]);
+        assert_eq!(pages(Mirror::SingleScreenB), vec![1, 1, 1, 1, 1]);
+        assert_eq!(pages(Mirror::FourScreen), vec![0, 1, 2, 3, 3]);
+    }
+}
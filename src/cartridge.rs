@@ -1,8 +1,60 @@
+#[cfg(test)]
+use std::cmp;
+
+/// Name table mirroring. The console has 2 KiB of VRAM for the four logical
+/// name tables at $2000, $2400, $2800 and $2C00; the cartridge decides how
+/// they map onto physical 1 KiB pages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mirror {
+    Horizontal,
+    Vertical,
+    SingleScreenA,
+    SingleScreenB,
+    FourScreen,
+    /// Any combination of the two console pages, set by the mapper.
+    Custom([u8; 4]),
+}
+
+impl Mirror {
+    /// Physical page used for the logical name table (0-3).
+    pub fn page(self, table: u16) -> u16 {
+        match self {
+            Mirror::Horizontal => table / 2,
+            Mirror::Vertical => table % 2,
+            Mirror::SingleScreenA => 0,
+            Mirror::SingleScreenB => 1,
+            // Four-screen boards add 2 KiB of VRAM for pages 2 and 3.
+            Mirror::FourScreen => table,
+            Mirror::Custom(pages) => pages[table as usize] as u16,
+        }
+    }
+}
+
 pub struct Cartridge {
     pub prg: Vec<u8>,
     pub chr: Vec<u8>,
+    pub chr_ram: bool,
     pub sram: Vec<u8>,
     pub mapper_type: u8,
-    pub mirror_mode: u8,
-    pub battery_present: bool,
+    pub submapper: u8,
+    pub mirror_mode: Mirror,
+}
+
+/// Cartridge for tests, with each 16 KiB PRG bank filled with its bank
+/// number. Without CHR banks it gets 8 KiB of CHR RAM.
+#[cfg(test)]
+pub fn test_cartridge(mapper_type: u8, prg_banks: usize, chr_banks: usize) -> Cartridge {
+    let mut prg = vec![0; prg_banks * 0x4000];
+    for (i, byte) in prg.iter_mut().enumerate() {
+        *byte = (i / 0x4000) as u8;
+    }
+    Cartridge {
+        prg,
+        chr: vec![0; cmp::max(chr_banks, 1) * 0x2000],
+        chr_ram: chr_banks == 0,
+        sram: vec![0; 0x2000],
+        mapper_type,
+        submapper: 0,
+        mirror_mode: Mirror::Horizontal,
+    }
 }
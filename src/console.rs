@@ -1,4 +1,4 @@
-use cpu::{CPU, CPU_FREQUENCY};
+use cpu::CPU;
 use ppu::PPU;
 use bus::Bus;
 use apu::APU;
@@ -15,26 +15,31 @@ impl Console {
         self.cpu.reset(&mut self.bus)
     }
 
-    pub fn log_string(&mut self) -> String {
+    pub fn log_string(&self) -> String {
         self.cpu.log_string(&self.bus)
     }
 
     pub fn step(&mut self) -> u32 {
         let cpu_cycles = self.cpu.step(&mut self.bus);
-        let ppu_cycles = cpu_cycles * 3;
-        for _ in 0..ppu_cycles {
-            self.ppu.step(&mut self.bus);
-        }
-        for _ in 0..cpu_cycles {
+        for cycle in 0..cpu_cycles {
+            for _ in 0..3 {
+                self.ppu.step(&mut self.bus);
+            }
+            if cycle + 1 == cpu_cycles {
+                self.apu.write_registers(&mut self.bus);
+            }
             self.apu.step(&mut self.bus);
+            self.bus.mapper.step();
+            self.cpu.sample_interrupts(&mut self.bus, cycle);
         }
         cpu_cycles
     }
 
-    pub fn step_seconds(&mut self, seconds: f64) {
-        let mut cycles = (CPU_FREQUENCY as f64 * seconds) as i32;
-        while cycles > 0 {
-            cycles = cycles - self.step() as i32;
+    /// Run until the PPU has finished the current frame.
+    pub fn step_frame(&mut self) {
+        let frame = self.ppu.frame;
+        while self.ppu.frame == frame {
+            self.step();
         }
     }
 }
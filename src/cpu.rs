@@ -16,6 +16,13 @@ const ADDRESS_MODE_ZERO_PAGE_Y: u8 = 13;
 
 pub const CPU_FREQUENCY: u64 = 1_789_773;
 
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+// Number of cycles it takes to push PC and P and fetch an interrupt vector.
+const INTERRUPT_CYCLES: u64 = 7;
+
 const INSTRUCTION_MODES: [u8; 256] = [
     6, 7, 6, 7, 11, 11, 11, 11, 6, 5, 4, 5, 1, 1, 1, 1, 10, 9, 6, 9, 12, 12, 12, 12, 6, 3, 6, 3, 2,
     2, 2, 2, 1, 7, 6, 7, 11, 11, 11, 11, 6, 5, 4, 5, 1, 1, 1, 1, 10, 9, 6, 9, 12, 12, 12, 12, 6, 3,
@@ -106,12 +113,22 @@ fn pages_differ(a: u16, b: u16) -> bool {
 
 pub struct CPU {
     pub cycles: u64,
+    pub stall: u32,
     pub pc: u16,
     pub sp: u8,
     pub a: u8,
     pub x: u8,
     pub y: u8,
     pub flags: Flags,
+
+    // Interrupt state
+    pub nmi_previous: bool,
+    pub nmi_detected: bool,
+    pub nmi_pending: bool,
+    pub irq_pending: bool,
+    pub irq_inhibit: bool,
+    pub poll_cycle: Option<u32>,
+    pub hijackable: bool,
 }
 
 // http://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
@@ -120,20 +137,36 @@ impl CPU {
     pub fn new() -> CPU {
         CPU {
             cycles: 0,
+            stall: 0,
             pc: 0,
             sp: 0,
             a: 0,
             x: 0,
             y: 0,
             flags: Default::default(),
+
+            nmi_previous: false,
+            nmi_detected: false,
+            nmi_pending: false,
+            irq_pending: false,
+            irq_inhibit: true,
+            poll_cycle: None,
+            hijackable: false,
         }
     }
 
     pub fn reset(&mut self, bus: &mut Bus) {
-        self.pc = bus.read_16(0xFFFC);
+        self.pc = bus.read_16(RESET_VECTOR);
         println!("RESET PC: {:04X}", self.pc);
         self.sp = 0xFD;
         self.flags = Flags::UNUSED | Flags::INTERRUPT_DISABLE;
+        self.nmi_previous = bus.nmi_line;
+        self.nmi_detected = false;
+        self.nmi_pending = false;
+        self.irq_pending = false;
+        self.irq_inhibit = true;
+        self.poll_cycle = None;
+        self.hijackable = false;
     }
 
     /// Set the zero flag if the value is 0.
@@ -155,29 +188,29 @@ impl CPU {
     pub fn push(&mut self, bus: &mut Bus, v: u8) {
         let sp = self.sp as u16;
         bus.write(0x100 + sp, v);
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
     }
 
-    pub fn pull(&mut self, bus: &Bus) -> u8 {
-        self.sp += 1;
+    pub fn pull(&mut self, bus: &mut Bus) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
         let sp = self.sp as u16;
         bus.read(0x100 + sp)
     }
 
-    pub fn push_16(&mut self, mut bus: &mut Bus, v: u16) {
+    pub fn push_16(&mut self, bus: &mut Bus, v: u16) {
         let hi = (v >> 8) as u8;
         let lo = (v & 0xFF) as u8;
-        self.push(&mut bus, hi);
-        self.push(&mut bus, lo);
+        self.push(bus, hi);
+        self.push(bus, lo);
     }
 
-    pub fn pull_16(&mut self, bus: &Bus) -> u16 {
-        let lo = self.pull(&bus);
-        let hi = self.pull(&bus);
+    pub fn pull_16(&mut self, bus: &mut Bus) -> u16 {
+        let lo = self.pull(bus);
+        let hi = self.pull(bus);
         (hi as u16) << 8 | lo as u16
     }
 
-    pub fn get_address(&mut self, bus: &Bus, opcode: u8, side_effects: bool) -> u16 {
+    pub fn get_address(&mut self, bus: &mut Bus, opcode: u8) -> u16 {
         let address_mode = INSTRUCTION_MODES[opcode as usize];
         let mut page_crossed = false;
         let address = match address_mode {
@@ -196,12 +229,16 @@ impl CPU {
             ADDRESS_MODE_IMMEDIATE => self.pc + 1,
             ADDRESS_MODE_IMPLIED => 0,
             ADDRESS_MODE_INDEXED_INDIRECT => {
-                bus.read_16_bug((bus.read(self.pc + 1).wrapping_add(self.x)) as u16)
+                let pointer = bus.read(self.pc + 1).wrapping_add(self.x);
+                bus.read_16_bug(pointer as u16)
+            }
+            ADDRESS_MODE_INDIRECT => {
+                let pointer = bus.read_16(self.pc + 1);
+                bus.read_16_bug(pointer)
             }
-            ADDRESS_MODE_INDIRECT => bus.read_16_bug(bus.read_16(self.pc + 1)),
             ADDRESS_MODE_INDIRECT_INDEXED => {
-                let address = bus.read_16_bug((bus.read(self.pc + 1) as u16))
-                    .wrapping_add(self.y as u16);
+                let pointer = bus.read(self.pc + 1);
+                let address = bus.read_16_bug(pointer as u16).wrapping_add(self.y as u16);
                 page_crossed = pages_differ(address.wrapping_sub(self.y as u16), address);
                 address
             }
@@ -223,18 +260,115 @@ impl CPU {
             _ => panic!("Invalid address mode {}", address_mode),
         };
 
-        if side_effects && page_crossed {
+        if page_crossed {
             self.cycles += INSTRUCTION_PAGE_CYCLES[opcode as usize] as u64;
         }
         address
     }
 
-    pub fn branch_to(&mut self, address: u16) {
+    /// Compute the address of the operand at PC like `get_address`, with
+    /// peeks instead of reads so the bus doesn't see any accesses.
+    pub fn peek_address(&self, bus: &Bus, opcode: u8) -> u16 {
+        match INSTRUCTION_MODES[opcode as usize] {
+            ADDRESS_MODE_ABSOLUTE => bus.peek_16(self.pc + 1),
+            ADDRESS_MODE_ABSOLUTE_X => bus.peek_16(self.pc + 1).wrapping_add(self.x as u16),
+            ADDRESS_MODE_ABSOLUTE_Y => bus.peek_16(self.pc + 1).wrapping_add(self.y as u16),
+            ADDRESS_MODE_IMMEDIATE => self.pc + 1,
+            ADDRESS_MODE_INDEXED_INDIRECT => {
+                let pointer = bus.peek(self.pc + 1).wrapping_add(self.x);
+                bus.peek_16_bug(pointer as u16)
+            }
+            ADDRESS_MODE_INDIRECT => bus.peek_16_bug(bus.peek_16(self.pc + 1)),
+            ADDRESS_MODE_INDIRECT_INDEXED => {
+                let pointer = bus.peek(self.pc + 1);
+                bus.peek_16_bug(pointer as u16).wrapping_add(self.y as u16)
+            }
+            ADDRESS_MODE_RELATIVE => {
+                let offset = bus.peek(self.pc + 1) as i8;
+                (self.pc + 2).wrapping_add(offset as u16)
+            }
+            ADDRESS_MODE_ZERO_PAGE => bus.peek(self.pc + 1) as u16,
+            ADDRESS_MODE_ZERO_PAGE_X => bus.peek(self.pc + 1).wrapping_add(self.x) as u16,
+            ADDRESS_MODE_ZERO_PAGE_Y => bus.peek(self.pc + 1).wrapping_add(self.y) as u16,
+            _ => 0,
+        }
+    }
+
+    /// Read the operand of a read-modify-write instruction. The 6502 writes
+    /// the unmodified value back before writing the result, which mappers
+    /// and memory mapped registers can observe.
+    pub fn read_modify(&mut self, bus: &mut Bus, address: u16) -> u8 {
+        let v = bus.read(address);
+        bus.write(address, v);
+        v
+    }
+
+    /// Take a branch. Returns true if the branch crossed a page boundary.
+    pub fn branch_to(&mut self, address: u16) -> bool {
         let prev_pc = self.pc;
         self.pc = address;
         self.cycles += 1;
         if pages_differ(prev_pc, address) {
             self.cycles += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Push PC and the status register and jump through the given vector.
+    /// BRK pushes the status register with the B flag set, NMI and IRQ
+    /// push it with the B flag cleared.
+    pub fn interrupt(&mut self, bus: &mut Bus, vector: u16, brk: bool) {
+        let pc = self.pc;
+        self.push_16(bus, pc);
+        let flags = if brk {
+            self.flags | Flags::BREAK | Flags::UNUSED
+        } else {
+            (self.flags | Flags::UNUSED) - Flags::BREAK
+        };
+        self.push(bus, flags.bits());
+        self.flags |= Flags::INTERRUPT_DISABLE;
+        self.pc = bus.read_16(vector);
+    }
+
+    /// Copy a page of CPU memory to OAM through OAMDATA. The CPU is halted
+    /// for 513 cycles, plus one more to align to an even cycle.
+    pub fn oam_dma(&mut self, bus: &mut Bus, page: u8) {
+        let address = (page as u16) << 8;
+        for i in 0..256 {
+            let value = bus.read(address + i);
+            bus.write(0x2004, value);
+        }
+        self.stall += 513;
+        if self.cycles % 2 == 1 {
+            self.stall += 1;
+        }
+    }
+
+    /// Sample the interrupt lines at the end of the given cycle of the last
+    /// step. The console calls this once per CPU cycle, after the PPU and
+    /// APU have caught up, so the CPU sees interrupts with cycle precision.
+    pub fn sample_interrupts(&mut self, bus: &mut Bus, cycle: u32) {
+        // NMI is edge triggered: remember the edge until it is serviced.
+        if bus.nmi_line && !self.nmi_previous {
+            self.nmi_detected = true;
+        }
+        self.nmi_previous = bus.nmi_line;
+
+        // An NMI that shows up before the vector fetch of a BRK or IRQ
+        // sequence hijacks it: the CPU jumps through the NMI vector instead
+        // and the NMI itself is lost.
+        if self.hijackable && cycle < 4 && self.nmi_detected {
+            self.nmi_detected = false;
+            self.pc = bus.read_16(NMI_VECTOR);
+        }
+
+        // The 6502 decides whether to service an interrupt at the end of
+        // the second to last cycle of an instruction. An NMI that arrives
+        // later is only serviced after the next instruction.
+        if self.poll_cycle == Some(cycle) {
+            self.nmi_pending = self.nmi_detected;
+            self.irq_pending = bus.irq_asserted() && !self.irq_inhibit;
         }
     }
 
@@ -243,10 +377,13 @@ impl CPU {
         self.flags.set(Flags::CARRY, a >= b);
     }
 
-    pub fn log_string(&mut self, bus: &Bus) -> String {
-        let opcode = bus.read(self.pc);
-        let arg1 = bus.read(self.pc + 1);
-        let arg2 = bus.read(self.pc + 2);
+    /// Disassemble the instruction at PC in the format of the nestest log.
+    /// Only peeks at the bus, so logging doesn't change what the program
+    /// sees.
+    pub fn log_string(&self, bus: &Bus) -> String {
+        let opcode = bus.peek(self.pc);
+        let arg1 = bus.peek(self.pc + 1);
+        let arg2 = bus.peek(self.pc + 2);
         let name = INSTRUCTION_NAMES[opcode as usize];
         let opcode_size = INSTRUCTION_SIZES[opcode as usize];
         let opcode_string = match opcode_size {
@@ -259,8 +396,8 @@ impl CPU {
             ),
         };
         let address_mode = INSTRUCTION_MODES[opcode as usize];
-        let address = self.get_address(&bus, opcode, false);
-        let value = bus.read(address);
+        let address = self.peek_address(bus, opcode);
+        let value = bus.peek(address);
         let mut address_string = match address_mode {
             ADDRESS_MODE_ABSOLUTE => format!("${:04X} = {:02X}", address, value),
             ADDRESS_MODE_ABSOLUTE_X => format!(
@@ -285,7 +422,7 @@ impl CPU {
             ADDRESS_MODE_INDIRECT_INDEXED => format!(
                 "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
                 arg1,
-                bus.read_16_bug(arg1 as u16),
+                bus.peek_16_bug(arg1 as u16),
                 address,
                 value
             ),
@@ -322,15 +459,43 @@ impl CPU {
         )
     }
 
-    pub fn log(&mut self, bus: &Bus) {
-        println!("{}", self.log_string(&bus));
-    }
-
-    pub fn step(&mut self, mut bus: &mut Bus) -> u32 {
+    pub fn step(&mut self, bus: &mut Bus) -> u32 {
         let old_cycles = self.cycles;
+
+        // The CPU is halted while DMA is in progress.
+        self.poll_cycle = None;
+        self.hijackable = false;
+        self.stall += bus.dmc_stall;
+        bus.dmc_stall = 0;
+        if self.stall > 0 {
+            self.cycles += self.stall as u64;
+            self.stall = 0;
+            return (self.cycles - old_cycles) as u32;
+        }
+
+        // Pending interrupts run in place of the next instruction. The
+        // interrupt sequence itself doesn't poll, so at least one
+        // instruction of the handler runs before the next interrupt.
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.nmi_detected = false;
+            self.interrupt(bus, NMI_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+            return (self.cycles - old_cycles) as u32;
+        }
+        if self.irq_pending {
+            self.irq_pending = false;
+            self.interrupt(bus, IRQ_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+            self.hijackable = true;
+            return (self.cycles - old_cycles) as u32;
+        }
+
+        let interrupt_disable = self.flags.contains(Flags::INTERRUPT_DISABLE);
+        let mut early_poll = false;
         let opcode = bus.read(self.pc);
         let address_mode = INSTRUCTION_MODES[opcode as usize];
-        let address = self.get_address(&bus, opcode, true);
+        let address = self.get_address(bus, opcode);
 
         //println!("Address: {:04X} mode {:?}", address, address_mode);
         //
@@ -340,16 +505,24 @@ impl CPU {
         match opcode {
             //// Control Instructions ////
 
+            // BRK - Force Interrupt
+            0x00 => {
+                // BRK is a two byte instruction; the second byte is skipped.
+                self.pc += 1;
+                self.interrupt(bus, IRQ_VECTOR, true);
+                self.hijackable = true;
+            }
+
             // PHP - Push Processor Status
             0x08 => {
                 let flags = self.flags.bits();
-                self.push(&mut bus, flags | 0x10);
+                self.push(bus, flags | 0x10);
             }
 
             // BPL - Branch If Positive
             0x10 => {
                 if !self.flags.intersects(Flags::NEGATIVE) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -361,7 +534,7 @@ impl CPU {
             // JSR - Jump to Subroutine
             0x20 => {
                 let pc = self.pc;
-                self.push_16(&mut bus, pc - 1);
+                self.push_16(bus, pc - 1);
                 self.pc = address;
             }
 
@@ -376,14 +549,14 @@ impl CPU {
 
             // PLP - Pull Processor Status
             0x28 => {
-                let flags = self.pull(&bus) & 0xEF | 0x20;
+                let flags = self.pull(bus) & 0xEF | 0x20;
                 self.flags = Flags::from_bits(flags).unwrap();
             }
 
             // BMI - Branch on Minus
             0x30 => {
                 if self.flags.intersects(Flags::NEGATIVE) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -394,15 +567,15 @@ impl CPU {
 
             // RTI - Return from Interrupt
             0x40 => {
-                let flags = self.pull(&bus) & 0xEF | 0x20;
+                let flags = self.pull(bus) & 0xEF | 0x20;
                 self.flags = Flags::from_bits(flags).unwrap();
-                self.pc = self.pull_16(&bus);
+                self.pc = self.pull_16(bus);
             }
 
             // PHA - Push Accumulator
             0x48 => {
                 let a = self.a;
-                self.push(&mut bus, a);
+                self.push(bus, a);
             }
 
             // JMP - Jump
@@ -413,7 +586,7 @@ impl CPU {
             // BVC - Branch on Overflow Clear
             0x50 => {
                 if !self.flags.intersects(Flags::OVERFLOW) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -424,12 +597,12 @@ impl CPU {
 
             // RTS - Return from Subroutine
             0x60 => {
-                self.pc = self.pull_16(&bus) + 1;
+                self.pc = self.pull_16(bus) + 1;
             }
 
             // PLA - Pull Accumulator
             0x68 => {
-                self.a = self.pull(&bus);
+                self.a = self.pull(bus);
                 let a = self.a;
                 self.set_zn_flag(a);
             }
@@ -437,7 +610,7 @@ impl CPU {
             // BVS - Branch on Overflow Set
             0x70 => {
                 if self.flags.intersects(Flags::OVERFLOW) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -459,7 +632,7 @@ impl CPU {
             // BCC - Branch on Carry Clear
             0x90 => {
                 if !self.flags.intersects(Flags::CARRY) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -487,7 +660,7 @@ impl CPU {
             // BCS - Branch on Carry Set
             0xB0 => {
                 if self.flags.intersects(Flags::CARRY) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -513,7 +686,7 @@ impl CPU {
             // BNE - Branch on Not Equal
             0xD0 => {
                 if !self.flags.intersects(Flags::ZERO) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -539,7 +712,7 @@ impl CPU {
             // BEQ - Branch on Equal
             0xF0 => {
                 if self.flags.intersects(Flags::ZERO) {
-                    self.branch_to(address);
+                    early_poll = !self.branch_to(address);
                 }
             }
 
@@ -552,21 +725,21 @@ impl CPU {
 
             // ORA - Bitwise OR with Accumulator
             0x01 | 0x05 | 0x09 | 0x0D | 0x11 | 0x15 | 0x19 | 0x1D => {
-                self.a = self.a | bus.read(address);
+                self.a |= bus.read(address);
                 let a = self.a;
                 self.set_zn_flag(a);
             }
 
             // AND - Bitwise AND with Accumulator
             0x21 | 0x25 | 0x29 | 0x2D | 0x31 | 0x35 | 0x39 | 0x3D => {
-                self.a = self.a & bus.read(address);
+                self.a &= bus.read(address);
                 let a = self.a;
                 self.set_zn_flag(a);
             }
 
             // EOR - Bitwise Exclusive OR
             0x41 | 0x45 | 0x49 | 0x4D | 0x51 | 0x55 | 0x59 | 0x5D => {
-                self.a = self.a ^ bus.read(address);
+                self.a ^= bus.read(address);
                 let a = self.a;
                 self.set_zn_flag(a);
             }
@@ -638,7 +811,7 @@ impl CPU {
                     let a = self.a;
                     self.set_zn_flag(a);
                 } else {
-                    let mut v = bus.read(address);
+                    let mut v = self.read_modify(bus, address);
                     self.flags.set(Flags::CARRY, ((v >> 7) & 1) > 0);
                     v <<= 1;
                     bus.write(address, v);
@@ -659,7 +832,7 @@ impl CPU {
                     let a = self.a;
                     self.set_zn_flag(a);
                 } else {
-                    let mut v = bus.read(address);
+                    let mut v = self.read_modify(bus, address);
                     self.flags.set(Flags::CARRY, ((v >> 7) & 1) > 0);
                     v = (v << 1) | c;
                     bus.write(address, v);
@@ -675,7 +848,7 @@ impl CPU {
                     let a = self.a;
                     self.set_zn_flag(a);
                 } else {
-                    let mut v = bus.read(address);
+                    let mut v = self.read_modify(bus, address);
                     self.flags.set(Flags::CARRY, (v & 1) > 0);
                     v >>= 1;
                     bus.write(address, v);
@@ -696,7 +869,7 @@ impl CPU {
                     let a = self.a;
                     self.set_zn_flag(a);
                 } else {
-                    let mut v = bus.read(address);
+                    let mut v = self.read_modify(bus, address);
                     self.flags.set(Flags::CARRY, (v & 1) > 0);
                     v = (v >> 1) | (c << 7);
                     bus.write(address, v);
@@ -744,7 +917,7 @@ impl CPU {
 
             // DEC - Decrement Memory
             0xC6 | 0xCE | 0xD6 | 0xDE => {
-                let mut v = bus.read(address);
+                let mut v = self.read_modify(bus, address);
                 v = v.wrapping_sub(1);
                 bus.write(address, v);
                 self.set_zn_flag(v);
@@ -759,7 +932,7 @@ impl CPU {
 
             // INC - Increment Memory
             0xE6 | 0xEE | 0xF6 | 0xFE => {
-                let mut v = bus.read(address);
+                let mut v = self.read_modify(bus, address);
                 v = v.wrapping_add(1);
                 bus.write(address, v);
                 self.set_zn_flag(v);
@@ -769,7 +942,7 @@ impl CPU {
 
             // SLO - ASL + ORA
             0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F => {
-                let mut b: u8 = bus.read(address);
+                let mut b: u8 = self.read_modify(bus, address);
                 self.flags.set(Flags::CARRY, ((b >> 7) & 1) > 0);
                 b <<= 1;
                 self.a |= b;
@@ -780,7 +953,7 @@ impl CPU {
 
             // RLA - ROL + AND
             0x23 | 0x27 | 0x2F | 0x33 | 0x37 | 0x3B | 0x3F => {
-                let mut b: u8 = bus.read(address);
+                let mut b: u8 = self.read_modify(bus, address);
                 let c: u8 = if self.flags.intersects(Flags::CARRY) {
                     1
                 } else {
@@ -796,7 +969,7 @@ impl CPU {
 
             // SRE - LSR + EOR
             0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F => {
-                let mut b: u8 = bus.read(address);
+                let mut b: u8 = self.read_modify(bus, address);
                 self.flags.set(Flags::CARRY, (b & 1) > 0);
                 b >>= 1;
                 self.a ^= b;
@@ -808,7 +981,7 @@ impl CPU {
             // RRA - ROR + ADC
             0x63 | 0x67 | 0x6F | 0x73 | 0x77 | 0x7B | 0x7F => {
                 let a = self.a;
-                let mut b: u8 = bus.read(address);
+                let mut b: u8 = self.read_modify(bus, address);
                 let mut c: u8 = if self.flags.intersects(Flags::CARRY) {
                     1
                 } else {
@@ -846,7 +1019,7 @@ impl CPU {
 
             // DCP - DEC + CMP
             0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF => {
-                let mut v = bus.read(address);
+                let mut v = self.read_modify(bus, address);
                 v = v.wrapping_sub(1);
                 let a = self.a;
                 self.compare(a, v);
@@ -856,7 +1029,7 @@ impl CPU {
             // ISB/ISC - INC + SBC
             0xE3 | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF => {
                 let a = self.a;
-                let mut b: u8 = bus.read(address);
+                let mut b: u8 = self.read_modify(bus, address);
                 b = b.wrapping_add(1);
                 let c: u8 = if self.flags.intersects(Flags::CARRY) {
                     1
@@ -887,6 +1060,191 @@ impl CPU {
                 );
             }
         }
-        return (self.cycles - old_cycles) as u32;
+
+        // CLI, SEI and PLP change the I flag after interrupts have been
+        // polled, so their effect is delayed by one instruction. RTI
+        // restores the flag before polling and takes effect immediately.
+        self.irq_inhibit = match opcode {
+            0x28 | 0x58 | 0x78 => interrupt_disable,
+            _ => self.flags.contains(Flags::INTERRUPT_DISABLE),
+        };
+
+        // A taken branch that doesn't cross a page polls before its last
+        // cycle, like a two cycle instruction.
+        let cycles = (self.cycles - old_cycles) as u32;
+        self.poll_cycle = Some(if early_poll { cycles - 3 } else { cycles - 2 });
+
+        // Writing $4014 starts an OAM DMA transfer after the write cycle.
+        if let Some(page) = bus.oam_dma_page.take() {
+            self.oam_dma(bus, page);
+        }
+
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::Irq;
+    use cartridge::test_cartridge;
+    use mapper::new_mapper;
+
+    /// CPU and bus running the given program from $0200 in RAM, with the
+    /// NMI handler at $0300 and the IRQ/BRK handler at $0400.
+    fn test_cpu(program: &[u8]) -> (CPU, Bus) {
+        let mut cartridge = test_cartridge(0, 2, 1);
+        cartridge.prg[0x7FFA..].copy_from_slice(&[0x00, 0x03, 0x00, 0x02, 0x00, 0x04]);
+        let mut bus = Bus::new(new_mapper(cartridge).unwrap(), vec![0xEA; 2048]);
+        for (i, &byte) in program.iter().enumerate() {
+            bus.write(0x0200 + i as u16, byte);
+        }
+        let mut cpu = CPU::new();
+        cpu.reset(&mut bus);
+        (cpu, bus)
+    }
+
+    /// Step like the console does, raising the NMI line at the end of the
+    /// given cycle of the instruction.
+    fn step(cpu: &mut CPU, bus: &mut Bus, nmi_cycle: Option<u32>) -> u32 {
+        let cycles = cpu.step(bus);
+        for cycle in 0..cycles {
+            if nmi_cycle == Some(cycle) {
+                bus.nmi_line = true;
+            }
+            cpu.sample_interrupts(bus, cycle);
+        }
+        bus.nmi_line = false;
+        cycles
+    }
+
+    #[test]
+    fn it_polls_interrupts_before_the_last_cycle() {
+        // LDA $0000 takes four cycles and polls at the end of the third.
+        let (mut cpu, mut bus) = test_cpu(&[0xAD, 0x00, 0x00]);
+        assert_eq!(step(&mut cpu, &mut bus, Some(2)), 4);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0300);
+
+        // An NMI on the last cycle waits for the next instruction.
+        let (mut cpu, mut bus) = test_cpu(&[0xAD, 0x00, 0x00]);
+        step(&mut cpu, &mut bus, Some(3));
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0204);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    #[test]
+    fn it_polls_taken_branches_early() {
+        // LDA #$00; BEQ +0 is taken without crossing a page, so it polls at
+        // the end of its first cycle, not its second.
+        let program = [0xA9, 0x00, 0xF0, 0x00];
+        let (mut cpu, mut bus) = test_cpu(&program);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(step(&mut cpu, &mut bus, Some(0)), 3);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0300);
+
+        let (mut cpu, mut bus) = test_cpu(&program);
+        step(&mut cpu, &mut bus, None);
+        step(&mut cpu, &mut bus, Some(1));
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0205);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    #[test]
+    fn it_hijacks_brk_and_irq_with_nmi() {
+        // An NMI early in BRK takes its vector but keeps the B flag.
+        let (mut cpu, mut bus) = test_cpu(&[0x00, 0x00]);
+        step(&mut cpu, &mut bus, Some(2));
+        assert_eq!(cpu.pc, 0x0300);
+        assert_eq!(bus.read(0x01FB), 0x34);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0301);
+
+        // Later, BRK runs and the NMI is serviced after it.
+        let (mut cpu, mut bus) = test_cpu(&[0x00, 0x00]);
+        step(&mut cpu, &mut bus, Some(4));
+        assert_eq!(cpu.pc, 0x0400);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0300);
+
+        // The same goes for an IRQ, which pushes the B flag clear.
+        let (mut cpu, mut bus) = test_cpu(&[0x58, 0xEA]);
+        step(&mut cpu, &mut bus, None);
+        bus.irq_line = Irq::DMC;
+        step(&mut cpu, &mut bus, None);
+        step(&mut cpu, &mut bus, Some(3));
+        assert_eq!(cpu.pc, 0x0300);
+        assert_eq!(bus.read(0x01FB), 0x20);
+    }
+
+    #[test]
+    fn it_delays_irqs_after_cli_sei_and_plp() {
+        // CLI; NOP: the IRQ is taken after the NOP, not after CLI.
+        let (mut cpu, mut bus) = test_cpu(&[0x58, 0xEA]);
+        bus.irq_line = Irq::DMC;
+        step(&mut cpu, &mut bus, None);
+        assert!(!cpu.irq_pending);
+        step(&mut cpu, &mut bus, None);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(bus.read_16(0x01FC), 0x0202);
+
+        // PLP clearing the I flag is delayed the same way.
+        let (mut cpu, mut bus) = test_cpu(&[0x28, 0xEA]);
+        bus.write(0x01FE, 0x20);
+        bus.irq_line = Irq::DMC;
+        step(&mut cpu, &mut bus, None);
+        assert!(!cpu.flags.contains(Flags::INTERRUPT_DISABLE));
+        assert!(!cpu.irq_pending);
+        step(&mut cpu, &mut bus, None);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0400);
+        // PLP popped a byte, so the return address sits one byte higher.
+        assert_eq!(bus.read_16(0x01FD), 0x0202);
+
+        // SEI still lets one IRQ through.
+        let (mut cpu, mut bus) = test_cpu(&[0x78, 0xEA]);
+        cpu.flags.remove(Flags::INTERRUPT_DISABLE);
+        cpu.irq_inhibit = false;
+        bus.irq_line = Irq::DMC;
+        step(&mut cpu, &mut bus, None);
+        assert!(cpu.flags.contains(Flags::INTERRUPT_DISABLE));
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(bus.read_16(0x01FC), 0x0201);
+    }
+
+    #[test]
+    fn it_stalls_for_oam_dma() {
+        // LDA #$02; STA $4014 ends on an even cycle: 513 cycles.
+        let (mut cpu, mut bus) = test_cpu(&[0xA9, 0x02, 0x8D, 0x14, 0x40]);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(step(&mut cpu, &mut bus, None), 4);
+        assert_eq!(cpu.cycles % 2, 0);
+        assert_eq!(step(&mut cpu, &mut bus, None), 513);
+        assert_eq!(&bus.ppu_oam[..5], &[0xA9, 0x02, 0x8D, 0x14, 0x40]);
+        assert_eq!(cpu.pc, 0x0205);
+
+        // LDA #$02; LDX $00; STA $4014 ends on an odd one: 514 cycles.
+        let (mut cpu, mut bus) = test_cpu(&[0xA9, 0x02, 0xA6, 0x00, 0x8D, 0x14, 0x40]);
+        step(&mut cpu, &mut bus, None);
+        step(&mut cpu, &mut bus, None);
+        step(&mut cpu, &mut bus, None);
+        assert_eq!(cpu.cycles % 2, 1);
+        assert_eq!(step(&mut cpu, &mut bus, None), 514);
+
+        // A DMC fetch that halts the CPU at the same time adds its cycles.
+        let (mut cpu, mut bus) = test_cpu(&[0xA9, 0x02, 0x8D, 0x14, 0x40]);
+        step(&mut cpu, &mut bus, None);
+        step(&mut cpu, &mut bus, None);
+        bus.dmc_stall = 4;
+        assert_eq!(step(&mut cpu, &mut bus, None), 517);
+        assert_eq!(bus.dmc_stall, 0);
+        assert_eq!(step(&mut cpu, &mut bus, None), 2);
     }
 }
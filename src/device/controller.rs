@@ -0,0 +1,97 @@
+use super::{Device, Input, Video};
+
+bitflags! {
+    /// Buttons of the standard controller, in the order they are shifted
+    /// out.
+    #[derive(Default)]
+    pub struct Buttons: u8 {
+        const A      = 1 << 0;
+        const B      = 1 << 1;
+        const SELECT = 1 << 2;
+        const START  = 1 << 3;
+        const UP     = 1 << 4;
+        const DOWN   = 1 << 5;
+        const LEFT   = 1 << 6;
+        const RIGHT  = 1 << 7;
+    }
+}
+
+impl Buttons {
+    pub fn from_name(name: &str) -> Option<Buttons> {
+        match name {
+            "a" => Some(Buttons::A),
+            "b" => Some(Buttons::B),
+            "select" => Some(Buttons::SELECT),
+            "start" => Some(Buttons::START),
+            "up" => Some(Buttons::UP),
+            "down" => Some(Buttons::DOWN),
+            "left" => Some(Buttons::LEFT),
+            "right" => Some(Buttons::RIGHT),
+            _ => None,
+        }
+    }
+}
+
+/// Standard controller, a 4021 shift register loaded with the button
+/// states.
+///
+/// While the strobe bit written to $4016 is set the register keeps
+/// reloading, so reads return the state of A. Once it is cleared every read
+/// shifts out the next button. After all eight buttons the official
+/// controllers return 1.
+///
+/// See https://wiki.nesdev.com/w/index.php/Standard_controller
+pub struct Controller {
+    // Player whose buttons the controller reads from the host input
+    pub player: usize,
+    pub buttons: Buttons,
+    pub index: u8,
+    pub strobe: bool,
+    // Only the second controller of a Famicom has a microphone.
+    pub microphone: bool,
+}
+
+impl Controller {
+    pub fn new(player: usize) -> Controller {
+        Controller {
+            player,
+            buttons: Buttons::empty(),
+            index: 0,
+            strobe: false,
+            microphone: false,
+        }
+    }
+}
+
+impl Device for Controller {
+    fn set_input(&mut self, input: &Input) {
+        self.buttons = input.buttons[self.player];
+        self.microphone = self.player == 1 && input.microphone;
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self, _video: &Video) -> u8 {
+        if self.strobe {
+            self.index = 0;
+        }
+        let value = if self.index < 8 {
+            (self.buttons.bits() >> self.index) & 1
+        } else {
+            1
+        };
+        if !self.strobe && self.index < 8 {
+            self.index += 1;
+        }
+        value
+    }
+
+    fn microphone(&self) -> bool {
+        self.microphone
+    }
+}
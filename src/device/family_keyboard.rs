@@ -0,0 +1,79 @@
+use super::{Expansion, Input, Video};
+
+// The key matrix: nine rows of two columns of four keys, read on bits 1-4.
+// Keys that can't be used as script names are spelled out.
+const KEYS: [[&str; 8]; 9] = [
+    ["]", "[", "return", "f8", "stop", "yen", "rshift", "kana"],
+    [";", ":", "@", "f7", "^", "-", "/", "_"],
+    ["k", "l", "o", "f6", "0", "p", "comma", "period"],
+    ["j", "u", "i", "f5", "8", "9", "n", "m"],
+    ["h", "g", "y", "f4", "6", "7", "v", "b"],
+    ["d", "r", "t", "f3", "4", "5", "c", "f"],
+    ["a", "s", "w", "f2", "3", "e", "z", "x"],
+    ["ctr", "q", "esc", "f1", "2", "1", "grph", "lshift"],
+    ["left", "right", "up", "clr", "ins", "del", "space", "down"],
+];
+
+/// Row and bit in `Input::family_keys` of a key, by the name it has in
+/// `KEYS`.
+pub fn key_position(name: &str) -> Option<(usize, u8)> {
+    KEYS.iter().enumerate().filter_map(|(row, keys)| {
+        keys.iter().position(|&key| key == name).map(|bit| (row, bit as u8))
+    }).next()
+}
+
+/// Family BASIC keyboard, on the Famicom expansion port.
+///
+/// Writes to $4016 control it: bit 2 enables it, bit 1 selects the column
+/// and bit 0 returns to the first row. Every time the column goes from 1
+/// back to 0 it moves on to the next row. $4017 bits 1-4 read the four keys
+/// of the selected row and column, 0 while pressed.
+///
+/// See https://wiki.nesdev.com/w/index.php/Family_BASIC_Keyboard
+pub struct FamilyKeyboard {
+    // Keys held in each row, column 0 in bits 0-3 and column 1 in 4-7
+    pub keys: [u8; 9],
+    pub enabled: bool,
+    pub row: usize,
+    pub column: u8,
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> FamilyKeyboard {
+        FamilyKeyboard {
+            keys: [0; 9],
+            enabled: false,
+            row: 0,
+            column: 0,
+        }
+    }
+}
+
+impl Expansion for FamilyKeyboard {
+    fn set_input(&mut self, input: &Input) {
+        self.keys = input.family_keys;
+    }
+
+    fn write(&mut self, value: u8) {
+        let column = value >> 1 & 1;
+        self.enabled = value & 4 != 0;
+        if value & 1 != 0 {
+            self.row = 0;
+        } else if self.enabled && self.column == 1 && column == 0 && self.row < KEYS.len() {
+            self.row += 1;
+        }
+        self.column = column;
+    }
+
+    fn read(&mut self, port: usize, _video: &Video) -> u8 {
+        if port != 1 || !self.enabled {
+            return 0;
+        }
+        // Past the last row no keys are pressed.
+        let keys = match self.keys.get(self.row) {
+            Some(&keys) => keys >> (self.column * 4) & 0x0F,
+            None => 0,
+        };
+        (!keys & 0x0F) << 1
+    }
+}
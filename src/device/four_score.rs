@@ -0,0 +1,115 @@
+use super::{Device, Expansion, Input, Video};
+
+/// Four player adapters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Adapter {
+    /// NES Four Score, plugged into both controller ports
+    FourScore,
+    /// Famicom Hori 4 Players Adapter in 4 player mode, see `Hori`
+    Hori,
+}
+
+/// One side of a four player adapter. The side on port 1 ($4016) carries
+/// players 1 and 3, the side on port 2 ($4017) players 2 and 4.
+///
+/// After strobing, each side shifts out 24 bits: the eight buttons of the
+/// first player, then those of the second, then a signature byte games use
+/// to detect the adapter. Further reads return 1.
+///
+/// See https://wiki.nesdev.com/w/index.php/Four_player_adapters
+pub struct FourScore {
+    pub adapter: Adapter,
+    pub port: usize,
+    // Bits in the order they are read
+    pub bits: u32,
+    pub index: u8,
+    pub strobe: bool,
+}
+
+impl FourScore {
+    pub fn new(adapter: Adapter, port: usize) -> FourScore {
+        FourScore {
+            adapter,
+            port,
+            bits: 0,
+            index: 0,
+            strobe: false,
+        }
+    }
+
+    /// The signature in the order it is read. Games shift the bits in from
+    /// the right, so the first bit read ends up in bit 7: the Four Score
+    /// reads as $10 on $4016 and $20 on $4017, the Hori adapter the other
+    /// way around.
+    fn signature(&self) -> u8 {
+        match (self.adapter, self.port) {
+            (Adapter::FourScore, 0) | (Adapter::Hori, 1) => 0x10u8.reverse_bits(),
+            _ => 0x20u8.reverse_bits(),
+        }
+    }
+}
+
+impl Device for FourScore {
+    fn set_input(&mut self, input: &Input) {
+        let first = input.buttons[self.port].bits() as u32;
+        let second = input.buttons[self.port + 2].bits() as u32;
+        self.bits = first | second << 8 | (self.signature() as u32) << 16;
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self, _video: &Video) -> u8 {
+        if self.strobe {
+            self.index = 0;
+        }
+        let value = if self.index < 24 {
+            (self.bits >> self.index) as u8 & 1
+        } else {
+            1
+        };
+        if !self.strobe && self.index < 24 {
+            self.index += 1;
+        }
+        value
+    }
+}
+
+/// Hori 4 Players Adapter in 4 player mode. It plugs into the Famicom
+/// expansion port and reports its two sides on bit 1 of $4016 and $4017,
+/// next to the Famicom's own controllers on bit 0.
+///
+/// See https://wiki.nesdev.com/w/index.php/Four_player_adapters
+pub struct Hori {
+    pub sides: [FourScore; 2],
+}
+
+impl Hori {
+    pub fn new() -> Hori {
+        Hori {
+            sides: [FourScore::new(Adapter::Hori, 0), FourScore::new(Adapter::Hori, 1)],
+        }
+    }
+}
+
+impl Expansion for Hori {
+    fn set_input(&mut self, input: &Input) {
+        for side in self.sides.iter_mut() {
+            side.set_input(input);
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        for side in self.sides.iter_mut() {
+            side.write(value);
+        }
+    }
+
+    fn read(&mut self, port: usize, video: &Video) -> u8 {
+        self.sides[port].read(video) << 1
+    }
+}
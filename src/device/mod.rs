@@ -0,0 +1,403 @@
+mod controller;
+mod family_keyboard;
+mod four_score;
+mod power_pad;
+mod snes_mouse;
+mod vaus;
+mod zapper;
+
+pub use self::controller::{Buttons, Controller};
+pub use self::family_keyboard::{key_position, FamilyKeyboard};
+pub use self::four_score::{Adapter, FourScore, Hori};
+pub use self::power_pad::PowerPad;
+pub use self::snes_mouse::SNESMouse;
+pub use self::vaus::Vaus;
+pub use self::zapper::Zapper;
+
+/// State of the host's input devices, updated by the platform layer once
+/// per frame. Each device picks out the parts it uses.
+#[derive(Clone, Default)]
+pub struct Input {
+    // Buttons held on each player's controller
+    pub buttons: [Buttons; 4],
+    // Mouse position in picture coordinates, None while it is outside the
+    // picture
+    pub cursor: Option<(i32, i32)>,
+    pub left_button: bool,
+    pub right_button: bool,
+    // Relative mouse motion since the last update, in mouse counts
+    pub mouse_motion: (i32, i32),
+    // Power Pad buttons, bit n is button n + 1
+    pub power_pad: u16,
+    // Family BASIC keyboard keys held, see `family_keyboard::KEYS`
+    pub family_keys: [u8; 9],
+    // Blowing into the microphone of the Famicom's second controller
+    pub microphone: bool,
+}
+
+/// What devices watching the screen can see: the picture and where the
+/// PPU is drawing it.
+pub struct Video<'a> {
+    pub pixels: &'a [u32],
+    pub scan_line: u32,
+    pub cycle: u32,
+}
+
+/// Something plugged into a controller port. Port 1 is read from $4016,
+/// port 2 from $4017, and both see the writes to $4016.
+pub trait Device {
+    /// Take the current state of the host's input.
+    fn set_input(&mut self, _input: &Input) {}
+
+    /// Write to $4016. Bit 0 is the strobe (OUT0) line.
+    fn write(&mut self, value: u8);
+
+    /// Read the data lines, bits 0-4. The bus fills in the other bits.
+    fn read(&mut self, video: &Video) -> u8;
+
+    /// True while the microphone of a Famicom second controller picks up
+    /// sound. It is read from bit 2 of $4016.
+    fn microphone(&self) -> bool {
+        false
+    }
+}
+
+/// Something plugged into the Famicom expansion port. It sees all three
+/// OUT lines written to $4016 and drives bits 1-4 of both $4016 and $4017.
+///
+/// See https://wiki.nesdev.com/w/index.php/Expansion_port
+pub trait Expansion {
+    /// Take the current state of the host's input.
+    fn set_input(&mut self, _input: &Input) {}
+
+    /// Write to $4016, bits 0-2.
+    fn write(&mut self, value: u8);
+
+    /// Read bits 1-4 of $4016 (port 0) or $4017 (port 1).
+    fn read(&mut self, port: usize, video: &Video) -> u8;
+}
+
+/// Devices that can be plugged into the ports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceKind {
+    Controller,
+    Zapper,
+    Vaus,
+    PowerPad,
+    SNESMouse,
+    // Takes both ports
+    FourScore,
+}
+
+impl DeviceKind {
+    pub fn from_name(name: &str) -> Option<DeviceKind> {
+        match name {
+            "controller" => Some(DeviceKind::Controller),
+            "zapper" => Some(DeviceKind::Zapper),
+            "vaus" => Some(DeviceKind::Vaus),
+            "powerpad" => Some(DeviceKind::PowerPad),
+            "snesmouse" => Some(DeviceKind::SNESMouse),
+            "fourscore" => Some(DeviceKind::FourScore),
+            _ => None,
+        }
+    }
+
+    pub fn is_multitap(&self) -> bool {
+        *self == DeviceKind::FourScore
+    }
+
+    /// True for devices that take relative mouse motion.
+    pub fn uses_mouse_motion(&self) -> bool {
+        *self == DeviceKind::Vaus || *self == DeviceKind::SNESMouse
+    }
+}
+
+/// Devices that can be plugged into the expansion port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpansionKind {
+    FamilyKeyboard,
+    Hori,
+}
+
+impl ExpansionKind {
+    pub fn from_name(name: &str) -> Option<ExpansionKind> {
+        match name {
+            "keyboard" => Some(ExpansionKind::FamilyKeyboard),
+            "hori" => Some(ExpansionKind::Hori),
+            _ => None,
+        }
+    }
+}
+
+pub fn new_expansion(kind: ExpansionKind) -> Box<dyn Expansion> {
+    match kind {
+        ExpansionKind::FamilyKeyboard => Box::new(FamilyKeyboard::new()),
+        ExpansionKind::Hori => Box::new(Hori::new()),
+    }
+}
+
+/// Create a device for port 0 (port 1 on the console) or 1 (port 2).
+pub fn new_device(kind: DeviceKind, port: usize) -> Box<dyn Device> {
+    match kind {
+        DeviceKind::Controller => Box::new(Controller::new(port)),
+        DeviceKind::Zapper => Box::new(Zapper::new()),
+        DeviceKind::Vaus => Box::new(Vaus::new()),
+        DeviceKind::PowerPad => Box::new(PowerPad::new()),
+        DeviceKind::SNESMouse => Box::new(SNESMouse::new()),
+        DeviceKind::FourScore => Box::new(FourScore::new(Adapter::FourScore, port)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::{test_bus, Bus, BUFFER_WIDTH};
+
+    fn no_video() -> Video<'static> {
+        Video {
+            pixels: &[],
+            scan_line: 0,
+            cycle: 0,
+        }
+    }
+
+    #[test]
+    fn it_shifts_out_buttons() {
+        let mut controller = Controller::new(0);
+        controller.buttons = Buttons::A | Buttons::START | Buttons::RIGHT;
+
+        // Strobing keeps returning A.
+        controller.write(1);
+        assert_eq!(controller.read(&no_video()), 1);
+        assert_eq!(controller.read(&no_video()), 1);
+        controller.buttons.remove(Buttons::A);
+        assert_eq!(controller.read(&no_video()), 0);
+        controller.buttons.insert(Buttons::A);
+
+        controller.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read(&no_video())).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+
+        // Strobing again starts over.
+        controller.write(1);
+        controller.write(0);
+        assert_eq!(controller.read(&no_video()), 1);
+        assert_eq!(controller.read(&no_video()), 0);
+    }
+
+    #[test]
+    fn it_reads_controllers_with_open_bus() {
+        let mut bus = test_bus();
+        let mut input = Input::default();
+        input.buttons[0] = Buttons::A;
+        input.buttons[1] = Buttons::B;
+        bus.set_input(&input);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+
+        // LDA $4016 leaves the high byte of the address on the bus.
+        bus.open_bus = 0x40;
+        assert_eq!(bus.read(0x4016), 0x41);
+        assert_eq!(bus.read(0x4016), 0x40);
+        bus.open_bus = 0x40;
+        assert_eq!(bus.read(0x4017), 0x40);
+        assert_eq!(bus.read(0x4017), 0x41);
+    }
+
+    #[test]
+    fn it_reads_four_players_and_the_signature() {
+        let mut bus = test_bus();
+        bus.port1 = new_device(DeviceKind::FourScore, 0);
+        bus.port2 = new_device(DeviceKind::FourScore, 1);
+        let input = Input {
+            buttons: [Buttons::A, Buttons::B, Buttons::SELECT, Buttons::START],
+            ..Input::default()
+        };
+        bus.set_input(&input);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+
+        // Shift the bits in like games do, first read ending up in bit 7.
+        let read_byte = |bus: &mut Bus, address, bit: u8| {
+            (0..8).fold(0, |byte, _| byte << 1 | (bus.read(address) >> bit) & 1)
+        };
+        let port1: Vec<u8> = (0..4).map(|_| read_byte(&mut bus, 0x4016, 0)).collect();
+        let port2: Vec<u8> = (0..4).map(|_| read_byte(&mut bus, 0x4017, 0)).collect();
+        assert_eq!(port1, vec![0x80, 0x20, 0x10, 0xFF]);
+        assert_eq!(port2, vec![0x40, 0x10, 0x20, 0xFF]);
+
+        // The Hori adapter swaps the signatures and reports on bit 1, with
+        // the Famicom's controllers staying on bit 0.
+        let mut bus = test_bus();
+        bus.expansion = Some(new_expansion(ExpansionKind::Hori));
+        bus.set_input(&input);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        let port1: Vec<u8> = (0..3).map(|_| read_byte(&mut bus, 0x4016, 1)).collect();
+        let port2: Vec<u8> = (0..3).map(|_| read_byte(&mut bus, 0x4017, 1)).collect();
+        assert_eq!(port1, vec![0x80, 0x20, 0x20]);
+        assert_eq!(port2, vec![0x40, 0x10, 0x10]);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        assert_eq!(read_byte(&mut bus, 0x4016, 0), 0x80);
+        assert_eq!(read_byte(&mut bus, 0x4017, 0), 0x40);
+        assert_eq!(read_byte(&mut bus, 0x4016, 0), 0xFF);
+    }
+
+    #[test]
+    fn it_reads_the_vaus_knob_inverted() {
+        let mut vaus = Vaus::new();
+        let mut input = Input {
+            mouse_motion: (0xA5 - vaus.knob, 0),
+            left_button: true,
+            ..Input::default()
+        };
+        vaus.set_input(&input);
+        vaus.write(1);
+        vaus.write(0);
+
+        let bits: Vec<u8> = (0..9).map(|_| vaus.read(&no_video())).collect();
+        // !$A5 = %0101 1010, with the fire button on bit 3
+        assert_eq!(bits, vec![0x08, 0x18, 0x08, 0x18, 0x18, 0x08, 0x18, 0x08, 0x08]);
+
+        // The knob stops at the end of its range.
+        input.mouse_motion = (1000, 0);
+        vaus.set_input(&input);
+        assert_eq!(vaus.knob, vaus::KNOB_MAX);
+    }
+
+    #[test]
+    fn it_reads_the_power_pad() {
+        let mut power_pad = PowerPad::new();
+        let input = Input {
+            // Buttons 1, 4 and 7
+            power_pad: 1 << 0 | 1 << 3 | 1 << 6,
+            ..Input::default()
+        };
+        power_pad.set_input(&input);
+        power_pad.write(1);
+        power_pad.write(0);
+
+        let bits: Vec<u8> = (0..9).map(|_| power_pad.read(&no_video())).collect();
+        assert_eq!(bits, vec![0x10, 0x08, 0x00, 0x00, 0x10, 0x10, 0x10, 0x18, 0x18]);
+    }
+
+    #[test]
+    fn it_reports_snes_mouse_motion() {
+        let mut mouse = SNESMouse::new();
+        let input = Input {
+            mouse_motion: (-3, 200),
+            right_button: true,
+            ..Input::default()
+        };
+        mouse.set_input(&input);
+        mouse.write(1);
+        mouse.write(0);
+
+        let read_byte = |mouse: &mut SNESMouse| {
+            (0..8).fold(0, |byte, _| byte << 1 | mouse.read(&no_video()))
+        };
+        let bytes: Vec<u8> = (0..5).map(|_| read_byte(&mut mouse)).collect();
+        assert_eq!(bytes, vec![0x00, 0x81, 0x7F, 0x83, 0xFF]);
+
+        // The motion was reset by the latch. Reading with the strobe set
+        // changes the sensitivity.
+        mouse.write(1);
+        mouse.read(&no_video());
+        mouse.write(0);
+        let bytes: Vec<u8> = (0..4).map(|_| read_byte(&mut mouse)).collect();
+        assert_eq!(bytes, vec![0x00, 0x91, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn it_scans_the_family_keyboard() {
+        let mut bus = test_bus();
+        bus.expansion = Some(new_expansion(ExpansionKind::FamilyKeyboard));
+        let mut input = Input::default();
+        for name in &["return", "a", "x", "space"] {
+            let (row, bit) = key_position(name).unwrap();
+            input.family_keys[row] |= 1 << bit;
+        }
+        bus.set_input(&input);
+
+        // Read the whole matrix like Family BASIC does: reset, then select
+        // column 0 and column 1 of each row in turn.
+        bus.write(0x4016, 0x05);
+        let mut matrix = Vec::new();
+        for _ in 0..9 {
+            bus.write(0x4016, 0x04);
+            matrix.push(bus.read(0x4017) & 0x1E);
+            bus.write(0x4016, 0x06);
+            matrix.push(bus.read(0x4017) & 0x1E);
+        }
+        let mut expected = vec![0x1E; 18];
+        expected[0] = 0x16; // return
+        expected[12] = 0x1C; // a
+        expected[13] = 0x0E; // x
+        expected[17] = 0x16; // space
+        assert_eq!(matrix, expected);
+
+        // Disabled, the keyboard leaves the lines alone.
+        bus.write(0x4016, 0x00);
+        assert_eq!(bus.read(0x4017) & 0x1E, 0x00);
+    }
+
+    #[test]
+    fn it_reads_the_microphone() {
+        let mut bus = test_bus();
+        bus.open_bus = 0x40;
+        assert_eq!(bus.read(0x4016), 0x40);
+        let input = Input {
+            microphone: true,
+            ..Input::default()
+        };
+        bus.set_input(&input);
+        assert_eq!(bus.read(0x4016), 0x44);
+
+        // Only the second controller has a microphone.
+        bus.port2 = new_device(DeviceKind::Zapper, 1);
+        bus.set_input(&input);
+        assert_eq!(bus.read(0x4016) & 0x04, 0x00);
+    }
+
+    #[test]
+    fn it_senses_light_behind_the_beam() {
+        let mut bus = test_bus();
+        bus.port2 = new_device(DeviceKind::Zapper, 1);
+        // A white box around (100, 50) on a black screen
+        for y in 48..53 {
+            for x in 98..103 {
+                bus.ppu_pixels[y * BUFFER_WIDTH + x] = 0xFFFEFF;
+            }
+        }
+        let mut input = Input {
+            cursor: Some((100, 50)),
+            ..Input::default()
+        };
+        bus.set_input(&input);
+        bus.open_bus = 0x40;
+
+        let read_at = |bus: &mut Bus, scan_line, cycle| {
+            bus.ppu_scan_line = scan_line;
+            bus.ppu_cycle = cycle;
+            bus.read(0x4017) & 0x18
+        };
+        // Not drawn yet in this frame
+        assert_eq!(read_at(&mut bus, 47, 200), 0x08);
+        assert_eq!(read_at(&mut bus, 48, 98), 0x08);
+        // The beam passes the box
+        assert_eq!(read_at(&mut bus, 48, 99), 0x00);
+        assert_eq!(read_at(&mut bus, 60, 0), 0x00);
+        // and the sensor goes dark again.
+        assert_eq!(read_at(&mut bus, 73, 0), 0x08);
+        assert_eq!(read_at(&mut bus, 241, 0), 0x08);
+
+        input.left_button = true;
+        bus.set_input(&input);
+        assert_eq!(read_at(&mut bus, 50, 120), 0x10);
+        // Aimed away from the screen
+        input.right_button = true;
+        bus.set_input(&input);
+        assert_eq!(read_at(&mut bus, 50, 120), 0x18);
+    }
+}
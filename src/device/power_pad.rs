@@ -0,0 +1,60 @@
+use super::{Device, Input, Video};
+
+// Buttons shifted out on bits 3 and 4, numbered 1-12 like on side B of the
+// mat
+const BIT3_BUTTONS: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const BIT4_BUTTONS: [u8; 4] = [4, 3, 12, 8];
+
+/// Power Pad (Family Trainer) mat with twelve buttons in three rows of
+/// four.
+///
+/// The buttons are split over two shift registers. After strobing, bit 3
+/// shifts out eight of them and bit 4 the other four, followed by 1s.
+///
+/// See https://wiki.nesdev.com/w/index.php/Power_Pad
+pub struct PowerPad {
+    // Bit n is button n + 1, 1 while pressed
+    pub buttons: u16,
+    pub index: u8,
+    pub strobe: bool,
+}
+
+impl PowerPad {
+    pub fn new() -> PowerPad {
+        PowerPad {
+            buttons: 0,
+            index: 0,
+            strobe: false,
+        }
+    }
+
+    fn pressed(&self, button: u8) -> u8 {
+        (self.buttons >> (button - 1)) as u8 & 1
+    }
+}
+
+impl Device for PowerPad {
+    fn set_input(&mut self, input: &Input) {
+        self.buttons = input.power_pad;
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self, _video: &Video) -> u8 {
+        if self.strobe {
+            self.index = 0;
+        }
+        let index = self.index as usize;
+        let bit3 = BIT3_BUTTONS.get(index).map_or(1, |&button| self.pressed(button));
+        let bit4 = BIT4_BUTTONS.get(index).map_or(1, |&button| self.pressed(button));
+        if !self.strobe && self.index < 8 {
+            self.index += 1;
+        }
+        bit4 << 4 | bit3 << 3
+    }
+}
@@ -0,0 +1,87 @@
+use super::{Device, Input, Video};
+
+/// Super NES mouse, connected through a port adapter.
+///
+/// Setting the strobe latches 32 bits, shifted out on bit 0 most
+/// significant bit first:
+///
+///     0000 0000  RLSS 0001  Yyyy yyyy  Xxxx xxxx
+///
+/// R and L are the buttons, SS the sensitivity and 0001 the signature. Y
+/// and X are the motion since the last latch as a direction bit (1 for up
+/// or left) and a 7 bit magnitude. Further reads return 1. Reading while
+/// the strobe is set cycles through the three sensitivities.
+///
+/// See https://wiki.nesdev.com/w/index.php/Super_NES_Mouse
+pub struct SNESMouse {
+    pub left_button: bool,
+    pub right_button: bool,
+    pub sensitivity: u8,
+    // Motion since the last latch
+    pub dx: i32,
+    pub dy: i32,
+    pub latch: u32,
+    pub index: u8,
+    pub strobe: bool,
+}
+
+impl SNESMouse {
+    pub fn new() -> SNESMouse {
+        SNESMouse {
+            left_button: false,
+            right_button: false,
+            sensitivity: 0,
+            dx: 0,
+            dy: 0,
+            latch: 0,
+            index: 0,
+            strobe: false,
+        }
+    }
+
+    fn latch(&mut self) {
+        let motion = |delta: i32, direction: bool| {
+            (direction as u32) << 7 | delta.abs().min(0x7F) as u32
+        };
+        self.latch = (self.right_button as u32) << 23 | (self.left_button as u32) << 22
+            | (self.sensitivity as u32) << 20 | 1 << 16
+            | motion(self.dy, self.dy < 0) << 8 | motion(self.dx, self.dx < 0);
+        self.dx = 0;
+        self.dy = 0;
+    }
+}
+
+impl Device for SNESMouse {
+    fn set_input(&mut self, input: &Input) {
+        let (dx, dy) = input.mouse_motion;
+        self.dx += dx;
+        self.dy += dy;
+        self.left_button = input.left_button;
+        self.right_button = input.right_button;
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.latch();
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self, _video: &Video) -> u8 {
+        if self.strobe {
+            self.sensitivity = (self.sensitivity + 1) % 3;
+            self.latch();
+            self.index = 0;
+        }
+        let bit = if self.index < 32 {
+            (self.latch >> (31 - self.index)) as u8 & 1
+        } else {
+            1
+        };
+        if !self.strobe && self.index < 32 {
+            self.index += 1;
+        }
+        bit
+    }
+}
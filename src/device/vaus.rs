@@ -0,0 +1,62 @@
+use super::{Device, Input, Video};
+
+// Range the knob turns through. Mouse motion turns it one step per count.
+pub const KNOB_MIN: i32 = 0x54;
+pub const KNOB_MAX: i32 = 0xF4;
+
+/// Arkanoid Vaus controller, NES version. A potentiometer turned by the
+/// knob and a fire button.
+///
+/// Setting the strobe latches the knob's position, which is then shifted
+/// out on bit 4, most significant bit first and inverted. The fire button
+/// is read directly from bit 3.
+///
+/// See https://wiki.nesdev.com/w/index.php/Arkanoid_controller
+pub struct Vaus {
+    pub knob: i32,
+    pub fire: bool,
+    pub latch: u8,
+    pub index: u8,
+    pub strobe: bool,
+}
+
+impl Vaus {
+    pub fn new() -> Vaus {
+        Vaus {
+            knob: (KNOB_MIN + KNOB_MAX) / 2,
+            fire: false,
+            latch: 0,
+            index: 0,
+            strobe: false,
+        }
+    }
+}
+
+impl Device for Vaus {
+    /// Horizontal mouse motion turns the knob, the left button fires.
+    fn set_input(&mut self, input: &Input) {
+        let (dx, _) = input.mouse_motion;
+        self.knob = (self.knob + dx).clamp(KNOB_MIN, KNOB_MAX);
+        self.fire = input.left_button;
+    }
+
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.latch = self.knob as u8;
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self, _video: &Video) -> u8 {
+        let bit = if self.index < 8 {
+            !self.latch >> (7 - self.index) & 1
+        } else {
+            0
+        };
+        if !self.strobe && self.index < 8 {
+            self.index += 1;
+        }
+        bit << 4 | (self.fire as u8) << 3
+    }
+}
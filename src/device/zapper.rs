@@ -0,0 +1,100 @@
+use std::cmp;
+
+use bus::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+use super::{Device, Input, Video};
+
+// Half the size of the square around the aim point the light sensor sees,
+// in pixels
+const SENSE_RADIUS: i32 = 2;
+// The sensor keeps reporting light for a while after the beam passed. Games
+// poll it for roughly this many scan lines.
+const SENSE_LINES: i32 = 20;
+// Brightness (0-255) of a pixel the sensor picks up
+const LIGHT_THRESHOLD: u32 = 85;
+
+/// Zapper light gun. It has no shift register, the trigger and the light
+/// sensor are read directly from bits 4 and 3.
+///
+/// The sensor only sees light while the beam draws bright pixels near
+/// where the gun is aimed, and for a short time after. Games make the
+/// screen black with white boxes over the targets for a frame and check
+/// the sensor while that frame is drawn.
+///
+/// See https://wiki.nesdev.com/w/index.php/Zapper
+pub struct Zapper {
+    // Aim point in picture coordinates, None when aimed away from the
+    // screen
+    pub cursor: Option<(i32, i32)>,
+    pub trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper {
+            cursor: None,
+            trigger: false,
+        }
+    }
+
+    /// True if the beam drew a bright pixel around the aim point within the
+    /// last `SENSE_LINES` scan lines. Pixels the beam has not reached yet
+    /// in this frame still hold the previous frame and are skipped.
+    pub fn light_sensed(&self, video: &Video) -> bool {
+        let (x, y) = match self.cursor {
+            Some(cursor) => cursor,
+            None => return false,
+        };
+        let scan_line = video.scan_line as i32;
+        // Dot 1 outputs pixel 0.
+        let beam_x = video.cycle as i32 - 1;
+
+        let top = cmp::max(y - SENSE_RADIUS, 0);
+        let bottom = cmp::min(y + SENSE_RADIUS, BUFFER_HEIGHT as i32 - 1);
+        let left = cmp::max(x - SENSE_RADIUS, 0);
+        let right = cmp::min(x + SENSE_RADIUS, BUFFER_WIDTH as i32 - 1);
+        for pixel_y in top..bottom + 1 {
+            if scan_line < pixel_y || scan_line - pixel_y > SENSE_LINES {
+                continue;
+            }
+            for pixel_x in left..right + 1 {
+                if scan_line == pixel_y && beam_x < pixel_x {
+                    break;
+                }
+                let pixel = video.pixels[pixel_y as usize * BUFFER_WIDTH + pixel_x as usize];
+                if brightness(pixel) >= LIGHT_THRESHOLD {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Device for Zapper {
+    /// The left mouse button pulls the trigger. The right one pulls it with
+    /// the gun aimed away from the screen, which some games use to reload.
+    fn set_input(&mut self, input: &Input) {
+        self.trigger = input.left_button || input.right_button;
+        self.cursor = if input.right_button {
+            None
+        } else {
+            input.cursor
+        };
+    }
+
+    fn write(&mut self, _value: u8) {}
+
+    fn read(&mut self, video: &Video) -> u8 {
+        let light = if self.light_sensed(video) { 0 } else { 1 };
+        (self.trigger as u8) << 4 | light << 3
+    }
+}
+
+/// Perceived brightness of an RGB pixel.
+fn brightness(pixel: u32) -> u32 {
+    let r = (pixel >> 16) & 0xFF;
+    let g = (pixel >> 8) & 0xFF;
+    let b = pixel & 0xFF;
+    (r * 299 + g * 587 + b * 114) / 1000
+}
@@ -0,0 +1,46 @@
+use std::f32::consts::PI;
+
+/// First order IIR filter. The console's audio output path has two
+/// high-pass filters (90 Hz and 440 Hz) and a 14 kHz low-pass filter.
+///
+/// See https://wiki.nesdev.com/w/index.php/APU_Mixer
+pub struct Filter {
+    pub b0: f32,
+    pub b1: f32,
+    pub a1: f32,
+    pub previous_x: f32,
+    pub previous_y: f32,
+}
+
+impl Filter {
+    pub fn low_pass(sample_rate: f32, cutoff: f32) -> Filter {
+        let c = sample_rate / PI / cutoff;
+        let a0i = 1.0 / (1.0 + c);
+        Filter {
+            b0: a0i,
+            b1: a0i,
+            a1: (1.0 - c) * a0i,
+            previous_x: 0.0,
+            previous_y: 0.0,
+        }
+    }
+
+    pub fn high_pass(sample_rate: f32, cutoff: f32) -> Filter {
+        let c = sample_rate / PI / cutoff;
+        let a0i = 1.0 / (1.0 + c);
+        Filter {
+            b0: c * a0i,
+            b1: -c * a0i,
+            a1: (1.0 - c) * a0i,
+            previous_x: 0.0,
+            previous_y: 0.0,
+        }
+    }
+
+    pub fn step(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.previous_x - self.a1 * self.previous_y;
+        self.previous_x = x;
+        self.previous_y = y;
+        y
+    }
+}
@@ -0,0 +1,250 @@
+// Maps the host's keyboard and gamepads to the buttons of the players'
+// controllers. Part of the SDL frontend, not of the emulated console.
+
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::keyboard::{KeyboardState, Keycode, Scancode};
+
+use device::{key_position, Buttons};
+
+// How far a stick has to be pushed to count as pressing a direction
+const AXIS_THRESHOLD: i16 = 16_384;
+
+// Keys for Power Pad buttons 1-12, laid out in three rows of four like the
+// mat
+const POWER_PAD_KEYS: [Keycode; 12] = [
+    Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+    Keycode::Q, Keycode::W, Keycode::E, Keycode::R,
+    Keycode::A, Keycode::S, Keycode::D, Keycode::F,
+];
+
+// Host keys for the Family BASIC keyboard's keys, mostly where they are on
+// a US keyboard
+const FAMILY_KEYS: [(Keycode, &str); 73] = [
+    (Keycode::RightBracket, "]"), (Keycode::LeftBracket, "["), (Keycode::Return, "return"),
+    (Keycode::F8, "f8"), (Keycode::End, "stop"), (Keycode::Backslash, "yen"),
+    (Keycode::RShift, "rshift"), (Keycode::RAlt, "kana"), (Keycode::Semicolon, ";"),
+    (Keycode::Quote, ":"), (Keycode::Backquote, "@"), (Keycode::F7, "f7"),
+    (Keycode::Equals, "^"), (Keycode::Minus, "-"), (Keycode::Slash, "/"),
+    (Keycode::RCtrl, "_"), (Keycode::K, "k"), (Keycode::L, "l"), (Keycode::O, "o"),
+    (Keycode::F6, "f6"), (Keycode::Num0, "0"), (Keycode::P, "p"), (Keycode::Comma, "comma"),
+    (Keycode::Period, "period"), (Keycode::J, "j"), (Keycode::U, "u"), (Keycode::I, "i"),
+    (Keycode::F5, "f5"), (Keycode::Num8, "8"), (Keycode::Num9, "9"), (Keycode::N, "n"),
+    (Keycode::M, "m"), (Keycode::H, "h"), (Keycode::G, "g"), (Keycode::Y, "y"),
+    (Keycode::F4, "f4"), (Keycode::Num6, "6"), (Keycode::Num7, "7"), (Keycode::V, "v"),
+    (Keycode::B, "b"), (Keycode::D, "d"), (Keycode::R, "r"), (Keycode::T, "t"),
+    (Keycode::F3, "f3"), (Keycode::Num4, "4"), (Keycode::Num5, "5"), (Keycode::C, "c"),
+    (Keycode::F, "f"), (Keycode::A, "a"), (Keycode::S, "s"), (Keycode::W, "w"),
+    (Keycode::F2, "f2"), (Keycode::Num3, "3"), (Keycode::E, "e"), (Keycode::Z, "z"),
+    (Keycode::X, "x"), (Keycode::LCtrl, "ctr"), (Keycode::Q, "q"), (Keycode::Escape, "esc"),
+    (Keycode::F1, "f1"), (Keycode::Num2, "2"), (Keycode::Num1, "1"), (Keycode::LAlt, "grph"),
+    (Keycode::LShift, "lshift"), (Keycode::Left, "left"), (Keycode::Right, "right"),
+    (Keycode::Up, "up"), (Keycode::Home, "clr"), (Keycode::Insert, "ins"),
+    (Keycode::Backspace, "del"), (Keycode::Delete, "del"), (Keycode::Space, "space"),
+    (Keycode::Down, "down"),
+];
+
+/// A key, gamepad button or half of a gamepad axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Key(Keycode),
+    PadButton(Button),
+    /// The axis pushed in the positive (true) or negative direction.
+    PadAxis(Axis, bool),
+}
+
+impl Source {
+    /// Parse an SDL key name like `Z` or `Left Shift`, or a gamepad button
+    /// or axis in SDL's mapping string format prefixed with `pad:`, like
+    /// `pad:a`, `pad:dpup` or `pad:leftx-`.
+    pub fn from_name(name: &str) -> Option<Source> {
+        if !name.starts_with("pad:") {
+            return Keycode::from_name(name).map(Source::Key);
+        }
+        let name = &name[4..];
+        if name.ends_with('+') || name.ends_with('-') {
+            let (axis, direction) = name.split_at(name.len() - 1);
+            Axis::from_string(axis).map(|axis| Source::PadAxis(axis, direction == "+"))
+        } else {
+            Button::from_string(name).map(Source::PadButton)
+        }
+    }
+
+    fn is_key(&self) -> bool {
+        matches!(*self, Source::Key(_))
+    }
+}
+
+/// Connects a source to a button of a player's controller (0-3).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Binding {
+    pub player: usize,
+    pub button: Buttons,
+    pub source: Source,
+}
+
+/// Parse a `PLAYER:BUTTON=SOURCE` binding, like `1:a=X` or
+/// `2:start=pad:start`.
+pub fn parse_binding(value: &str) -> Option<Binding> {
+    let mut parts = value.splitn(2, '=');
+    let mut target = parts.next()?.splitn(2, ':');
+    let player = match target.next()? {
+        "1" => 0,
+        "2" => 1,
+        "3" => 2,
+        "4" => 3,
+        _ => return None,
+    };
+    let button = Buttons::from_name(target.next()?)?;
+    let source = Source::from_name(parts.next()?)?;
+    Some(Binding {
+        player,
+        button,
+        source,
+    })
+}
+
+pub struct InputMap {
+    pub bindings: Vec<Binding>,
+    // Makes noise into the microphone of the Famicom's second controller
+    pub microphone: Source,
+}
+
+impl InputMap {
+    /// The default mapping. Player 1 plays with the arrow keys, Z (B), X
+    /// (A), right shift (Select) and return (Start), player 2 with WASD, G,
+    /// H, T and Y. Each player also gets a gamepad, with the buttons laid
+    /// out like on a Nintendo pad: the bottom face button is B, the right
+    /// one A.
+    pub fn new() -> InputMap {
+        let keys = [
+            [
+                (Buttons::UP, Keycode::Up),
+                (Buttons::DOWN, Keycode::Down),
+                (Buttons::LEFT, Keycode::Left),
+                (Buttons::RIGHT, Keycode::Right),
+                (Buttons::B, Keycode::Z),
+                (Buttons::A, Keycode::X),
+                (Buttons::SELECT, Keycode::RShift),
+                (Buttons::START, Keycode::Return),
+            ],
+            [
+                (Buttons::UP, Keycode::W),
+                (Buttons::DOWN, Keycode::S),
+                (Buttons::LEFT, Keycode::A),
+                (Buttons::RIGHT, Keycode::D),
+                (Buttons::B, Keycode::G),
+                (Buttons::A, Keycode::H),
+                (Buttons::SELECT, Keycode::T),
+                (Buttons::START, Keycode::Y),
+            ],
+        ];
+        let pad = [
+            (Buttons::UP, Source::PadButton(Button::DPadUp)),
+            (Buttons::DOWN, Source::PadButton(Button::DPadDown)),
+            (Buttons::LEFT, Source::PadButton(Button::DPadLeft)),
+            (Buttons::RIGHT, Source::PadButton(Button::DPadRight)),
+            (Buttons::UP, Source::PadAxis(Axis::LeftY, false)),
+            (Buttons::DOWN, Source::PadAxis(Axis::LeftY, true)),
+            (Buttons::LEFT, Source::PadAxis(Axis::LeftX, false)),
+            (Buttons::RIGHT, Source::PadAxis(Axis::LeftX, true)),
+            (Buttons::B, Source::PadButton(Button::A)),
+            (Buttons::A, Source::PadButton(Button::B)),
+            (Buttons::SELECT, Source::PadButton(Button::Back)),
+            (Buttons::START, Source::PadButton(Button::Start)),
+        ];
+
+        let mut bindings = Vec::new();
+        for (player, keys) in keys.iter().enumerate() {
+            for &(button, key) in keys {
+                bindings.push(Binding {
+                    player,
+                    button,
+                    source: Source::Key(key),
+                });
+            }
+        }
+        for player in 0..4 {
+            for &(button, source) in &pad {
+                bindings.push(Binding {
+                    player,
+                    button,
+                    source,
+                });
+            }
+        }
+        InputMap {
+            bindings,
+            microphone: Source::Key(Keycode::M),
+        }
+    }
+
+    /// Add a binding. It replaces the button's existing keyboard bindings
+    /// for a key, or its gamepad bindings for a gamepad source.
+    pub fn bind(&mut self, binding: Binding) {
+        self.bindings.retain(|existing| {
+            existing.player != binding.player || existing.button != binding.button
+                || existing.source.is_key() != binding.source.is_key()
+        });
+        self.bindings.push(binding);
+    }
+
+    /// The buttons held on `player`'s controller, from the keyboard and the
+    /// gamepad assigned to the player.
+    pub fn buttons(
+        &self,
+        player: usize,
+        keyboard: &KeyboardState,
+        pad: Option<&GameController>,
+    ) -> Buttons {
+        let mut buttons = Buttons::empty();
+        for binding in self.bindings.iter().filter(|binding| binding.player == player) {
+            if pressed(binding.source, keyboard, pad) {
+                buttons.insert(binding.button);
+            }
+        }
+        buttons
+    }
+
+    /// True while the microphone source is held, with the second player's
+    /// gamepad for gamepad sources.
+    pub fn microphone(&self, keyboard: &KeyboardState, pad: Option<&GameController>) -> bool {
+        pressed(self.microphone, keyboard, pad)
+    }
+}
+
+fn pressed(source: Source, keyboard: &KeyboardState, pad: Option<&GameController>) -> bool {
+    match (source, pad) {
+        (Source::Key(key), _) => key_pressed(keyboard, key),
+        (Source::PadButton(button), Some(pad)) => pad.button(button),
+        (Source::PadAxis(axis, true), Some(pad)) => pad.axis(axis) > AXIS_THRESHOLD,
+        (Source::PadAxis(axis, false), Some(pad)) => pad.axis(axis) < -AXIS_THRESHOLD,
+        (_, None) => false,
+    }
+}
+
+fn key_pressed(keyboard: &KeyboardState, key: Keycode) -> bool {
+    Scancode::from_keycode(key).is_some_and(|scancode| keyboard.is_scancode_pressed(scancode))
+}
+
+/// The Power Pad buttons held, bit n for button n + 1.
+pub fn power_pad_buttons(keyboard: &KeyboardState) -> u16 {
+    POWER_PAD_KEYS
+        .iter()
+        .enumerate()
+        .filter(|&(_, &key)| key_pressed(keyboard, key))
+        .fold(0, |buttons, (button, _)| buttons | 1 << button)
+}
+
+/// The Family BASIC keyboard keys held, in the layout of
+/// `Input::family_keys`.
+pub fn family_keyboard_keys(keyboard: &KeyboardState) -> [u8; 9] {
+    let mut keys = [0; 9];
+    for &(key, name) in FAMILY_KEYS.iter() {
+        if key_pressed(keyboard, key) {
+            if let Some((row, bit)) = key_position(name) {
+                keys[row] |= 1 << bit;
+            }
+        }
+    }
+    keys
+}
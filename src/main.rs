@@ -1,3 +1,6 @@
+// Chips and boards go by their usual names: CPU, DMC, NROM...
+#![allow(clippy::upper_case_acronyms)]
+
 #[macro_use]
 extern crate bitflags;
 extern crate sdl2;
@@ -5,10 +8,11 @@ extern crate sdl2;
 use std::mem;
 
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::rect::Rect;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, LCTRLMOD, LSHIFTMOD, RCTRLMOD, RSHIFTMOD};
 use sdl2::audio::AudioSpecDesired;
+use sdl2::controller::GameController;
 use sdl2::render::TextureQuery;
 use sdl2::pixels::Color;
 
@@ -18,11 +22,19 @@ mod ppu;
 mod bus;
 mod cartridge;
 mod apu;
-
+mod blip;
+mod filter;
+mod mapper;
+mod device;
+mod input;
+mod script;
+
+use std::fs;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::io;
 use std::env;
+use std::cmp;
 use std::time::{Duration, Instant};
 use std::thread;
 
@@ -30,17 +42,23 @@ use console::Console;
 use cpu::CPU;
 use ppu::PPU;
 use bus::{Bus, BUFFER_HEIGHT, BUFFER_WIDTH};
-use cartridge::Cartridge;
-use apu::APU;
+use cartridge::{Cartridge, Mirror};
+use apu::{Channel, APU, CHANNELS};
+use mapper::{new_mapper, Mapper, MMC3Revision, MMC3};
+use device::{new_device, new_expansion, DeviceKind, ExpansionKind, Input};
+use input::{parse_binding, Binding, InputMap, Source};
+use script::Script;
 
 const BUFFER_SCALE: usize = 3;
 const WINDOW_WIDTH: usize = BUFFER_WIDTH * BUFFER_SCALE;
 const WINDOW_HEIGHT: usize = BUFFER_HEIGHT * BUFFER_SCALE;
-const TARGET_FRAME_RATE: u64 = 60;
-const BILLION: u64 = 1_000_000_000;
-const FRAME_TIME_NS: u64 = BILLION / TARGET_FRAME_RATE;
 
-const AUDIO_SAMPLE_RATE: u32 = 44_100;
+const AUDIO_SAMPLE_RATE: u32 = apu::SAMPLE_RATE;
+// Audio queued ahead of playback that the main loop aims for (50 ms)
+const AUDIO_LATENCY_SAMPLES: u32 = AUDIO_SAMPLE_RATE / 20;
+// Largest change dynamic rate control makes to the resampling ratio. Half a
+// percent is below what listeners notice as a change in pitch.
+const MAX_RATE_DELTA: f64 = 0.005;
 
 const OSD_FONT_SIZE: u16 = 14;
 
@@ -81,6 +99,35 @@ impl BitReader for File {
     }
 }
 
+/// How the main loop paces the emulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncMode {
+    /// Run a frame whenever the audio queue drains below the target
+    /// latency. Video is presented without waiting for vsync.
+    Audio,
+    /// Run a frame per display refresh, with vsync. Only runs at the right
+    /// speed on displays close to 60 Hz.
+    Video,
+}
+
+pub struct Options {
+    pub filename: String,
+    pub sync_mode: SyncMode,
+    pub muted: Vec<Channel>,
+    pub solo: Option<Channel>,
+    pub volumes: Vec<(Channel, f32)>,
+    pub bindings: Vec<Binding>,
+    pub port1: DeviceKind,
+    pub port2: DeviceKind,
+    pub expansion: Option<ExpansionKind>,
+    pub microphone: Option<Source>,
+    // Overrides the MMC3 revision from the ROM header
+    pub mmc3: Option<MMC3Revision>,
+    // Run this many frames without a window or sound
+    pub headless: Option<u64>,
+    pub script: Option<String>,
+}
+
 pub struct RomHeader {
     pub magic: u32,
     pub prg_count: u8,
@@ -90,49 +137,6 @@ pub struct RomHeader {
     pub ram_count: u8,
 }
 
-pub trait Mapper {
-    fn read(&self, address: u16) -> u8;
-    fn write(&mut self, address: u16, value: u8);
-    fn step(&self);
-}
-
-pub struct Mapper2<'a> {
-    cartridge: &'a mut Cartridge,
-}
-
-impl<'a> Mapper2<'a> {
-    pub fn new(cartridge: &mut Cartridge) -> Mapper2 {
-        Mapper2 { cartridge }
-    }
-}
-
-impl<'a> Mapper for Mapper2<'a> {
-    fn read(&self, address: u16) -> u8 {
-        match address {
-            0x0000...0x2000 => self.cartridge.chr[address as usize],
-            0x2001...0xC000 => self.cartridge.prg[address as usize],
-            _ => panic!("Invalid mapper read {:?}", address),
-        }
-    }
-
-    fn write(&mut self, address: u16, value: u8) {
-        match address {
-            0x0000...0x2000 => self.cartridge.chr[address as usize] = value,
-            0x2001...0xC000 => self.cartridge.prg[address as usize] = value,
-            _ => panic!("Invalid mapper write {:?}", address),
-        }
-    }
-
-    fn step(&self) {}
-}
-
-pub fn new_mapper(mapper_type: u8, cartridge: &mut Cartridge) -> Mapper2 {
-    match mapper_type {
-        0 => Mapper2::new(cartridge),
-        _ => panic!("Invalid mapper_type {:?}", mapper_type),
-    }
-}
-
 fn read_rom(path: &str) -> Result<Cartridge, io::Error> {
     let mut fp = File::open(path)?;
     let magic = fp.read_u32_be()?;
@@ -148,8 +152,22 @@ fn read_rom(path: &str) -> Result<Cartridge, io::Error> {
     let flags_1 = fp.read_u8()?;
     let flags_2 = fp.read_u8()?;
     let prg_ram_size = fp.read_u8()?;
+    fp.seek(SeekFrom::Current(1))?;
+    let ram_shifts = fp.read_u8()?;
     // Skip padding
-    fp.seek(SeekFrom::Current(7))?;
+    fp.seek(SeekFrom::Current(5))?;
+
+    // NES 2.0 headers reuse byte 8 for the submapper and byte 10 for the
+    // PRG RAM sizes as shift counts.
+    let nes2 = flags_2 & 0x0C == 0x08;
+    let submapper = if nes2 { prg_ram_size >> 4 } else { 0 };
+    let sram_size = if nes2 {
+        let shift_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+        shift_size(ram_shifts & 0x0F) + shift_size(ram_shifts >> 4)
+    } else {
+        // Older dumps leave the PRG RAM size at 0, which means 8 KiB.
+        cmp::max(prg_ram_size as usize, 1) * 8192
+    };
 
     println!("prg_rom_size: {}", prg_rom_size);
     println!("chr_rom_size: {}", chr_rom_size);
@@ -159,11 +177,16 @@ fn read_rom(path: &str) -> Result<Cartridge, io::Error> {
 
     let mapper_type = (flags_1 >> 4) | (flags_2 >> 4) << 4;
     println!("mapper: {}", mapper_type);
-
-    let mirror_mode = (flags_1 & 1) | ((flags_1 >> 3) & 1) << 1;
-    println!("mirror_mode: {}", mirror_mode);
-
-    let battery = (flags_1 >> 1) & 1;
+    println!("submapper: {}", submapper);
+
+    let mirror_mode = if flags_1 & 8 == 8 {
+        Mirror::FourScreen
+    } else if flags_1 & 1 == 1 {
+        Mirror::Vertical
+    } else {
+        Mirror::Horizontal
+    };
+    println!("mirror_mode: {:?}", mirror_mode);
 
     // Read trainer data (need to skip this)
     if flags_1 & 4 == 4 {
@@ -189,81 +212,260 @@ fn read_rom(path: &str) -> Result<Cartridge, io::Error> {
         chr.resize(8192, 0);
     }
 
-    let mut sram: Vec<u8> = Vec::new();
-    sram.resize(8192, 0);
+    let sram = vec![0; cmp::max(sram_size, 8192)];
 
     Ok(Cartridge {
         prg,
         chr,
+        chr_ram: chr_rom_size == 0,
         sram,
         mapper_type,
+        submapper,
         mirror_mode,
-        battery_present: battery == 1,
     })
 }
 
 fn usage() {
-    println!("Usage: emunes romfile.nes");
+    println!("Usage: emunes [options] romfile.nes");
+    println!();
+    println!("Options:");
+    println!("  --sync audio|video  Pace emulation on the audio queue (default) or on vsync");
+    println!("  --mute CHANNEL      Mute an APU channel, can be repeated");
+    println!("  --solo CHANNEL      Only play one APU channel");
+    println!("  --volume CHANNEL=N  Mix a channel at N times its volume");
+    println!("  --port1 DEVICE      Plug a device into port 1, see below");
+    println!("  --port2 DEVICE      Plug a device into port 2");
+    println!("  --multitap ADAPTER  Connect four controllers with a fourscore or hori adapter");
+    println!("  --expansion keyboard|hori");
+    println!("                      Plug the Family BASIC keyboard or the hori adapter into");
+    println!("                      the expansion port");
+    println!("  --bind PLAYER:BUTTON=SOURCE");
+    println!("                      Bind a key or gamepad input to a controller button,");
+    println!("                      e.g. 1:a=X, 2:start=pad:start or 1:left=pad:leftx-");
+    println!("  --mic SOURCE        Key or gamepad input for the second controller's");
+    println!("                      microphone, M by default");
+    println!("  --mmc3 a|b          Emulate an MMC3A or MMC3B scan line counter, for ROMs");
+    println!("                      without a NES 2.0 header");
+    println!("  --headless FRAMES   Run FRAMES frames without window or sound, then print a");
+    println!("                      checksum of the picture");
+    println!("  --script FILE       Read the input for a headless run from FILE");
+    println!();
+    println!("Channels: pulse1, pulse2, triangle, noise and dmc, and the cartridge's");
+    println!("mmc5.pulse1, mmc5.pulse2, mmc5.pcm, vrc6.pulse1, vrc6.pulse2, vrc6.saw,");
+    println!("vrc7.fm1-vrc7.fm6, n163.ch1-n163.ch8 or 5b.a-5b.c. F1-F5 toggle muting the");
+    println!("APU channels while running and Ctrl+F1-F8 the cartridge's, in that order.");
+    println!("Shift solos them instead.");
+    println!();
+    println!("Buttons: a, b, select, start, up, down, left and right. Keys use SDL key");
+    println!("names, gamepad inputs SDL's game controller names prefixed with pad:.");
+    println!("Player 1 defaults to the arrow keys, Z, X, right shift and return, player 2");
+    println!("to WASD, G, H, T and Y. Gamepads go to players 1 to 4 in the order they");
+    println!("are connected.");
+    println!();
+    println!("Devices: controller (default), zapper, vaus, powerpad and snesmouse.");
+    println!("The zapper is aimed with the mouse, the left button pulls the trigger and");
+    println!("the right button fires away from the screen. The vaus knob turns and the");
+    println!("snesmouse moves with the mouse, which is then captured by the window.");
+    println!("The powerpad buttons are on 1-4, Q-R and A-F.");
+    println!("While the Family BASIC keyboard is plugged in, Escape and F1-F8 are keys on");
+    println!("it. Close the window to quit.");
+}
+
+/// Parse `pulse1=0.5` style channel volumes.
+fn parse_volume(value: &str) -> Option<(Channel, f32)> {
+    let mut parts = value.splitn(2, '=');
+    let channel = Channel::from_name(parts.next()?)?;
+    let volume = parts.next()?.parse().ok()?;
+    Some((channel, volume))
+}
+
+/// The first cartridge channel the options name that the board, with
+/// channels `names`, does not have.
+fn missing_channel(options: &Options, names: &[&str]) -> Option<&'static str> {
+    let channels = options
+        .muted
+        .iter()
+        .chain(options.solo.iter())
+        .chain(options.volumes.iter().map(|(channel, _)| channel));
+    for &channel in channels {
+        match channel {
+            Channel::Expansion(name) if !names.contains(&name) => return Some(name),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut filename = None;
+    let mut sync_mode = SyncMode::Audio;
+    let mut muted = Vec::new();
+    let mut solo = None;
+    let mut volumes = Vec::new();
+    let mut bindings = Vec::new();
+    let mut port1 = DeviceKind::Controller;
+    let mut port2 = DeviceKind::Controller;
+    let mut expansion = None;
+    let mut microphone = None;
+    let mut mmc3 = None;
+    let mut headless = None;
+    let mut script = None;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sync" => {
+                sync_mode = match args.next().map(|value| value.as_str()) {
+                    Some("audio") => SyncMode::Audio,
+                    Some("video") => SyncMode::Video,
+                    _ => return None,
+                };
+            }
+            "--mute" => muted.push(Channel::from_name(args.next()?)?),
+            "--solo" => solo = Some(Channel::from_name(args.next()?)?),
+            "--volume" => volumes.push(parse_volume(args.next()?)?),
+            "--bind" => bindings.push(parse_binding(args.next()?)?),
+            "--port1" | "--port2" => {
+                // The Four Score takes both ports.
+                let kind = DeviceKind::from_name(args.next()?)?;
+                if kind.is_multitap() {
+                    return None;
+                }
+                if arg == "--port1" {
+                    port1 = kind;
+                } else {
+                    port2 = kind;
+                }
+            }
+            "--multitap" => match args.next()?.as_str() {
+                "fourscore" => {
+                    port1 = DeviceKind::FourScore;
+                    port2 = DeviceKind::FourScore;
+                }
+                // The Hori adapter plugs into the expansion port instead.
+                "hori" => expansion = Some(ExpansionKind::Hori),
+                _ => return None,
+            },
+            "--expansion" => expansion = Some(ExpansionKind::from_name(args.next()?)?),
+            "--mic" => microphone = Some(Source::from_name(args.next()?)?),
+            "--mmc3" => mmc3 = Some(MMC3Revision::from_name(args.next()?)?),
+            "--headless" => headless = Some(args.next()?.parse().ok()?),
+            "--script" => script = Some(args.next()?.clone()),
+            _ if arg.starts_with("--") => return None,
+            _ => filename = Some(arg.clone()),
+        }
+    }
+    Some(Options {
+        filename: filename?,
+        sync_mode,
+        muted,
+        solo,
+        volumes,
+        bindings,
+        port1,
+        port2,
+        expansion,
+        microphone,
+        mmc3,
+        headless,
+        script,
+    })
+}
+
+/// Dynamic rate control: resample slightly faster while the audio queue is
+/// below the target latency and slightly slower while it is above, so the
+/// queue neither runs dry nor grows without bound.
+/// See https://docs.libretro.com/development/cores/dynamic-rate-control/
+fn audio_rate_adjustment(queued_samples: u32) -> f64 {
+    let fill = queued_samples as f64 / (2 * AUDIO_LATENCY_SAMPLES) as f64;
+    1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill.min(1.0))
+}
+
+/// Run `frames` frames with input from `script` and return a checksum of
+/// the last picture (FNV-1a), to compare runs without a window.
+fn run_headless(console: &mut Console, frames: u64, script: &Script) -> u64 {
+    let mut input = Input::default();
+    for frame in 0..frames {
+        script.apply(frame, &mut input);
+        console.bus.set_input(&input);
+        console.step_frame();
+        console.apu.buffer.clear();
+    }
+    console.bus.ppu_pixels.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &pixel| {
+        (hash ^ pixel as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
 }
 
 fn main() {
     let args: Vec<_> = env::args().collect();
-    if args.len() < 2 {
-        usage();
-        std::process::exit(1);
-    }
+    let options = match parse_args(&args) {
+        Some(options) => options,
+        None => {
+            usage();
+            std::process::exit(1);
+        }
+    };
 
-    let filename = &args[1];
-    let cartridge = read_rom(&filename).unwrap();
+    let cartridge = read_rom(&options.filename).unwrap();
+    let mapper = match (cartridge.mapper_type, options.mmc3) {
+        (4, Some(revision)) => Ok(Box::new(MMC3::new(cartridge, revision)) as Box<dyn Mapper>),
+        _ => new_mapper(cartridge),
+    };
+    let mapper = match mapper {
+        Ok(mapper) => mapper,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    //let mapper = new_mapper(cartridge.mapper_type, cartridge);
     let mut ram: Vec<u8> = Vec::new();
     ram.resize(2048, 0);
 
     let cpu = CPU::new();
     let ppu = PPU::new();
-    let apu = APU::new(AUDIO_SAMPLE_RATE);
+    let apu = APU::new();
 
-    let bus = Bus::new(cartridge, ram);
+    let bus = Bus::new(mapper, ram);
 
     let mut console = Console { cpu, ppu, apu, bus };
+    if let Some(name) = missing_channel(&options, console.bus.mapper.audio_channels()) {
+        println!("This cartridge has no {} channel", name);
+        std::process::exit(1);
+    }
+    for &channel in &options.muted {
+        console.apu.set_mute(channel, true);
+    }
+    console.apu.solo = options.solo;
+    for &(channel, volume) in &options.volumes {
+        console.apu.set_volume(channel, volume);
+    }
+    console.bus.port1 = new_device(options.port1, 0);
+    console.bus.port2 = new_device(options.port2, 1);
+    console.bus.expansion = options.expansion.map(new_expansion);
 
     console.reset();
-    let mut buffer: Vec<u32> = vec![0; WINDOW_WIDTH * WINDOW_HEIGHT];
 
-    let mut x_off = 0;
-    let mut y_off = 0;
-    let mut chr_off = 0;
-    while chr_off < 4096 {
-        for y in 0..8 {
-            for x in 0..8 {
-                // let b1 = console.bus.cartridge.chr[chr_off] >> x & 0x01;
-                let plane0 = console.bus.cartridge.chr[chr_off];
-                let plane1 = console.bus.cartridge.chr[chr_off + 8];
-
-                let b0 = (plane0 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-                let b1 = (plane1 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-
-                let c;
-                if b0 == 0 {
-                    c = 0;
-                } else {
-                    c = 0xFFFFFF;
-                }
-                //let c = *(&console.bus.cartridge.chr[y * 8 + x + chr_off]) as u32;
-                console.bus.ppu_pixels[((y_off + y) * BUFFER_WIDTH) + x + x_off] = c;
-                // (0xFF << 24) | (c << 16) | (c << 8) | c;
+    if let Some(frames) = options.headless {
+        let script = match options.script {
+            Some(ref path) => fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Script::parse(&text)),
+            None => Ok(Script { changes: Vec::new() }),
+        };
+        let script = match script {
+            Ok(script) => script,
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
             }
-            chr_off += 2;
-        }
-        //chr_off += 64;
-        x_off += 10;
-        if x_off > BUFFER_WIDTH - 10 {
-            x_off = 0;
-            y_off += 10;
-        }
+        };
+        let checksum = run_headless(&mut console, frames, &script);
+        println!("Picture checksum after {} frames: {:016X}", frames, checksum);
+        return;
     }
 
+    let mut buffer: Vec<u32> = vec![0; WINDOW_WIDTH * WINDOW_HEIGHT];
+
     // Initialize SDL
     let sdl_context = sdl2::init().unwrap();
 
@@ -275,7 +477,10 @@ fn main() {
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().build().unwrap();
+    let mut canvas = match options.sync_mode {
+        SyncMode::Audio => window.into_canvas().build().unwrap(),
+        SyncMode::Video => window.into_canvas().present_vsync().build().unwrap(),
+    };
     let texture_creator = canvas.texture_creator();
     let mut texture = texture_creator
         .create_texture_streaming(
@@ -289,7 +494,7 @@ fn main() {
     let audio_subsystem = sdl_context.audio().unwrap();
     let desired_spec = AudioSpecDesired {
         freq: Some(AUDIO_SAMPLE_RATE as i32),
-        channels: Some(2),
+        channels: Some(1),
         samples: Some(4),
     };
     let device = audio_subsystem
@@ -308,35 +513,126 @@ fn main() {
     let mut current_fps = 0;
     let mut frames_elapsed = 0;
 
-    let mut last_timestamp = Instant::now();
-
     // Declare variables for calculating CPS (cycles per second)
     let mut current_cps = 0;
 
+    // Initialize SDL Game Controller. Gamepads already plugged in show up as
+    // added devices with the first events.
+    let game_controller_subsystem = sdl_context.game_controller().unwrap();
+    let mut pads: Vec<GameController> = Vec::new();
+    let mut input_map = InputMap::new();
+    for &binding in &options.bindings {
+        input_map.bind(binding);
+    }
+    if let Some(source) = options.microphone {
+        input_map.microphone = source;
+    }
+    // The Family BASIC keyboard takes over the keys used as hotkeys.
+    let hotkeys = options.expansion != Some(ExpansionKind::FamilyKeyboard);
+    let mut mouse_inside = false;
+    // Devices turned or moved by the mouse capture it, like games do.
+    if options.port1.uses_mouse_motion() || options.port2.uses_mouse_motion() {
+        sdl_context.mouse().set_relative_mouse_mode(true);
+    }
+
     let mut event_pump = sdl_context.event_pump().unwrap();
     'running: loop {
-        let start_time = Instant::now();
-
         for event in event_pump.poll_iter() {
             match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match game_controller_subsystem.open(which) {
+                        Ok(pad) => {
+                            println!("Gamepad {}: {}", pads.len() + 1, pad.name());
+                            pads.push(pad);
+                        }
+                        Err(err) => println!("Could not open gamepad: {}", err),
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    pads.retain(|pad| pad.instance_id() != which);
+                }
+                Event::Window { win_event, .. } => match win_event {
+                    WindowEvent::Enter => mouse_inside = true,
+                    WindowEvent::Leave => mouse_inside = false,
+                    _ => {}
+                },
+                Event::Quit { .. } => break 'running,
+                Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => break 'running,
+                } if hotkeys => break 'running,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if hotkeys => {
+                    let index = match keycode {
+                        Keycode::F1 => Some(0),
+                        Keycode::F2 => Some(1),
+                        Keycode::F3 => Some(2),
+                        Keycode::F4 => Some(3),
+                        Keycode::F5 => Some(4),
+                        Keycode::F6 => Some(5),
+                        Keycode::F7 => Some(6),
+                        Keycode::F8 => Some(7),
+                        _ => None,
+                    };
+                    // Control switches F1-F8 over to the cartridge's channels.
+                    let channel = match index {
+                        Some(index) if keymod.intersects(LCTRLMOD | RCTRLMOD) => {
+                            let names = console.bus.mapper.audio_channels();
+                            names.get(index).map(|&name| Channel::Expansion(name))
+                        }
+                        Some(index) => CHANNELS.get(index).cloned(),
+                        None => None,
+                    };
+                    if let Some(channel) = channel {
+                        if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+                            console.apu.toggle_solo(channel);
+                        } else {
+                            console.apu.toggle_mute(channel);
+                        }
+                    }
+                }
                 _ => {}
             }
         }
 
-        // NOTE(m): Calculate how much time we need data for and
-        // ask the console api to provide it.
-        let timestamp = Instant::now();
-        let duration = timestamp - last_timestamp;
-        last_timestamp = timestamp;
-        // NOTE(m): Convert to seconds following fogleman's implementation.
-        // See https://doc.rust-lang.org/std/time/struct.Duration.html#method.as_secs
-        let dt = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
-        console.step_seconds(dt);
+        // Samples are 16-bit mono.
+        let queued_samples = || device.size() / 2;
+        if options.sync_mode == SyncMode::Audio {
+            while queued_samples() > AUDIO_LATENCY_SAMPLES {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        console
+            .apu
+            .set_rate_adjustment(audio_rate_adjustment(queued_samples()));
+        let mut input = Input::default();
+        {
+            let keyboard = event_pump.keyboard_state();
+            for player in 0..4 {
+                input.buttons[player] = input_map.buttons(player, &keyboard, pads.get(player));
+            }
+            input.power_pad = input::power_pad_buttons(&keyboard);
+            input.family_keys = input::family_keyboard_keys(&keyboard);
+            input.microphone = input_map.microphone(&keyboard, pads.get(1));
+        }
+        let mouse = event_pump.mouse_state();
+        if mouse_inside {
+            let x = mouse.x() / BUFFER_SCALE as i32;
+            let y = mouse.y() / BUFFER_SCALE as i32;
+            if x >= 0 && x < BUFFER_WIDTH as i32 && y >= 0 && y < BUFFER_HEIGHT as i32 {
+                input.cursor = Some((x, y));
+            }
+        }
+        input.left_button = mouse.left();
+        input.right_button = mouse.right();
+        let motion = event_pump.relative_mouse_state();
+        input.mouse_motion = (motion.x(), motion.y());
+        console.bus.set_input(&input);
+        console.step_frame();
 
         // Output video
         let _ = texture.update(
@@ -401,7 +697,8 @@ fn main() {
         canvas.present();
 
         // Output audio
-        device.queue(&console.bus.apu_buffer);
+        device.queue(&console.apu.buffer);
+        console.apu.buffer.clear();
         device.resume();
 
         // Calculate framerate.
@@ -416,16 +713,7 @@ fn main() {
             // let frame_end_cycles = console.cpu.cycles;
             // current_cps = (frame_end_cycles - frame_start_cycles) * current_fps;
         }
-        frames_elapsed = frames_elapsed + 1;
-
-        // Cap framerate.
-        let end_time = Instant::now();
-        let time_elapsed = end_time - start_time;
-        let time_elapsed: u64 =
-            time_elapsed.as_secs() * BILLION + time_elapsed.subsec_nanos() as u64;
-        if time_elapsed < FRAME_TIME_NS {
-            thread::sleep(Duration::new(0, (FRAME_TIME_NS - time_elapsed) as u32));
-        }
+        frames_elapsed += 1;
     }
     // for y in 0..256 {
     //     for x in 0..256 {
@@ -483,25 +771,184 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use cartridge::test_cartridge;
+    use device::Buttons;
     use std::io::prelude::*;
     use std::io::BufReader;
 
+    #[test]
+    fn it_parses_options() {
+        let args = |list: &[&str]| -> Vec<String> {
+            list.iter().map(|arg| arg.to_string()).collect()
+        };
+        let options = parse_args(&args(&["emunes", "--sync", "video", "game.nes"])).unwrap();
+        assert_eq!(options.filename, "game.nes");
+        assert_eq!(options.sync_mode, SyncMode::Video);
+        assert!(parse_args(&args(&["emunes", "--sync", "never", "game.nes"])).is_none());
+        assert!(parse_args(&args(&["emunes"])).is_none());
+
+        let options = parse_args(&args(&[
+            "emunes", "--mute", "dmc", "--volume", "noise=0.5", "--solo", "pulse2", "game.nes",
+        ])).unwrap();
+        assert_eq!(options.muted, vec![Channel::DMC]);
+        assert_eq!(options.volumes, vec![(Channel::Noise, 0.5)]);
+        assert_eq!(options.solo, Some(Channel::Pulse2));
+        assert!(parse_args(&args(&["emunes", "--mute", "kazoo", "game.nes"])).is_none());
+        let options = parse_args(&args(&[
+            "emunes", "--mute", "n163.ch8", "--volume", "vrc6.saw=2", "game.nes",
+        ])).unwrap();
+        assert_eq!(options.muted, vec![Channel::Expansion("n163.ch8")]);
+        assert_eq!(missing_channel(&options, &["vrc6.pulse1", "vrc6.pulse2"]), Some("n163.ch8"));
+        assert_eq!(missing_channel(&options, &["n163.ch8"]), Some("vrc6.saw"));
+        assert_eq!(missing_channel(&options, &["n163.ch8", "vrc6.saw"]), None);
+
+        let options = parse_args(&args(&["emunes", "--bind", "2:select=Q", "game.nes"])).unwrap();
+        assert_eq!(options.bindings.len(), 1);
+        assert_eq!(options.bindings[0].player, 1);
+        assert_eq!(options.bindings[0].button, Buttons::SELECT);
+        assert!(parse_args(&args(&["emunes", "--bind", "5:a=X", "game.nes"])).is_none());
+        assert!(parse_args(&args(&["emunes", "--bind", "1:turbo=X", "game.nes"])).is_none());
+
+        let options = parse_args(&args(&["emunes", "--port2", "zapper", "game.nes"])).unwrap();
+        assert_eq!(options.port2, DeviceKind::Zapper);
+        assert!(parse_args(&args(&["emunes", "--port2", "lasso", "game.nes"])).is_none());
+        let options =
+            parse_args(&args(&["emunes", "--multitap", "fourscore", "game.nes"])).unwrap();
+        assert_eq!((options.port1, options.port2), (DeviceKind::FourScore, DeviceKind::FourScore));
+        let options = parse_args(&args(&["emunes", "--multitap", "hori", "game.nes"])).unwrap();
+        assert_eq!(options.port1, DeviceKind::Controller);
+        assert_eq!(options.port2, DeviceKind::Controller);
+        assert_eq!(options.expansion, Some(ExpansionKind::Hori));
+        assert!(parse_args(&args(&["emunes", "--multitap", "zapper", "game.nes"])).is_none());
+        assert!(parse_args(&args(&["emunes", "--port2", "fourscore", "game.nes"])).is_none());
+        let options = parse_args(&args(&[
+            "emunes", "--port1", "snesmouse", "--port2", "vaus", "--headless", "600", "game.nes",
+        ])).unwrap();
+        assert_eq!((options.port1, options.port2), (DeviceKind::SNESMouse, DeviceKind::Vaus));
+        assert_eq!(options.headless, Some(600));
+        let options =
+            parse_args(&args(&["emunes", "--expansion", "keyboard", "game.nes"])).unwrap();
+        assert_eq!(options.expansion, Some(ExpansionKind::FamilyKeyboard));
+        assert!(parse_args(&args(&["emunes", "--expansion", "modem", "game.nes"])).is_none());
+        let options = parse_args(&args(&["emunes", "--mmc3", "a", "game.nes"])).unwrap();
+        assert_eq!(options.mmc3, Some(MMC3Revision::MMC3A));
+        assert!(parse_args(&args(&["emunes", "--mmc3", "c", "game.nes"])).is_none());
+
+        // Rate control balances around the target latency.
+        assert_eq!(audio_rate_adjustment(AUDIO_LATENCY_SAMPLES), 1.0);
+        assert_eq!(audio_rate_adjustment(0), 1.0 + MAX_RATE_DELTA);
+        assert_eq!(audio_rate_adjustment(u32::MAX), 1.0 - MAX_RATE_DELTA);
+    }
+
+    #[test]
+    fn it_runs_headless() {
+        let run = |script: &str| {
+            let mapper = new_mapper(read_rom("testroms/nestest.nes").unwrap()).unwrap();
+            let mut console = Console {
+                cpu: CPU::new(),
+                ppu: PPU::new(),
+                apu: APU::new(),
+                bus: Bus::new(mapper, vec![0; 2048]),
+            };
+            console.reset();
+            run_headless(&mut console, 60, &Script::parse(script).unwrap())
+        };
+        // Start runs the selected tests, which replaces the menu with
+        // their results.
+        let menu = run("");
+        let results = run("5 p1=start\n6 p1=");
+        assert_ne!(results, menu);
+        assert_eq!(results, 0xDDAA_2509_631D_4FB4);
+    }
+
+    /// Run one of blargg's test ROMs headless and return the result code
+    /// and message it leaves in PRG RAM. Once the signature $DE $B0 $61 is
+    /// at $6001, $6000 reads $80 while the test runs, $81 when it needs the
+    /// reset button pressed, and the result when it is done (0 for a pass),
+    /// with the message as text from $6004.
+    /// See http://blargg.8bitalley.com/nes-tests/
+    fn run_blargg_test(console: &mut Console) -> (u8, String) {
+        let script = Script { changes: Vec::new() };
+        let read = |console: &Console, address| console.bus.mapper.read(address);
+        let mut reset_countdown = None;
+        // Give up after a minute.
+        for _ in 0..60 * 60 {
+            run_headless(console, 1, &script);
+            let signature = [read(console, 0x6001), read(console, 0x6002), read(console, 0x6003)];
+            if signature != [0xDE, 0xB0, 0x61] {
+                continue;
+            }
+            match read(console, 0x6000) {
+                0x80 => {}
+                // The ROMs want reset pressed at least 100 ms later.
+                0x81 => match reset_countdown {
+                    None => reset_countdown = Some(6),
+                    Some(0) => {
+                        console.reset();
+                        reset_countdown = None;
+                    }
+                    Some(frames) => reset_countdown = Some(frames - 1),
+                },
+                result => {
+                    let text = (0x6004..0x7FFF)
+                        .map(|address| read(console, address))
+                        .take_while(|&byte| byte != 0)
+                        .map(|byte| byte as char)
+                        .collect();
+                    return (result, text);
+                }
+            }
+        }
+        panic!("The test did not finish");
+    }
+
+    #[test]
+    fn it_follows_the_blargg_test_protocol() {
+        // Asks for a reset, then passes.
+        let program = [
+            0xAD, 0x00, 0x60, // LDA $6000
+            0xC9, 0x81, // CMP #$81
+            0xF0, 0x17, // BEQ reset
+            0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE, STA $6001
+            0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0, STA $6002
+            0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61, STA $6003
+            0xA9, 0x81, 0x8D, 0x00, 0x60, // LDA #$81, STA $6000
+            0x4C, 0x1B, 0x80, // JMP *
+            0xA9, 0x6F, 0x8D, 0x04, 0x60, // reset: LDA #'o', STA $6004
+            0xA9, 0x6B, 0x8D, 0x05, 0x60, // LDA #'k', STA $6005
+            0xA9, 0x00, 0x8D, 0x00, 0x60, // LDA #0, STA $6000
+            0x4C, 0x2D, 0x80, // JMP *
+        ];
+        let mut cartridge = test_cartridge(0, 2, 1);
+        cartridge.prg[..program.len()].copy_from_slice(&program);
+        cartridge.prg[0x7FFC] = 0x00;
+        cartridge.prg[0x7FFD] = 0x80;
+        let mut console = Console {
+            cpu: CPU::new(),
+            ppu: PPU::new(),
+            apu: APU::new(),
+            bus: Bus::new(new_mapper(cartridge).unwrap(), vec![0; 2048]),
+        };
+        console.reset();
+        assert_eq!(run_blargg_test(&mut console), (0, "ok".to_owned()));
+    }
+
     #[test]
     fn it_runs_nestest() {
         let cartridge = read_rom("testroms/nestest.nes").unwrap();
+        let mapper = new_mapper(cartridge).unwrap();
 
-        //let mapper = new_mapper(cartridge.mapper_type, cartridge);
         let mut ram: Vec<u8> = Vec::new();
         ram.resize(2048, 0);
 
         let cpu = CPU::new();
 
-        let bus = Bus::new(cartridge, ram);
+        let bus = Bus::new(mapper, ram);
 
         let mut console = Console {
             cpu,
             ppu: PPU::new(),
-            apu: APU::new(AUDIO_SAMPLE_RATE),
+            apu: APU::new(),
             bus,
         };
 
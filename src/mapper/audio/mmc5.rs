@@ -0,0 +1,130 @@
+use apu::Pulse;
+
+/// Names of the channels, in the order `channel_levels` fills in their levels.
+pub const CHANNEL_NAMES: [&str; 3] = ["mmc5.pulse1", "mmc5.pulse2", "mmc5.pcm"];
+
+/// The audio frame sequencer runs at a fixed 240 Hz.
+const FRAME_CYCLES: u32 = 7457;
+
+/// MMC5 sound: two pulse channels like the APU's, without sweep units, and
+/// an 8-bit PCM channel that is either written directly or loaded from
+/// reads of $8000-$BFFF.
+///
+/// See https://wiki.nesdev.com/w/index.php/MMC5_audio
+pub struct MMC5Audio {
+    pub cycle: u64,
+    pub frame_cycle: u32,
+    pub pulses: [Pulse; 2],
+    pub pcm_read_mode: bool,
+    pub pcm_irq_enabled: bool,
+    pub pcm_irq_pending: bool,
+    pub pcm_value: u8,
+}
+
+impl MMC5Audio {
+    pub fn new() -> MMC5Audio {
+        MMC5Audio {
+            cycle: 0,
+            frame_cycle: 0,
+            pulses: [Pulse::default(), Pulse::default()],
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq_pending: false,
+            pcm_value: 0,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x5010 => (self.pcm_irq_pending as u8) << 7 | self.pcm_read_mode as u8,
+            0x5015 => {
+                let mut value = 0;
+                for (i, pulse) in self.pulses.iter().enumerate() {
+                    if pulse.length_value > 0 {
+                        value |= 1 << i;
+                    }
+                }
+                value
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5007 => {
+                let pulse = &mut self.pulses[(address as usize - 0x5000) / 4];
+                match address % 4 {
+                    0 => pulse.write_control(value),
+                    2 => pulse.write_timer_low(value),
+                    3 => pulse.write_timer_high(value),
+                    // There is no sweep unit.
+                    _ => {}
+                }
+            }
+            0x5010 => {
+                self.pcm_read_mode = value & 0x01 == 0x01;
+                self.pcm_irq_enabled = value & 0x80 == 0x80;
+            }
+            // Writing zero has no effect.
+            0x5011 if !self.pcm_read_mode && value != 0 => {
+                self.pcm_value = value;
+            }
+            0x5015 => {
+                for (i, pulse) in self.pulses.iter_mut().enumerate() {
+                    pulse.enabled = value & (1 << i) != 0;
+                    if !pulse.enabled {
+                        pulse.length_value = 0;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// In read mode the PCM channel plays the bytes the CPU reads from
+    /// $8000-$BFFF. A zero byte raises an IRQ instead.
+    pub fn read_pcm(&mut self, value: u8) {
+        if !self.pcm_read_mode {
+            return;
+        }
+        if value == 0 {
+            self.pcm_irq_pending = true;
+        } else {
+            self.pcm_value = value;
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.pcm_irq_pending && self.pcm_irq_enabled
+    }
+
+    pub fn step(&mut self) {
+        self.cycle += 1;
+        if self.cycle & 1 == 0 {
+            for pulse in self.pulses.iter_mut() {
+                pulse.step_timer();
+            }
+        }
+        self.frame_cycle += 1;
+        if self.frame_cycle == FRAME_CYCLES {
+            self.frame_cycle = 0;
+            for pulse in self.pulses.iter_mut() {
+                pulse.step_envelope();
+                pulse.step_length();
+            }
+        }
+    }
+
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        &CHANNEL_NAMES
+    }
+
+    /// The pulses match the APU's. Full scale PCM is about as loud as the
+    /// DMC at full scale.
+    pub fn channel_levels(&self, levels: &mut [f32]) {
+        levels[0] = self.pulses[0].output() as f32;
+        levels[1] = self.pulses[1].output() as f32;
+        levels[2] = self.pcm_value as f32 * 0.22;
+    }
+}
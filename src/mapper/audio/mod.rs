@@ -0,0 +1,147 @@
+mod mmc5;
+mod n163;
+mod sunsoft5b;
+mod vrc6;
+mod vrc7;
+
+pub use self::mmc5::MMC5Audio;
+pub use self::n163::N163Audio;
+pub use self::sunsoft5b::Sunsoft5BAudio;
+pub use self::vrc6::VRC6Audio;
+pub use self::vrc7::VRC7Audio;
+
+// Channel names of every sound chip
+const CHANNEL_NAMES: [&[&str]; 5] = [
+    &mmc5::CHANNEL_NAMES,
+    &n163::CHANNEL_NAMES,
+    &sunsoft5b::CHANNEL_NAMES,
+    &vrc6::CHANNEL_NAMES,
+    &vrc7::CHANNEL_NAMES,
+];
+
+/// Look up a channel of any sound chip, like `vrc6.saw`.
+pub fn expansion_channel(name: &str) -> Option<&'static str> {
+    CHANNEL_NAMES.iter().flat_map(|names| names.iter()).cloned().find(|&n| n == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_plays_vrc6_pulses() {
+        let mut audio = VRC6Audio::new();
+        let output = |audio: &VRC6Audio| {
+            let mut levels = [0.0; 3];
+            audio.channel_levels(&mut levels);
+            levels.iter().sum::<f32>()
+        };
+        // Constant volume 10, enabled.
+        audio.write(0x9000, 0x8A);
+        audio.write(0x9001, 0x10);
+        audio.write(0x9002, 0x80);
+        audio.step();
+        let mut levels = [0.0; 3];
+        audio.channel_levels(&mut levels);
+        assert_eq!(levels, [10.0, 0.0, 0.0]);
+
+        // Duty 0 is high for 1 of 16 steps.
+        audio.write(0x9000, 0x0A);
+        let mut high = 0;
+        for _ in 0..16 * 0x11 {
+            audio.step();
+            if output(&audio) > 0.0 {
+                high += 1;
+            }
+        }
+        assert_eq!(high, 0x11);
+
+        audio.write(0x9003, 0x01);
+        audio.write(0x9002, 0x00);
+        assert_eq!(output(&audio), 0.0);
+    }
+
+    #[test]
+    fn it_toggles_sunsoft_5b_tones() {
+        let mut audio = Sunsoft5BAudio::new();
+        let output = |audio: &Sunsoft5BAudio| {
+            let mut levels = [0.0; 3];
+            audio.channel_levels(&mut levels);
+            levels.iter().sum::<f32>()
+        };
+        let write = |audio: &mut Sunsoft5BAudio, register, value| {
+            audio.select(register);
+            audio.write(value);
+        };
+        write(&mut audio, 0x00, 0x02);
+        write(&mut audio, 0x07, 0x3E);
+        write(&mut audio, 0x08, 0x0F);
+
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..32 {
+                audio.step();
+            }
+            levels.push(output(&audio));
+        }
+        assert!(levels[0] > 0.0);
+        assert_eq!(levels[1], 0.0);
+        assert_eq!(levels[2], levels[0]);
+        assert_eq!(levels[3], 0.0);
+    }
+
+    #[test]
+    fn it_reads_n163_wavetables() {
+        let mut audio = N163Audio::new();
+        let output = |audio: &N163Audio| {
+            let mut levels = [0.0; 8];
+            audio.channel_levels(&mut levels);
+            levels.iter().sum::<f32>()
+        };
+        // A single channel with a flat wave of 15 at full volume.
+        for i in 0..4 {
+            audio.ram[i] = 0xFF;
+        }
+        audio.ram[0x7C] = 0xF8;
+        audio.ram[0x7F] = 0x0F;
+        for _ in 0..15 {
+            audio.step();
+        }
+        assert_eq!(audio.channel, 7);
+        assert_eq!(audio.level, 7 * 15);
+
+        audio.disabled = true;
+        assert_eq!(output(&audio), 0.0);
+    }
+
+    #[test]
+    fn it_keys_vrc7_channels() {
+        let mut audio = VRC7Audio::new();
+        let output = |audio: &VRC7Audio| {
+            let mut levels = [0.0; 6];
+            audio.channel_levels(&mut levels);
+            levels.iter().sum::<f32>()
+        };
+        let write = |audio: &mut VRC7Audio, register, value| {
+            audio.select(register);
+            audio.write(value);
+        };
+        // Instrument 3 (piano) at full volume.
+        write(&mut audio, 0x10, 0xAC);
+        write(&mut audio, 0x30, 0x30);
+        write(&mut audio, 0x20, 0x18);
+
+        let mut peak: f32 = 0.0;
+        for _ in 0..36 * 500 {
+            audio.step();
+            peak = peak.max(output(&audio).abs());
+        }
+        assert!(peak > 1.0);
+
+        write(&mut audio, 0x20, 0x08);
+        for _ in 0..36 * 50_000 {
+            audio.step();
+        }
+        assert!(output(&audio).abs() < 0.01);
+    }
+}
@@ -0,0 +1,105 @@
+/// Names of the channels by their register number, in the order
+/// `channel_levels` fills in their levels.
+pub const CHANNEL_NAMES: [&str; 8] = [
+    "n163.ch1", "n163.ch2", "n163.ch3", "n163.ch4",
+    "n163.ch5", "n163.ch6", "n163.ch7", "n163.ch8",
+];
+
+/// CPU cycles the N163 spends on each channel update.
+const CHANNEL_CYCLES: u32 = 15;
+
+/// Namco 163 sound: up to eight wavetable channels that live in the chip's
+/// 128 bytes of internal RAM. The channel registers take up the top of the
+/// RAM ($40-$7F, eight bytes per channel) and the 4-bit samples are packed
+/// two per byte anywhere in it.
+///
+/// The chip has a single DAC. It updates one channel every 15 CPU cycles and
+/// outputs that channel until the next update, so more channels means a
+/// lower rate per channel and an audible whine at the multiplexing rate.
+///
+/// See https://wiki.nesdev.com/w/index.php/Namco_163_audio
+pub struct N163Audio {
+    pub ram: [u8; 128],
+    pub disabled: bool,
+    pub cycle: u32,
+    pub channel: u8,
+    pub level: i16,
+}
+
+impl N163Audio {
+    pub fn new() -> N163Audio {
+        N163Audio {
+            ram: [0; 128],
+            disabled: false,
+            cycle: 0,
+            channel: 7,
+            level: 0,
+        }
+    }
+
+    /// Number of enabled channels (1-8), counted down from channel 7.
+    pub fn channel_count(&self) -> u8 {
+        ((self.ram[0x7F] >> 4) & 7) + 1
+    }
+
+    fn sample(&self, address: u8) -> u8 {
+        let byte = self.ram[(address as usize / 2) % 128];
+        if address & 1 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        }
+    }
+
+    /// Advance a channel's phase and return its output level.
+    fn update_channel(&mut self, channel: u8) -> i16 {
+        let base = 0x40 + channel as usize * 8;
+        let r = &mut self.ram[base..base + 8];
+        let frequency = r[0] as u32 | (r[2] as u32) << 8 | ((r[4] & 3) as u32) << 16;
+        let length = 256 - (r[4] & 0xFC) as u32;
+        let mut phase = r[1] as u32 | (r[3] as u32) << 8 | (r[5] as u32) << 16;
+        phase = (phase + frequency) % (length << 16);
+        r[1] = phase as u8;
+        r[3] = (phase >> 8) as u8;
+        r[5] = (phase >> 16) as u8;
+        let address = r[6].wrapping_add((phase >> 16) as u8);
+        let volume = (r[7] & 0x0F) as i16;
+        (self.sample(address) as i16 - 8) * volume
+    }
+
+    pub fn step(&mut self) {
+        if self.disabled {
+            return;
+        }
+        self.cycle += 1;
+        if self.cycle < CHANNEL_CYCLES {
+            return;
+        }
+        self.cycle = 0;
+        let first = 8 - self.channel_count();
+        self.channel = if self.channel <= first {
+            7
+        } else {
+            self.channel - 1
+        };
+        let channel = self.channel;
+        self.level = self.update_channel(channel);
+    }
+
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        &CHANNEL_NAMES
+    }
+
+    /// A single channel at full volume is roughly twice as loud as an APU
+    /// pulse at full volume. Only the channel the DAC is playing has an
+    /// output, the others are silent until their turn.
+    pub fn channel_levels(&self, levels: &mut [f32]) {
+        for (channel, level) in levels.iter_mut().enumerate() {
+            *level = if self.disabled || channel != self.channel as usize {
+                0.0
+            } else {
+                self.level as f32 * 0.25
+            };
+        }
+    }
+}
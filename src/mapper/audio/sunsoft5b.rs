@@ -0,0 +1,183 @@
+/// Names of the channels, in the order `channel_levels` fills in their levels.
+pub const CHANNEL_NAMES: [&str; 3] = ["5b.a", "5b.b", "5b.c"];
+
+/// The tone, noise and envelope generators tick once every 16 CPU cycles.
+const CLOCK_DIVIDER: u32 = 16;
+
+/// Sunsoft 5B sound, a YM2149F (a licensed AY-3-8910) inside the FME-7:
+/// three square wave channels with a shared noise generator and envelope.
+/// Volumes are logarithmic, 3 dB per volume step and 1.5 dB per envelope
+/// step.
+///
+/// See https://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+pub struct Sunsoft5BAudio {
+    pub register: u8,
+    pub registers: [u8; 16],
+    pub divider: u32,
+
+    pub tone_timers: [u16; 3],
+    pub tone_outputs: [bool; 3],
+
+    pub noise_timer: u16,
+    pub noise_shift: u32,
+
+    pub envelope_timer: u16,
+    pub envelope_step: u8,
+    pub envelope_attack: bool,
+    pub envelope_holding: bool,
+
+    pub levels: [f32; 32],
+}
+
+impl Sunsoft5BAudio {
+    pub fn new() -> Sunsoft5BAudio {
+        let mut levels = [0.0; 32];
+        for (i, level) in levels.iter_mut().enumerate().skip(1) {
+            *level = 10f32.powf((i as f32 - 31.0) * 1.5 / 20.0);
+        }
+        Sunsoft5BAudio {
+            register: 0,
+            registers: [0; 16],
+            divider: 0,
+            tone_timers: [0; 3],
+            tone_outputs: [false; 3],
+            noise_timer: 0,
+            noise_shift: 1,
+            envelope_timer: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+            levels,
+        }
+    }
+
+    /// $C000: select a register.
+    pub fn select(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    /// $E000: write the selected register.
+    pub fn write(&mut self, value: u8) {
+        // The upper four bits must be zero to select a register.
+        if self.register >= 0x10 {
+            return;
+        }
+        self.registers[self.register as usize] = value;
+        if self.register == 0x0D {
+            self.envelope_step = 0;
+            self.envelope_attack = value & 0x04 == 0x04;
+            self.envelope_holding = false;
+            self.envelope_timer = 0;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let r = &self.registers;
+        let period = r[channel * 2] as u16 | ((r[channel * 2 + 1] & 0x0F) as u16) << 8;
+        period.max(1)
+    }
+
+    fn noise_period(&self) -> u16 {
+        ((self.registers[6] & 0x1F) as u16).max(1)
+    }
+
+    fn envelope_period(&self) -> u16 {
+        (self.registers[0x0B] as u16 | (self.registers[0x0C] as u16) << 8).max(1)
+    }
+
+    fn step_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+        // Shapes $00-$07 end silent, the others repeat or hold. The
+        // alternate bit flips the direction at the end of each cycle.
+        let shape = self.registers[0x0D];
+        let continuing = shape & 0x08 == 0x08;
+        let alternate = shape & 0x02 == 0x02;
+        let hold = shape & 0x01 == 0x01;
+        self.envelope_step = 0;
+        if !continuing {
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+            return;
+        }
+        if alternate {
+            self.envelope_attack = !self.envelope_attack;
+        }
+        self.envelope_holding = hold;
+    }
+
+    /// Envelope level (0-31) for the current step and shape.
+    fn envelope_level(&self) -> usize {
+        let step = self.envelope_step as usize;
+        match (self.envelope_holding, self.envelope_attack) {
+            (true, true) => 31,
+            (true, false) => 0,
+            (false, true) => step,
+            (false, false) => 31 - step,
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.divider += 1;
+        if self.divider < CLOCK_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        for channel in 0..3 {
+            self.tone_timers[channel] += 1;
+            if self.tone_timers[channel] >= self.tone_period(channel) {
+                self.tone_timers[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_timer += 1;
+        if self.noise_timer >= self.noise_period() * 2 {
+            self.noise_timer = 0;
+            // 17-bit LFSR with taps at bits 0 and 3.
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | feedback << 16;
+        }
+
+        self.envelope_timer += 1;
+        if self.envelope_timer >= self.envelope_period() {
+            self.envelope_timer = 0;
+            self.step_envelope();
+        }
+    }
+
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        &CHANNEL_NAMES
+    }
+
+    /// A channel at full volume is roughly twice as loud as an APU pulse at
+    /// full volume.
+    pub fn channel_levels(&self, levels: &mut [f32]) {
+        let mixer = self.registers[7];
+        let noise = self.noise_shift & 1 == 1;
+        for (channel, output) in levels.iter_mut().enumerate() {
+            let tone_on = mixer & (1 << channel) == 0;
+            let noise_on = mixer & (8 << channel) == 0;
+            let high = (self.tone_outputs[channel] || !tone_on) && (noise || !noise_on);
+            if !high {
+                *output = 0.0;
+                continue;
+            }
+            let volume = self.registers[8 + channel];
+            let level = if volume & 0x10 == 0x10 {
+                self.envelope_level()
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                (volume & 0x0F) as usize * 2 + 1
+            };
+            *output = self.levels[level] * 30.0;
+        }
+    }
+}
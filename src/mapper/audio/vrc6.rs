@@ -0,0 +1,164 @@
+/// Names of the channels, in the order `channel_levels` fills in their levels.
+pub const CHANNEL_NAMES: [&str; 3] = ["vrc6.pulse1", "vrc6.pulse2", "vrc6.saw"];
+
+/// A VRC6 pulse channel: a 16-step sequencer with eight duty cycles and a
+/// 4-bit volume.
+#[derive(Default)]
+pub struct VRC6Pulse {
+    pub enabled: bool,
+    pub constant: bool,
+    pub duty: u8,
+    pub volume: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub step: u8,
+}
+
+impl VRC6Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.constant = value & 0x80 == 0x80;
+                self.duty = (value >> 4) & 7;
+                self.volume = value & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.enabled = value & 0x80 == 0x80;
+                // Disabling the channel resets the duty cycle.
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn step_timer(&mut self, shift: u8) {
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+/// The VRC6 sawtooth channel: an accumulator that adds the rate on every
+/// other clock. After six additions it resets on the seventh such clock.
+#[derive(Default)]
+pub struct VRC6Sawtooth {
+    pub enabled: bool,
+    pub rate: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub step: u8,
+    pub accumulator: u8,
+}
+
+impl VRC6Sawtooth {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.enabled = value & 0x80 == 0x80;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn step_timer(&mut self, shift: u8) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled {
+            self.accumulator >> 3
+        } else {
+            0
+        }
+    }
+}
+
+/// VRC6 sound: two pulse channels and a sawtooth, all clocked by the CPU.
+/// Registers are addressed with the board's wiring already undone.
+///
+/// See https://wiki.nesdev.com/w/index.php/VRC6_audio
+#[derive(Default)]
+pub struct VRC6Audio {
+    pub pulses: [VRC6Pulse; 2],
+    pub sawtooth: VRC6Sawtooth,
+    pub halt: bool,
+    pub frequency_shift: u8,
+}
+
+impl VRC6Audio {
+    pub fn new() -> VRC6Audio {
+        VRC6Audio::default()
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0x9003 => {
+                self.halt = value & 0x01 == 0x01;
+                self.frequency_shift = if value & 0x04 == 0x04 {
+                    8
+                } else if value & 0x02 == 0x02 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000..=0x9002 => self.pulses[0].write(register & 3, value),
+            0xA000..=0xA002 => self.pulses[1].write(register & 3, value),
+            0xB000..=0xB002 => self.sawtooth.write(register & 3, value),
+            _ => {}
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.halt {
+            return;
+        }
+        for pulse in self.pulses.iter_mut() {
+            pulse.step_timer(self.frequency_shift);
+        }
+        self.sawtooth.step_timer(self.frequency_shift);
+    }
+
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        &CHANNEL_NAMES
+    }
+
+    /// A VRC6 pulse at full volume is about as loud as an APU pulse at full
+    /// volume, and the sawtooth has twice the range.
+    pub fn channel_levels(&self, levels: &mut [f32]) {
+        levels[0] = self.pulses[0].output() as f32;
+        levels[1] = self.pulses[1].output() as f32;
+        levels[2] = self.sawtooth.output() as f32;
+    }
+}
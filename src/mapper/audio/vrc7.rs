@@ -0,0 +1,382 @@
+use std::f32::consts::PI;
+
+/// Names of the channels, in the order `channel_levels` fills in their levels.
+pub const CHANNEL_NAMES: [&str; 6] = [
+    "vrc7.fm1", "vrc7.fm2", "vrc7.fm3", "vrc7.fm4", "vrc7.fm5", "vrc7.fm6",
+];
+
+/// The OPLL runs from a 3.58 MHz crystal and makes a sample every 72 clocks,
+/// once every 36 CPU cycles.
+const SAMPLE_CYCLES: u32 = 36;
+const SAMPLE_RATE: f32 = 1_789_773.0 / SAMPLE_CYCLES as f32;
+
+/// The built-in instruments. Instrument 0 is the custom instrument in
+/// registers $00-$07.
+/// See https://wiki.nesdev.com/w/index.php/VRC7_audio
+const PATCHES: [[u8; 8]; 16] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+const MULTIPLIERS: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+/// Key scale level attenuation in dB for the top four F-number bits in
+/// block 7. It drops by 6 dB per block below that.
+const KEY_SCALE_LEVELS: [f32; 16] = [
+    0.0, 9.0, 12.0, 13.875, 15.0, 16.125, 16.875, 17.625, 18.0, 18.75, 19.125, 19.5, 19.875,
+    20.25, 20.625, 21.0,
+];
+
+/// The envelope covers 48 dB, anything quieter is silent.
+const MAX_ATTENUATION: f32 = 48.0;
+
+/// Phase modulation depth of a modulator at full volume, in cycles (4π).
+const MODULATION_INDEX: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// One of the two FM operators of a channel. The envelope is kept as an
+/// attenuation in dB.
+#[derive(Clone, Copy)]
+pub struct Operator {
+    pub phase: f32,
+    pub attenuation: f32,
+    state: EnvelopeState,
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0.0,
+            attenuation: MAX_ATTENUATION,
+            state: EnvelopeState::Release,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Channel {
+    pub f_number: u16,
+    pub block: u8,
+    pub key_on: bool,
+    pub sustain: bool,
+    pub instrument: u8,
+    pub volume: u8,
+    pub operators: [Operator; 2],
+    pub feedback: [f32; 2],
+    pub output: f32,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            f_number: 0,
+            block: 0,
+            key_on: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            operators: [Operator::new(); 2],
+            feedback: [0.0; 2],
+            output: 0.0,
+        }
+    }
+}
+
+/// Time in seconds for an envelope rate to cover the full range, halved by
+/// every step of the effective rate.
+fn envelope_time(base: f32, rate: u8) -> f32 {
+    base / 2f32.powf((rate as f32 - 4.0) / 4.0)
+}
+
+/// VRC7 sound: a cut down Yamaha YM2413 (OPLL) with six two-operator FM
+/// channels, fifteen fixed instruments and one custom instrument.
+///
+/// This is a floating point model of the OPLL. It follows the chip's
+/// structure (phase modulation, feedback, ADSR envelopes, key scaling,
+/// tremolo and vibrato) but not its exact log-sin and exponent tables.
+///
+/// See https://wiki.nesdev.com/w/index.php/VRC7_audio
+pub struct VRC7Audio {
+    pub register: u8,
+    pub custom: [u8; 8],
+    pub channels: [Channel; 6],
+    pub silenced: bool,
+    pub cycle: u32,
+    pub tremolo_phase: f32,
+    pub vibrato_phase: f32,
+}
+
+impl VRC7Audio {
+    pub fn new() -> VRC7Audio {
+        VRC7Audio {
+            register: 0,
+            custom: [0; 8],
+            channels: [Channel::new(); 6],
+            silenced: false,
+            cycle: 0,
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+        }
+    }
+
+    /// $9010: select a register.
+    pub fn select(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    /// $9030: write the selected register.
+    pub fn write(&mut self, value: u8) {
+        let register = self.register;
+        let index = (register & 0x0F) as usize;
+        match register {
+            0x00..=0x07 => self.custom[index] = value,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[index];
+                channel.f_number = (channel.f_number & 0x100) | value as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[index];
+                channel.f_number = (channel.f_number & 0xFF) | ((value & 1) as u16) << 8;
+                channel.block = (value >> 1) & 7;
+                channel.sustain = value & 0x20 == 0x20;
+                let key_on = value & 0x10 == 0x10;
+                if key_on && !channel.key_on {
+                    for operator in channel.operators.iter_mut() {
+                        operator.phase = 0.0;
+                        operator.state = EnvelopeState::Attack;
+                    }
+                } else if !key_on && channel.key_on {
+                    for operator in channel.operators.iter_mut() {
+                        operator.state = EnvelopeState::Release;
+                    }
+                }
+                channel.key_on = key_on;
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[index];
+                channel.instrument = value >> 4;
+                channel.volume = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    /// $E000 bit 6 resets and silences the sound chip.
+    pub fn set_silenced(&mut self, silenced: bool) {
+        if silenced && !self.silenced {
+            self.channels = [Channel::new(); 6];
+        }
+        self.silenced = silenced;
+    }
+
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 {
+            self.custom
+        } else {
+            PATCHES[instrument as usize]
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.silenced {
+            return;
+        }
+        self.cycle += 1;
+        if self.cycle < SAMPLE_CYCLES {
+            return;
+        }
+        self.cycle = 0;
+
+        // Tremolo is 4.8 dB deep at 3.7 Hz, vibrato about 14 cents at 6.4 Hz.
+        self.tremolo_phase = (self.tremolo_phase + 3.7 / SAMPLE_RATE) % 1.0;
+        self.vibrato_phase = (self.vibrato_phase + 6.4 / SAMPLE_RATE) % 1.0;
+        let tremolo = (1.0 - (2.0 * PI * self.tremolo_phase).cos()) / 2.0 * 4.8;
+        let vibrato = 2f32.powf((2.0 * PI * self.vibrato_phase).sin() * 14.0 / 1200.0);
+
+        for i in 0..6 {
+            let patch = self.patch(self.channels[i].instrument);
+            let channel = &mut self.channels[i];
+            step_channel(channel, &patch, tremolo, vibrato);
+        }
+    }
+
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        &CHANNEL_NAMES
+    }
+
+    /// A channel at full volume is about as loud as an APU pulse at full
+    /// volume.
+    pub fn channel_levels(&self, levels: &mut [f32]) {
+        for (level, channel) in levels.iter_mut().zip(self.channels.iter()) {
+            *level = if self.silenced { 0.0 } else { channel.output * 15.0 };
+        }
+    }
+}
+
+fn step_channel(channel: &mut Channel, patch: &[u8; 8], tremolo: f32, vibrato: f32) {
+    let frequency = channel.f_number as f32 * SAMPLE_RATE * 2f32.powi(channel.block as i32)
+        / 2f32.powi(19);
+    let key_code = (channel.block << 1) | (channel.f_number >> 8) as u8;
+    let key_scale = (KEY_SCALE_LEVELS[(channel.f_number >> 5) as usize]
+        - 6.0 * (7 - channel.block) as f32)
+        .max(0.0);
+
+    let mut levels = [0.0; 2];
+    for (i, level) in levels.iter_mut().enumerate() {
+        let flags = patch[i];
+        let operator = &mut channel.operators[i];
+
+        // Phase
+        let mut increment = frequency * MULTIPLIERS[(flags & 0x0F) as usize] / SAMPLE_RATE;
+        if flags & 0x40 == 0x40 {
+            increment *= vibrato;
+        }
+        operator.phase = (operator.phase + increment) % 1.0;
+
+        // Envelope
+        let key_scale_rate = if flags & 0x10 == 0x10 {
+            key_code
+        } else {
+            key_code >> 2
+        };
+        let rates = (patch[4 + i], patch[6 + i]);
+        let attack = rates.0 >> 4;
+        let decay = rates.0 & 0x0F;
+        let sustain_level = (rates.1 >> 4) as f32 * 3.0;
+        let sustained = flags & 0x20 == 0x20;
+        let release = match operator.state {
+            EnvelopeState::Release if channel.sustain => 5,
+            EnvelopeState::Release if !sustained => 7,
+            _ => rates.1 & 0x0F,
+        };
+        step_envelope(
+            operator,
+            attack,
+            decay,
+            release,
+            sustain_level,
+            sustained,
+            key_scale_rate,
+        );
+
+        // Attenuation
+        let mut attenuation = operator.attenuation;
+        let key_scale_level = patch[2 + i] >> 6;
+        attenuation += key_scale * [0.0, 0.25, 0.5, 1.0][key_scale_level as usize];
+        attenuation += if i == 0 {
+            (patch[2] & 0x3F) as f32 * 0.75
+        } else {
+            channel.volume as f32 * 3.0
+        };
+        if flags & 0x80 == 0x80 {
+            attenuation += tremolo;
+        }
+        *level = if attenuation >= MAX_ATTENUATION {
+            0.0
+        } else {
+            10f32.powf(-attenuation / 20.0)
+        };
+    }
+
+    // The modulator feeds back into itself and modulates the carrier.
+    let feedback = patch[3] & 0x07;
+    let feedback_phase = if feedback > 0 {
+        (channel.feedback[0] + channel.feedback[1]) / 2.0 * 2f32.powi(feedback as i32 - 1)
+            / 32.0
+    } else {
+        0.0
+    };
+    let modulator_phase = channel.operators[0].phase + feedback_phase;
+    let modulator = wave(modulator_phase, patch[3] & 0x08 == 0x08) * levels[0];
+    channel.feedback = [channel.feedback[1], modulator];
+
+    let carrier_phase = channel.operators[1].phase + modulator * MODULATION_INDEX;
+    channel.output = wave(carrier_phase, patch[3] & 0x10 == 0x10) * levels[1];
+}
+
+fn step_envelope(
+    operator: &mut Operator,
+    attack: u8,
+    decay: u8,
+    release: u8,
+    sustain_level: f32,
+    sustained: bool,
+    key_scale_rate: u8,
+) {
+    let effective = |rate: u8| if rate == 0 { 0 } else { (rate * 4 + key_scale_rate).min(63) };
+    match operator.state {
+        EnvelopeState::Attack => {
+            let rate = effective(attack);
+            if rate >= 60 {
+                operator.attenuation = 0.0;
+            } else if rate > 0 {
+                // The attack curve is exponential.
+                let time = envelope_time(2.8, rate);
+                operator.attenuation -= operator.attenuation * 6.17 / (time * SAMPLE_RATE);
+            }
+            if operator.attenuation < 0.1 {
+                operator.attenuation = 0.0;
+                operator.state = EnvelopeState::Decay;
+            }
+        }
+        EnvelopeState::Decay => {
+            decay_envelope(operator, effective(decay));
+            if operator.attenuation >= sustain_level {
+                operator.attenuation = sustain_level;
+                operator.state = EnvelopeState::Sustain;
+            }
+        }
+        // Percussive instruments keep decaying at the release rate.
+        EnvelopeState::Sustain => {
+            if !sustained {
+                decay_envelope(operator, effective(release));
+            }
+        }
+        EnvelopeState::Release => decay_envelope(operator, effective(release)),
+    }
+}
+
+fn decay_envelope(operator: &mut Operator, rate: u8) {
+    if rate == 0 {
+        return;
+    }
+    let time = envelope_time(19.6, rate);
+    operator.attenuation += MAX_ATTENUATION / (time * SAMPLE_RATE);
+    if operator.attenuation > MAX_ATTENUATION {
+        operator.attenuation = MAX_ATTENUATION;
+    }
+}
+
+/// Sine wave for a phase in cycles. The rectified wave drops the negative
+/// half.
+fn wave(phase: f32, rectified: bool) -> f32 {
+    let value = (2.0 * PI * phase).sin();
+    if rectified && value < 0.0 {
+        0.0
+    } else {
+        value
+    }
+}
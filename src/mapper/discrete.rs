@@ -0,0 +1,324 @@
+//! Boards built from discrete logic chips: a latch that holds the bank
+//! numbers written to $8000-$FFFF.
+//!
+//! On boards with bus conflicts the ROM drives the data bus at the same time
+//! as the CPU during the write, so the latch sees the written value ANDed
+//! with the ROM byte at that address. Games avoid this by writing to a ROM
+//! location that holds the same value. NES 2.0 submapper 1 marks boards
+//! without bus conflicts and submapper 2 boards with them.
+//!
+//! See https://wiki.nesdev.com/w/index.php/Bus_conflict
+
+use cartridge::{Cartridge, Mirror};
+use mapper::Mapper;
+
+fn bus_conflicts(cartridge: &Cartridge, default: bool) -> bool {
+    match cartridge.submapper {
+        1 => false,
+        2 => true,
+        _ => default,
+    }
+}
+
+fn read_chr(cartridge: &Cartridge, chr_offset: usize, address: u16) -> u8 {
+    cartridge.chr[chr_offset + address as usize]
+}
+
+fn write_chr(cartridge: &mut Cartridge, chr_offset: usize, address: u16, value: u8) {
+    if cartridge.chr_ram {
+        cartridge.chr[chr_offset + address as usize] = value;
+    }
+}
+
+/// UxROM (mapper 2): a switchable 16 KiB PRG bank at $8000 and the last
+/// bank fixed at $C000. Used by Mega Man, Castlevania and Contra.
+pub struct UxROM {
+    pub cartridge: Cartridge,
+    pub bus_conflicts: bool,
+    pub prg_bank: usize,
+}
+
+impl UxROM {
+    pub fn new(cartridge: Cartridge) -> UxROM {
+        let bus_conflicts = bus_conflicts(&cartridge, false);
+        UxROM {
+            cartridge,
+            bus_conflicts,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for UxROM {
+    fn read(&self, address: u16) -> u8 {
+        let banks = self.cartridge.prg.len() / 0x4000;
+        match address {
+            0x0000..=0x1FFF => read_chr(&self.cartridge, 0, address),
+            0x8000..=0xBFFF => {
+                let offset = (self.prg_bank % banks) * 0x4000;
+                self.cartridge.prg[offset + (address - 0x8000) as usize]
+            }
+            0xC000..=0xFFFF => {
+                let offset = (banks - 1) * 0x4000;
+                self.cartridge.prg[offset + (address - 0xC000) as usize]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => write_chr(&mut self.cartridge, 0, address, value),
+            0x8000..=0xFFFF => {
+                let value = if self.bus_conflicts {
+                    value & self.read(address)
+                } else {
+                    value
+                };
+                self.prg_bank = value as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.cartridge.mirror_mode
+    }
+}
+
+/// CNROM (mapper 3): fixed PRG ROM and a switchable 8 KiB CHR bank.
+pub struct CNROM {
+    pub cartridge: Cartridge,
+    pub bus_conflicts: bool,
+    pub chr_bank: usize,
+}
+
+impl CNROM {
+    pub fn new(cartridge: Cartridge) -> CNROM {
+        let bus_conflicts = bus_conflicts(&cartridge, true);
+        CNROM {
+            cartridge,
+            bus_conflicts,
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_offset(&self) -> usize {
+        (self.chr_bank * 0x2000) % self.cartridge.chr.len()
+    }
+}
+
+impl Mapper for CNROM {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => read_chr(&self.cartridge, self.chr_offset(), address),
+            0x8000..=0xFFFF => {
+                let index = (address - 0x8000) as usize % self.cartridge.prg.len();
+                self.cartridge.prg[index]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let offset = self.chr_offset();
+                write_chr(&mut self.cartridge, offset, address, value);
+            }
+            0x8000..=0xFFFF => {
+                let value = if self.bus_conflicts {
+                    value & self.read(address)
+                } else {
+                    value
+                };
+                self.chr_bank = value as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.cartridge.mirror_mode
+    }
+}
+
+/// AxROM (mapper 7): a switchable 32 KiB PRG bank and single-screen
+/// mirroring selected by bit 4. Used by Battletoads and most Rare games.
+pub struct AxROM {
+    pub cartridge: Cartridge,
+    pub bus_conflicts: bool,
+    pub prg_bank: usize,
+    pub mirror: Mirror,
+}
+
+impl AxROM {
+    pub fn new(cartridge: Cartridge) -> AxROM {
+        let bus_conflicts = bus_conflicts(&cartridge, false);
+        AxROM {
+            cartridge,
+            bus_conflicts,
+            prg_bank: 0,
+            mirror: Mirror::SingleScreenA,
+        }
+    }
+}
+
+impl Mapper for AxROM {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => read_chr(&self.cartridge, 0, address),
+            0x8000..=0xFFFF => {
+                let offset = (self.prg_bank * 0x8000) % self.cartridge.prg.len();
+                self.cartridge.prg[offset + (address - 0x8000) as usize]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => write_chr(&mut self.cartridge, 0, address, value),
+            0x8000..=0xFFFF => {
+                let value = if self.bus_conflicts {
+                    value & self.read(address)
+                } else {
+                    value
+                };
+                self.prg_bank = (value & 0x07) as usize;
+                self.mirror = if value & 0x10 == 0 {
+                    Mirror::SingleScreenA
+                } else {
+                    Mirror::SingleScreenB
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.mirror
+    }
+}
+
+/// GxROM (mapper 66): a 32 KiB PRG bank in bits 4-5 and an 8 KiB CHR bank
+/// in bits 0-1.
+pub struct GxROM {
+    pub cartridge: Cartridge,
+    pub bus_conflicts: bool,
+    pub prg_bank: usize,
+    pub chr_bank: usize,
+}
+
+impl GxROM {
+    pub fn new(cartridge: Cartridge) -> GxROM {
+        let bus_conflicts = bus_conflicts(&cartridge, true);
+        GxROM {
+            cartridge,
+            bus_conflicts,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_offset(&self) -> usize {
+        (self.chr_bank * 0x2000) % self.cartridge.chr.len()
+    }
+}
+
+impl Mapper for GxROM {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => read_chr(&self.cartridge, self.chr_offset(), address),
+            0x8000..=0xFFFF => {
+                let offset = (self.prg_bank * 0x8000) % self.cartridge.prg.len();
+                self.cartridge.prg[offset + (address - 0x8000) as usize]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let offset = self.chr_offset();
+                write_chr(&mut self.cartridge, offset, address, value);
+            }
+            0x8000..=0xFFFF => {
+                let value = if self.bus_conflicts {
+                    value & self.read(address)
+                } else {
+                    value
+                };
+                self.prg_bank = ((value >> 4) & 0x03) as usize;
+                self.chr_bank = (value & 0x03) as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.cartridge.mirror_mode
+    }
+}
+
+/// Color Dreams (mapper 11): a 32 KiB PRG bank in bits 0-1 and an 8 KiB CHR
+/// bank in bits 4-7.
+pub struct ColorDreams {
+    pub cartridge: Cartridge,
+    pub bus_conflicts: bool,
+    pub prg_bank: usize,
+    pub chr_bank: usize,
+}
+
+impl ColorDreams {
+    pub fn new(cartridge: Cartridge) -> ColorDreams {
+        let bus_conflicts = bus_conflicts(&cartridge, true);
+        ColorDreams {
+            cartridge,
+            bus_conflicts,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_offset(&self) -> usize {
+        (self.chr_bank * 0x2000) % self.cartridge.chr.len()
+    }
+}
+
+impl Mapper for ColorDreams {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => read_chr(&self.cartridge, self.chr_offset(), address),
+            0x8000..=0xFFFF => {
+                let offset = (self.prg_bank * 0x8000) % self.cartridge.prg.len();
+                self.cartridge.prg[offset + (address - 0x8000) as usize]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let offset = self.chr_offset();
+                write_chr(&mut self.cartridge, offset, address, value);
+            }
+            0x8000..=0xFFFF => {
+                let value = if self.bus_conflicts {
+                    value & self.read(address)
+                } else {
+                    value
+                };
+                self.prg_bank = (value & 0x03) as usize;
+                self.chr_bank = (value >> 4) as usize;
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.cartridge.mirror_mode
+    }
+}
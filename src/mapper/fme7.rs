@@ -0,0 +1,157 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::audio::Sunsoft5BAudio;
+use mapper::Mapper;
+
+/// Sunsoft FME-7, 5A and 5B (mapper 69).
+///
+/// A command register at $8000 selects one of sixteen internal registers
+/// that is then written through $A000: eight 1 KiB CHR banks, four 8 KiB
+/// PRG banks (the one at $6000 can also select PRG RAM), mirroring and a
+/// 16-bit IRQ counter that counts down every CPU cycle. The 5B adds three
+/// sound channels.
+///
+/// See https://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+pub struct FME7 {
+    pub cartridge: Cartridge,
+    pub command: u8,
+    pub chr_banks: [u8; 8],
+    pub prg_banks: [u8; 4],
+    pub mirror: Mirror,
+
+    pub irq_enabled: bool,
+    pub irq_counter_enabled: bool,
+    pub irq_counter: u16,
+    pub irq_pending: bool,
+
+    pub audio: Sunsoft5BAudio,
+}
+
+impl FME7 {
+    pub fn new(cartridge: Cartridge) -> FME7 {
+        FME7 {
+            cartridge,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirror: Mirror::Vertical,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5BAudio::new(),
+        }
+    }
+
+    fn prg_rom_offset(&self, bank: u8, address: u16) -> usize {
+        let bank = (bank & 0x3F) as usize;
+        (bank * 0x2000) % self.cartridge.prg.len() + (address % 0x2000) as usize
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.chr_banks[(address / 0x0400) as usize] as usize;
+        (bank * 0x0400) % self.cartridge.chr.len() + (address % 0x0400) as usize
+    }
+
+    fn prg_ram_selected(&self) -> bool {
+        self.prg_banks[0] & 0x40 == 0x40
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_banks[0] & 0xC0 == 0xC0
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = value,
+            0x8..=0xB => self.prg_banks[(self.command - 8) as usize] = value,
+            0xC => {
+                self.mirror = match value & 3 {
+                    0 => Mirror::Vertical,
+                    1 => Mirror::Horizontal,
+                    2 => Mirror::SingleScreenA,
+                    _ => Mirror::SingleScreenB,
+                };
+            }
+            0xD => {
+                self.irq_enabled = value & 0x01 == 0x01;
+                self.irq_counter_enabled = value & 0x80 == 0x80;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for FME7 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.cartridge.chr[self.chr_offset(address)],
+            0x6000..=0x7FFF => {
+                if !self.prg_ram_selected() {
+                    self.cartridge.prg[self.prg_rom_offset(self.prg_banks[0], address)]
+                } else if self.prg_ram_enabled() {
+                    let bank = (self.prg_banks[0] & 0x3F) as usize;
+                    let index = (bank * 0x2000) % self.cartridge.sram.len();
+                    self.cartridge.sram[index + (address - 0x6000) as usize]
+                } else {
+                    0xFF
+                }
+            }
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((address - 0x6000) / 0x2000) as usize];
+                self.cartridge.prg[self.prg_rom_offset(bank, address)]
+            }
+            0xE000..=0xFFFF => {
+                let offset = self.cartridge.prg.len() - 0x2000;
+                self.cartridge.prg[offset + (address - 0xE000) as usize]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let index = self.chr_offset(address);
+                self.cartridge.chr[index] = value;
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let bank = (self.prg_banks[0] & 0x3F) as usize;
+                let index = (bank * 0x2000) % self.cartridge.sram.len();
+                self.cartridge.sram[index + (address - 0x6000) as usize] = value;
+            }
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(value),
+            0xC000..=0xDFFF => self.audio.select(value),
+            0xE000..=0xFFFF => self.audio.write(value),
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.mirror
+    }
+
+    fn step(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.step();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_channels(&self) -> &'static [&'static str] {
+        self.audio.channel_names()
+    }
+
+    fn audio_levels(&self, levels: &mut [f32]) {
+        self.audio.channel_levels(levels)
+    }
+}
@@ -0,0 +1,201 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::Mapper;
+
+/// MMC1 (mapper 1), used on the SxROM boards.
+///
+/// Registers are loaded serially: five writes to $8000-$FFFF shift in one bit
+/// each, and the fifth write stores the value in the register selected by
+/// address bits 13-14. Writing a value with bit 7 set resets the shift
+/// register.
+///
+/// The larger boards reuse the upper CHR bank bits when they only have 8 KiB
+/// of CHR RAM:
+///
+/// - SNROM: bit 4 disables PRG RAM
+/// - SOROM: bit 3 selects one of two 8 KiB PRG RAM banks
+/// - SUROM: bit 4 selects the 256 KiB PRG ROM half
+/// - SXROM: bit 4 selects the PRG ROM half, bits 2-3 one of four PRG RAM banks
+///
+/// See https://wiki.nesdev.com/w/index.php/MMC1
+pub struct MMC1 {
+    pub cartridge: Cartridge,
+    pub shift_register: u8,
+    pub control: u8,
+    pub prg_bank: u8,
+    pub chr_bank_0: u8,
+    pub chr_bank_1: u8,
+    pub prg_offsets: [usize; 2],
+    pub chr_offsets: [usize; 2],
+    pub sram_offset: usize,
+
+    // MMC1 ignores a write on the cycle following another write.
+    pub cycle: u64,
+    pub last_write_cycle: Option<u64>,
+}
+
+impl MMC1 {
+    pub fn new(cartridge: Cartridge) -> MMC1 {
+        let mut mapper = MMC1 {
+            cartridge,
+            shift_register: 0x10,
+            control: 0x0C,
+            prg_bank: 0,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_offsets: [0; 2],
+            chr_offsets: [0; 2],
+            sram_offset: 0,
+            cycle: 0,
+            last_write_cycle: None,
+        };
+        mapper.update_offsets();
+        mapper
+    }
+
+    pub fn load_register(&mut self, address: u16, value: u8) {
+        if value & 0x80 == 0x80 {
+            self.shift_register = 0x10;
+            let control = self.control | 0x0C;
+            self.write_register(0x8000, control);
+            return;
+        }
+        // The initial 1 bit reaches bit 0 after four writes.
+        let complete = self.shift_register & 1 == 1;
+        self.shift_register >>= 1;
+        self.shift_register |= (value & 1) << 4;
+        if complete {
+            let value = self.shift_register;
+            self.write_register(address, value);
+            self.shift_register = 0x10;
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+        self.update_offsets();
+    }
+
+    fn prg_bank_offset(&self, bank: usize) -> usize {
+        (bank * 0x4000) % self.cartridge.prg.len()
+    }
+
+    fn chr_bank_offset(&self, bank: usize) -> usize {
+        (bank * 0x1000) % self.cartridge.chr.len()
+    }
+
+    pub fn prg_ram_enabled(&self) -> bool {
+        if self.prg_bank & 0x10 == 0x10 {
+            return false;
+        }
+        // SNROM wires CHR A16 to the PRG RAM enable.
+        let snrom = self.cartridge.chr_ram && self.cartridge.prg.len() <= 0x40000;
+        !(snrom && self.chr_bank_0 & 0x10 == 0x10)
+    }
+
+    // PRG ROM bank mode (0, 1: switch 32 KB at $8000, ignoring low bit of bank number;
+    //                    2: fix first bank at $8000 and switch 16 KB bank at $C000;
+    //                    3: fix last bank at $C000 and switch 16 KB bank at $8000)
+    // CHR ROM bank mode (0: switch 8 KB at a time; 1: switch two separate 4 KB banks)
+    pub fn update_offsets(&mut self) {
+        let prg_mode = (self.control >> 2) & 3;
+        let chr_mode = (self.control >> 4) & 1;
+
+        // SUROM and SXROM select the 256 KiB PRG ROM half through CHR A16.
+        let outer = if self.cartridge.prg.len() > 0x40000 {
+            (self.chr_bank_0 & 0x10) as usize
+        } else {
+            0
+        };
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let (first, second) = match prg_mode {
+            0 | 1 => (bank & 0x0E, bank | 0x01),
+            2 => (0, bank),
+            _ => (bank, 0x0F),
+        };
+        self.prg_offsets[0] = self.prg_bank_offset(outer | first);
+        self.prg_offsets[1] = self.prg_bank_offset(outer | second);
+
+        let (first, second) = if chr_mode == 0 {
+            (self.chr_bank_0 & 0x1E, self.chr_bank_0 | 0x01)
+        } else {
+            (self.chr_bank_0, self.chr_bank_1)
+        };
+        self.chr_offsets[0] = self.chr_bank_offset(first as usize);
+        self.chr_offsets[1] = self.chr_bank_offset(second as usize);
+
+        // SOROM and SXROM select the PRG RAM bank through CHR A13 and A14.
+        let sram_bank = match self.cartridge.sram.len() {
+            0x4000 => (self.chr_bank_0 >> 3) & 1,
+            0x8000 => (self.chr_bank_0 >> 2) & 3,
+            _ => 0,
+        };
+        self.sram_offset = sram_bank as usize * 0x2000;
+    }
+}
+
+impl Mapper for MMC1 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => {
+                let bank = (address / 0x1000) as usize;
+                let offset = (address % 0x1000) as usize;
+                self.cartridge.chr[self.chr_offsets[bank] + offset]
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.cartridge.sram[self.sram_offset + (address - 0x6000) as usize]
+            }
+            0x8000..=0xFFFF => {
+                let address = address - 0x8000;
+                let bank = (address / 0x4000) as usize;
+                let offset = (address % 0x4000) as usize;
+                self.cartridge.prg[self.prg_offsets[bank] + offset]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let bank = (address / 0x1000) as usize;
+                let offset = (address % 0x1000) as usize;
+                self.cartridge.chr[self.chr_offsets[bank] + offset] = value;
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let index = self.sram_offset + (address - 0x6000) as usize;
+                self.cartridge.sram[index] = value;
+            }
+            0x8000..=0xFFFF => {
+                // Writes on consecutive cycles, like the double write of a
+                // read-modify-write instruction, only see the first one.
+                let consecutive = match self.last_write_cycle {
+                    Some(cycle) => self.cycle <= cycle + 1,
+                    None => false,
+                };
+                self.last_write_cycle = Some(self.cycle);
+                if !consecutive {
+                    self.load_register(address, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        match self.control & 3 {
+            0 => Mirror::SingleScreenA,
+            1 => Mirror::SingleScreenB,
+            2 => Mirror::Vertical,
+            _ => Mirror::Horizontal,
+        }
+    }
+
+    fn step(&mut self) {
+        self.cycle += 1;
+    }
+}
@@ -0,0 +1,117 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::Mapper;
+
+/// MMC2 (mapper 9, PxROM) and MMC4 (mapper 10, FxROM).
+///
+/// Each 4 KiB pattern table has two CHR banks and a latch that picks one of
+/// them. The latch flips when the PPU fetches the high plane of tile $FD or
+/// $FE, so a game can switch banks in the middle of a scan line by placing
+/// those tiles in the name table. The fetch itself still uses the old bank.
+///
+/// MMC2 switches 8 KiB of PRG at $8000 and fixes the last three 8 KiB banks.
+/// MMC4 switches 16 KiB at $8000, fixes the last 16 KiB bank and has PRG RAM.
+/// MMC2 only flips the first latch on the exact addresses $0FD8 and $0FE8.
+///
+/// See https://wiki.nesdev.com/w/index.php/MMC2
+pub struct MMC2 {
+    pub cartridge: Cartridge,
+    pub mmc4: bool,
+    pub prg_bank: usize,
+    pub chr_banks: [[usize; 2]; 2],
+    pub latches: [usize; 2],
+    pub mirror: Mirror,
+}
+
+impl MMC2 {
+    pub fn new(cartridge: Cartridge, mmc4: bool) -> MMC2 {
+        let mirror = cartridge.mirror_mode;
+        MMC2 {
+            cartridge,
+            mmc4,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [1; 2],
+            mirror,
+        }
+    }
+
+    fn prg_size(&self) -> usize {
+        if self.mmc4 {
+            0x4000
+        } else {
+            0x2000
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let size = self.prg_size();
+        let banks = self.cartridge.prg.len() / size;
+        let address = (address - 0x8000) as usize;
+        let window = address / size;
+        // Only the first window switches, the others hold the last banks.
+        let bank = if window == 0 {
+            self.prg_bank % banks
+        } else {
+            banks - 0x8000 / size + window
+        };
+        bank * size + address % size
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let table = (address / 0x1000) as usize;
+        let bank = self.chr_banks[table][self.latches[table]];
+        (bank * 0x1000) % self.cartridge.chr.len() + (address % 0x1000) as usize
+    }
+}
+
+impl Mapper for MMC2 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.cartridge.chr[self.chr_offset(address)],
+            0x6000..=0x7FFF if self.mmc4 => self.cartridge.sram[(address - 0x6000) as usize],
+            0x8000..=0xFFFF => self.cartridge.prg[self.prg_offset(address)],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let index = self.chr_offset(address);
+                self.cartridge.chr[index] = value;
+            }
+            0x6000..=0x7FFF if self.mmc4 => {
+                self.cartridge.sram[(address - 0x6000) as usize] = value;
+            }
+            0xA000..=0xAFFF => self.prg_bank = (value & 0x0F) as usize,
+            0xB000..=0xBFFF => self.chr_banks[0][0] = (value & 0x1F) as usize,
+            0xC000..=0xCFFF => self.chr_banks[0][1] = (value & 0x1F) as usize,
+            0xD000..=0xDFFF => self.chr_banks[1][0] = (value & 0x1F) as usize,
+            0xE000..=0xEFFF => self.chr_banks[1][1] = (value & 0x1F) as usize,
+            0xF000..=0xFFFF => {
+                self.mirror = if value & 1 == 0 {
+                    Mirror::Vertical
+                } else {
+                    Mirror::Horizontal
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.mirror
+    }
+
+    fn ppu_address(&mut self, address: u16) {
+        match address {
+            0x0FD8 => self.latches[0] = 0,
+            0x0FE8 => self.latches[0] = 1,
+            0x0FD9..=0x0FDF if self.mmc4 => self.latches[0] = 0,
+            0x0FE9..=0x0FEF if self.mmc4 => self.latches[0] = 1,
+            0x1FD8..=0x1FDF => self.latches[1] = 0,
+            0x1FE8..=0x1FEF => self.latches[1] = 1,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::Mapper;
+
+/// The MMC3 revisions differ in when the scanline counter raises an IRQ.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MMC3Revision {
+    /// MMC3A: only a counter that reaches zero by decrementing, or that is
+    /// reloaded with zero after a write to $C001, raises an IRQ.
+    MMC3A,
+    /// MMC3B and later: the IRQ fires whenever the counter is zero after it
+    /// is clocked, so a latch of 0 raises an IRQ on every scan line.
+    MMC3B,
+}
+
+impl MMC3Revision {
+    pub fn from_name(name: &str) -> Option<MMC3Revision> {
+        match name {
+            "a" => Some(MMC3Revision::MMC3A),
+            "b" => Some(MMC3Revision::MMC3B),
+            _ => None,
+        }
+    }
+}
+
+/// MMC3 (mapper 4), used on the TxROM boards.
+///
+/// Two switchable 8 KiB PRG banks, two 2 KiB and four 1 KiB CHR banks, and a
+/// scan line counter that is clocked by rising edges of PPU A12. With the
+/// usual setup (background from $0000, sprites from $1000) A12 rises once per
+/// scan line when the PPU starts fetching sprite patterns.
+///
+/// See https://wiki.nesdev.com/w/index.php/MMC3
+pub struct MMC3 {
+    pub cartridge: Cartridge,
+    pub revision: MMC3Revision,
+    pub register: u8,
+    pub registers: [u8; 8],
+    pub prg_mode: u8,
+    pub chr_mode: u8,
+    pub prg_offsets: [usize; 4],
+    pub chr_offsets: [usize; 8],
+    pub mirror: Mirror,
+    pub prg_ram_enabled: bool,
+    pub prg_ram_write_protect: bool,
+
+    // Scan line counter
+    pub irq_latch: u8,
+    pub irq_counter: u8,
+    pub irq_reload: bool,
+    pub irq_enabled: bool,
+    pub irq_pending: bool,
+
+    // PPU A12 edge detection. A12 has to stay low for a few CPU cycles
+    // before a rising edge clocks the counter, which filters out the short
+    // pulses during sprite fetches.
+    pub cycle: u64,
+    pub a12: bool,
+    pub a12_low_cycle: u64,
+}
+
+// Number of CPU cycles A12 has to be low before a rise is counted.
+const A12_FILTER_CYCLES: u64 = 3;
+
+impl MMC3 {
+    pub fn new(cartridge: Cartridge, revision: MMC3Revision) -> MMC3 {
+        let mirror = cartridge.mirror_mode;
+        let mut mapper = MMC3 {
+            cartridge,
+            revision,
+            register: 0,
+            registers: [0; 8],
+            prg_mode: 0,
+            chr_mode: 0,
+            prg_offsets: [0; 4],
+            chr_offsets: [0; 8],
+            mirror,
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            cycle: 0,
+            a12: false,
+            a12_low_cycle: 0,
+        };
+        mapper.update_offsets();
+        mapper
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        let even = address & 1 == 0;
+        match (address, even) {
+            (0x8000..=0x9FFF, true) => self.write_bank_select(value),
+            (0x8000..=0x9FFF, false) => self.write_bank_data(value),
+            (0xA000..=0xBFFF, true) => self.write_mirror(value),
+            (0xA000..=0xBFFF, false) => self.write_protect(value),
+            (0xC000..=0xDFFF, true) => self.irq_latch = value,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+
+    fn write_bank_select(&mut self, value: u8) {
+        self.prg_mode = (value >> 6) & 1;
+        self.chr_mode = (value >> 7) & 1;
+        self.register = value & 7;
+        self.update_offsets();
+    }
+
+    fn write_bank_data(&mut self, value: u8) {
+        self.registers[self.register as usize] = value;
+        self.update_offsets();
+    }
+
+    fn write_mirror(&mut self, value: u8) {
+        // Four-screen boards hardwire their mirroring.
+        if self.cartridge.mirror_mode == Mirror::FourScreen {
+            return;
+        }
+        self.mirror = if value & 1 == 0 {
+            Mirror::Vertical
+        } else {
+            Mirror::Horizontal
+        };
+    }
+
+    fn write_protect(&mut self, value: u8) {
+        self.prg_ram_enabled = value & 0x80 == 0x80;
+        self.prg_ram_write_protect = value & 0x40 == 0x40;
+    }
+
+    /// Offset of an 8 KiB PRG bank. Negative banks count from the end.
+    fn prg_bank_offset(&self, bank: i32) -> usize {
+        let count = (self.cartridge.prg.len() / 0x2000) as i32;
+        let bank = ((bank % count) + count) % count;
+        bank as usize * 0x2000
+    }
+
+    fn chr_bank_offset(&self, bank: u8) -> usize {
+        (bank as usize * 0x0400) % self.cartridge.chr.len()
+    }
+
+    pub fn update_offsets(&mut self) {
+        let r = self.registers;
+        match self.prg_mode {
+            0 => {
+                self.prg_offsets[0] = self.prg_bank_offset(r[6] as i32);
+                self.prg_offsets[1] = self.prg_bank_offset(r[7] as i32);
+                self.prg_offsets[2] = self.prg_bank_offset(-2);
+                self.prg_offsets[3] = self.prg_bank_offset(-1);
+            }
+            _ => {
+                self.prg_offsets[0] = self.prg_bank_offset(-2);
+                self.prg_offsets[1] = self.prg_bank_offset(r[7] as i32);
+                self.prg_offsets[2] = self.prg_bank_offset(r[6] as i32);
+                self.prg_offsets[3] = self.prg_bank_offset(-1);
+            }
+        }
+
+        // R0 and R1 select 2 KiB banks, R2-R5 select 1 KiB banks. CHR
+        // inversion swaps the two halves of the pattern tables.
+        let banks = [
+            r[0] & 0xFE,
+            r[0] | 0x01,
+            r[1] & 0xFE,
+            r[1] | 0x01,
+            r[2],
+            r[3],
+            r[4],
+            r[5],
+        ];
+        for (i, &bank) in banks.iter().enumerate() {
+            let slot = if self.chr_mode == 0 { i } else { i ^ 4 };
+            self.chr_offsets[slot] = self.chr_bank_offset(bank);
+        }
+    }
+
+    pub fn clock_counter(&mut self) {
+        let previous = self.irq_counter;
+        let reload = self.irq_reload;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+        } else {
+            self.irq_counter -= 1;
+        }
+        self.irq_reload = false;
+
+        let trigger = match self.revision {
+            MMC3Revision::MMC3A => self.irq_counter == 0 && (previous != 0 || reload),
+            MMC3Revision::MMC3B => self.irq_counter == 0,
+        };
+        if trigger && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for MMC3 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => {
+                let bank = (address / 0x0400) as usize;
+                let offset = (address % 0x0400) as usize;
+                self.cartridge.chr[self.chr_offsets[bank] + offset]
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled => {
+                self.cartridge.sram[(address - 0x6000) as usize]
+            }
+            0x8000..=0xFFFF => {
+                let address = address - 0x8000;
+                let bank = (address / 0x2000) as usize;
+                let offset = (address % 0x2000) as usize;
+                self.cartridge.prg[self.prg_offsets[bank] + offset]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let bank = (address / 0x0400) as usize;
+                let offset = (address % 0x0400) as usize;
+                self.cartridge.chr[self.chr_offsets[bank] + offset] = value;
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled && !self.prg_ram_write_protect => {
+                self.cartridge.sram[(address - 0x6000) as usize] = value;
+            }
+            0x8000..=0xFFFF => self.write_register(address, value),
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.mirror
+    }
+
+    fn step(&mut self) {
+        self.cycle += 1;
+    }
+
+    fn ppu_address(&mut self, address: u16) {
+        let a12 = address & 0x1000 == 0x1000;
+        if a12 && !self.a12 && self.cycle - self.a12_low_cycle >= A12_FILTER_CYCLES {
+            self.clock_counter();
+        }
+        if !a12 && self.a12 {
+            self.a12_low_cycle = self.cycle;
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+}
@@ -0,0 +1,455 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::audio::MMC5Audio;
+use mapper::Mapper;
+
+/// Reads of one PPU scan line as MMC5 counts them: 32 background tiles of
+/// four fetches, eight sprites of four fetches, two background tiles for the
+/// next line and two unused name table fetches.
+const FETCHES_PER_LINE: u32 = 170;
+const SPRITE_FETCHES: u32 = 128;
+const PREFETCHES: u32 = 160;
+const DUMMY_FETCHES: u32 = 168;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PrgBank {
+    Rom(usize),
+    Ram(usize),
+}
+
+/// MMC5 (mapper 5), used on the ExROM boards.
+///
+/// Besides PRG and CHR banking in four sizes each, MMC5 has 1 KiB of
+/// expansion RAM (ExRAM). Depending on $5104 ExRAM is an extra name table,
+/// holds a CHR bank and palette per background tile (extended attributes),
+/// or is plain RAM. Any name table can also be replaced by a single fill
+/// tile and attribute.
+///
+/// MMC5 has no connection to the PPU's state, it watches the PPU's fetches
+/// instead. Three reads of the same name table address in a row only happen
+/// at the end of a scan line, which clocks the scan line counter and lines
+/// up the fetch counter. The fetch counter tells background fetches from
+/// sprite fetches, which drives the vertical split, extended attributes and
+/// the separate sprite and background CHR banks in 8x16 sprite mode.
+///
+/// See https://wiki.nesdev.com/w/index.php/MMC5
+pub struct MMC5 {
+    pub cartridge: Cartridge,
+    pub exram: [u8; 1024],
+    pub exram_mode: u8,
+
+    // Banking
+    pub prg_mode: u8,
+    pub prg_registers: [u8; 5],
+    pub prg_ram_protect: [u8; 2],
+    pub chr_mode: u8,
+    pub chr_registers: [u16; 12],
+    pub chr_upper: u8,
+    pub chr_last_sprite: bool,
+    pub sprite_size_16: bool,
+
+    // Name tables
+    pub name_table_mapping: u8,
+    pub fill_tile: u8,
+    pub fill_attribute: u8,
+    pub exram_attribute: u8,
+
+    // Vertical split
+    pub split_control: u8,
+    pub split_scroll: u8,
+    pub split_bank: u8,
+
+    // Scan line detection
+    pub in_frame: bool,
+    pub scan_line: u8,
+    pub fetch: u32,
+    pub last_address: Option<u16>,
+    pub matches: u8,
+    pub idle_cycles: u8,
+
+    // Scan line IRQ
+    pub irq_compare: u8,
+    pub irq_enabled: bool,
+    pub irq_pending: bool,
+
+    // 8x8 multiplier
+    pub multiplicand: u8,
+    pub multiplier: u8,
+
+    pub audio: MMC5Audio,
+}
+
+impl MMC5 {
+    pub fn new(cartridge: Cartridge) -> MMC5 {
+        MMC5 {
+            cartridge,
+            exram: [0; 1024],
+            exram_mode: 0,
+            prg_mode: 3,
+            prg_registers: [0, 0, 0, 0, 0xFF],
+            prg_ram_protect: [0; 2],
+            chr_mode: 0,
+            chr_registers: [0; 12],
+            chr_upper: 0,
+            chr_last_sprite: true,
+            sprite_size_16: false,
+            name_table_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            exram_attribute: 0,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            in_frame: false,
+            scan_line: 0,
+            fetch: 0,
+            last_address: None,
+            matches: 0,
+            idle_cycles: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            audio: MMC5Audio::new(),
+        }
+    }
+
+    //// PRG ////
+
+    /// Register value and 8 KiB bank number for a CPU window ($6000, $8000,
+    /// $A000, $C000, $E000).
+    fn prg_bank(&self, window: usize) -> PrgBank {
+        let r = self.prg_registers;
+        if window == 0 {
+            return PrgBank::Ram(r[0] as usize);
+        }
+        let quarter = window - 1;
+        let (value, bank) = match (self.prg_mode, quarter) {
+            (0, _) => (r[4] | 0x80, (r[4] & 0x7C) as usize | quarter),
+            (1, 0..=1) | (2, 0..=1) => (r[2], (r[2] & 0x7E) as usize | quarter),
+            (1, _) => (r[4] | 0x80, (r[4] & 0x7E) as usize | (quarter & 1)),
+            (2, 2) => (r[3], r[3] as usize),
+            (2, _) => (r[4] | 0x80, r[4] as usize),
+            _ => (r[quarter + 1], r[quarter + 1] as usize),
+        };
+        // $5117 always selects ROM.
+        if value & 0x80 == 0x80 || quarter == 3 {
+            PrgBank::Rom(bank & 0x7F)
+        } else {
+            PrgBank::Ram(bank)
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> PrgBank {
+        let window = (address as usize - 0x6000) / 0x2000;
+        let offset = address as usize % 0x2000;
+        match self.prg_bank(window) {
+            PrgBank::Rom(bank) => {
+                PrgBank::Rom((bank * 0x2000) % self.cartridge.prg.len() + offset)
+            }
+            PrgBank::Ram(bank) => {
+                PrgBank::Ram(((bank & 7) * 0x2000) % self.cartridge.sram.len() + offset)
+            }
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0] & 3 == 2 && self.prg_ram_protect[1] & 3 == 1
+    }
+
+    //// CHR ////
+
+    /// True while the PPU is fetching sprite patterns.
+    fn sprite_fetch(&self) -> bool {
+        let fetch = self.fetch % FETCHES_PER_LINE;
+        self.in_frame && (SPRITE_FETCHES..PREFETCHES).contains(&fetch)
+    }
+
+    /// Screen column (0-33) and scan line of the background tile being
+    /// fetched, if any. The first two tiles of a line are fetched at the end
+    /// of the previous one.
+    fn background_fetch(&self) -> Option<(u32, u32)> {
+        if !self.in_frame {
+            return None;
+        }
+        // A count past the end of the line means the fetch that starts the
+        // next line is under way.
+        let fetch = self.fetch % FETCHES_PER_LINE;
+        let line = self.scan_line as u32 + self.fetch / FETCHES_PER_LINE;
+        if fetch < SPRITE_FETCHES {
+            Some((fetch / 4 + 2, line))
+        } else if (PREFETCHES..DUMMY_FETCHES).contains(&fetch) {
+            Some(((fetch - PREFETCHES) / 4, line + 1))
+        } else {
+            None
+        }
+    }
+
+    /// Row of the split region's name table for the tile being fetched, if
+    /// it lies inside the split region.
+    fn split_row(&self) -> Option<(u32, u32)> {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return None;
+        }
+        let (column, line) = self.background_fetch()?;
+        let threshold = (self.split_control & 0x1F) as u32;
+        let inside = if self.split_control & 0x40 == 0 {
+            column < threshold
+        } else {
+            column >= threshold
+        };
+        if !inside {
+            return None;
+        }
+        let y = (line + self.split_scroll as u32) % 240;
+        Some((column % 32, y))
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let len = self.cartridge.chr.len();
+        if self.background_fetch().is_some() {
+            if let Some((_, y)) = self.split_row() {
+                let offset = (address as usize & 0x0FF8) | (y as usize & 7);
+                return (self.split_bank as usize * 0x1000 + offset) % len;
+            }
+            if self.exram_mode == 1 {
+                let bank = (self.exram_attribute & 0x3F) as usize | (self.chr_upper as usize) << 6;
+                return (bank * 0x1000 + (address as usize & 0x0FFF)) % len;
+            }
+        }
+
+        // In 8x16 sprite mode sprites use $5120-$5127 and the background
+        // $5128-$512B. Otherwise the set that was written last is used.
+        let sprite_set = if self.sprite_size_16 && self.in_frame {
+            self.sprite_fetch()
+        } else {
+            self.chr_last_sprite
+        };
+        let size = 0x2000 >> self.chr_mode;
+        let group = address as usize / size;
+        let last = (group + 1) * (8 >> self.chr_mode) - 1;
+        let register = if sprite_set { last } else { 8 + last % 4 };
+        let bank = self.chr_registers[register] as usize;
+        (bank * size + address as usize % size) % len
+    }
+
+    //// Name tables ////
+
+    /// Name table or attribute byte from ExRAM for the split region.
+    fn split_name_table(&self, address: u16, column: u32, y: u32) -> u8 {
+        if address & 0x03FF < 0x03C0 {
+            self.exram[((y / 8) * 32 + column) as usize]
+        } else {
+            let attribute = self.exram[(0x03C0 + (y / 32) * 8 + column / 4) as usize];
+            let shift = ((y / 16) & 1) * 4 + ((column / 2) & 1) * 2;
+            let palette = (attribute >> shift) & 3;
+            palette * 0x55
+        }
+    }
+
+    //// Scan line detection ////
+
+    fn detect_scan_line(&mut self, address: u16) {
+        if (0x2000..0x3000).contains(&address) && self.last_address == Some(address) {
+            self.matches += 1;
+            if self.matches == 2 {
+                if self.in_frame {
+                    self.scan_line = self.scan_line.wrapping_add(1);
+                    if self.scan_line == self.irq_compare {
+                        self.irq_pending = true;
+                    }
+                } else {
+                    self.in_frame = true;
+                    self.scan_line = 0;
+                    self.irq_pending = false;
+                }
+                // This read was the first fetch of the line.
+                self.fetch = 0;
+            }
+        } else {
+            self.matches = 0;
+        }
+        self.last_address = Some(address);
+    }
+
+    fn leave_frame(&mut self) {
+        self.in_frame = false;
+        self.last_address = None;
+        self.matches = 0;
+    }
+}
+
+impl Mapper for MMC5 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.cartridge.chr[self.chr_offset(address)],
+            0x5010 | 0x5015 => self.audio.read(address),
+            0x5204 => (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6,
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[(address - 0x5C00) as usize],
+            0x6000..=0xFFFF => match self.prg_offset(address) {
+                PrgBank::Rom(offset) => self.cartridge.prg[offset],
+                PrgBank::Ram(offset) => self.cartridge.sram[offset],
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        let value = self.read(address);
+        match address {
+            0x5010 => self.audio.pcm_irq_pending = false,
+            0x5204 => self.irq_pending = false,
+            0x8000..=0xBFFF => self.audio.read_pcm(value),
+            // Fetching the NMI vector means the PPU is in vertical blank.
+            0xFFFA | 0xFFFB => self.leave_frame(),
+            _ => {}
+        }
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let index = self.chr_offset(address);
+                self.cartridge.chr[index] = value;
+            }
+            0x5000..=0x5015 => self.audio.write(address, value),
+            0x5100 => self.prg_mode = value & 3,
+            0x5101 => self.chr_mode = value & 3,
+            0x5102 => self.prg_ram_protect[0] = value,
+            0x5103 => self.prg_ram_protect[1] = value,
+            0x5104 => self.exram_mode = value & 3,
+            0x5105 => self.name_table_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 3,
+            0x5113..=0x5117 => self.prg_registers[(address - 0x5113) as usize] = value,
+            0x5120..=0x512B => {
+                let register = (address - 0x5120) as usize;
+                self.chr_registers[register] = value as u16 | (self.chr_upper as u16) << 8;
+                self.chr_last_sprite = register < 8;
+            }
+            0x5130 => self.chr_upper = value & 3,
+            0x5200 => self.split_control = value,
+            0x5201 => self.split_scroll = value,
+            0x5202 => self.split_bank = value,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 == 0x80,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            0x5C00..=0x5FFF => {
+                let index = (address - 0x5C00) as usize;
+                match self.exram_mode {
+                    // The PPU owns ExRAM while it is not rendering.
+                    0 | 1 => self.exram[index] = if self.in_frame { value } else { 0 },
+                    2 => self.exram[index] = value,
+                    _ => {}
+                }
+            }
+            0x6000..=0xFFFF => {
+                if let PrgBank::Ram(offset) = self.prg_offset(address) {
+                    if self.prg_ram_writable() {
+                        self.cartridge.sram[offset] = value;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        // Tables mapped to ExRAM or fill mode never reach console VRAM.
+        let mut pages = [0; 4];
+        for (table, page) in pages.iter_mut().enumerate() {
+            *page = (self.name_table_mapping >> (table * 2)) & 1;
+        }
+        Mirror::Custom(pages)
+    }
+
+    fn step(&mut self) {
+        // The PPU stops fetching outside of the visible frame.
+        if self.idle_cycles > 0 {
+            self.idle_cycles -= 1;
+            if self.idle_cycles == 0 {
+                self.leave_frame();
+            }
+        }
+        self.audio.step();
+    }
+
+    fn ppu_register_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x2000 => self.sprite_size_16 = value & 0x20 == 0x20,
+            0x2001 if value & 0x18 == 0 => {
+                self.leave_frame();
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_address(&mut self, address: u16) {
+        self.idle_cycles = 3;
+        self.detect_scan_line(address);
+        self.fetch += 1;
+    }
+
+    fn read_name_table(&mut self, address: u16) -> Option<u8> {
+        let table = (address - 0x2000) / 0x0400;
+        let offset = (address & 0x03FF) as usize;
+        let attribute = offset >= 0x03C0;
+
+        if self.background_fetch().is_some() {
+            if let Some((column, y)) = self.split_row() {
+                return Some(self.split_name_table(address, column, y));
+            }
+            if self.exram_mode == 1 {
+                if attribute {
+                    let palette = self.exram_attribute >> 6;
+                    return Some(palette * 0x55);
+                }
+                self.exram_attribute = self.exram[offset];
+            }
+        }
+
+        match (self.name_table_mapping >> (table * 2)) & 3 {
+            0 | 1 => None,
+            2 => Some(if self.exram_mode <= 1 {
+                self.exram[offset]
+            } else {
+                0
+            }),
+            _ => Some(if attribute {
+                self.fill_attribute * 0x55
+            } else {
+                self.fill_tile
+            }),
+        }
+    }
+
+    fn write_name_table(&mut self, address: u16, value: u8) -> bool {
+        let table = (address - 0x2000) / 0x0400;
+        match (self.name_table_mapping >> (table * 2)) & 3 {
+            0 | 1 => false,
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[(address & 0x03FF) as usize] = value;
+                }
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || self.audio.irq()
+    }
+
+    fn audio_channels(&self) -> &'static [&'static str] {
+        self.audio.channel_names()
+    }
+
+    fn audio_levels(&self, levels: &mut [f32]) {
+        self.audio.channel_levels(levels)
+    }
+}
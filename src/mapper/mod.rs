@@ -0,0 +1,412 @@
+use std::io;
+
+use cartridge::{Cartridge, Mirror};
+
+mod audio;
+mod discrete;
+mod fme7;
+mod mmc1;
+mod mmc2;
+mod mmc3;
+mod mmc5;
+mod namco163;
+mod nrom;
+mod vrc;
+mod vrc4;
+mod vrc6;
+mod vrc7;
+
+pub use self::audio::expansion_channel;
+pub use self::discrete::{AxROM, CNROM, ColorDreams, GxROM, UxROM};
+pub use self::fme7::FME7;
+pub use self::mmc1::MMC1;
+pub use self::mmc2::MMC2;
+pub use self::mmc3::{MMC3, MMC3Revision};
+pub use self::mmc5::MMC5;
+pub use self::namco163::Namco163;
+pub use self::nrom::NROM;
+pub use self::vrc4::VRC4;
+pub use self::vrc6::VRC6;
+pub use self::vrc7::VRC7;
+
+/// Cartridge hardware as seen from the console. Addresses $0000-$1FFF are
+/// the PPU pattern tables, $4020-$FFFF is CPU address space.
+pub trait Mapper {
+    /// Read without side effects. Also used for debug output.
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    /// CPU read. Boards with registers that change state when they are read
+    /// override this and keep `read` free of side effects.
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    /// Called when the CPU writes a PPU register ($2000-$2007). The
+    /// cartridge sits on the CPU bus and some boards watch these writes.
+    fn ppu_register_write(&mut self, _address: u16, _value: u8) {}
+
+    /// Current name table mirroring. Some boards switch it at runtime.
+    fn mirror_mode(&self) -> Mirror;
+
+    /// Called once per CPU cycle.
+    fn step(&mut self) {}
+
+    /// Called after every PPU memory access with the address the PPU put on
+    /// its address bus, so boards can watch the PPU's fetches.
+    fn ppu_address(&mut self, _address: u16) {}
+
+    /// Name table read ($2000-$2FFF). Boards that map their own memory into
+    /// the name tables return its contents, `None` reads the console VRAM
+    /// selected by `mirror_mode`.
+    fn read_name_table(&mut self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    /// Name table write. Returns true if the board handled the write.
+    fn write_name_table(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    /// True while the board pulls the CPU's /IRQ line low.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Names of the board's expansion audio channels, like `vrc6.saw`.
+    fn audio_channels(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Fill in the current level of each expansion audio channel, in the
+    /// order of `audio_channels` and in units of a 2A03 pulse volume step.
+    /// Sampled by the APU every CPU cycle.
+    fn audio_levels(&self, _levels: &mut [f32]) {}
+}
+
+pub fn new_mapper(cartridge: Cartridge) -> Result<Box<dyn Mapper>, io::Error> {
+    match cartridge.mapper_type {
+        0 => Ok(Box::new(NROM::new(cartridge))),
+        1 => Ok(Box::new(MMC1::new(cartridge))),
+        2 => Ok(Box::new(UxROM::new(cartridge))),
+        3 => Ok(Box::new(CNROM::new(cartridge))),
+        4 => {
+            // NES 2.0 submapper 4 marks boards with the older MMC3A.
+            let revision = if cartridge.submapper == 4 {
+                MMC3Revision::MMC3A
+            } else {
+                MMC3Revision::MMC3B
+            };
+            Ok(Box::new(MMC3::new(cartridge, revision)))
+        }
+        5 => Ok(Box::new(MMC5::new(cartridge))),
+        7 => Ok(Box::new(AxROM::new(cartridge))),
+        9 => Ok(Box::new(MMC2::new(cartridge, false))),
+        10 => Ok(Box::new(MMC2::new(cartridge, true))),
+        11 => Ok(Box::new(ColorDreams::new(cartridge))),
+        19 => Ok(Box::new(Namco163::new(cartridge))),
+        21 | 22 | 23 | 25 => Ok(Box::new(VRC4::new(cartridge))),
+        24 | 26 => Ok(Box::new(VRC6::new(cartridge))),
+        66 => Ok(Box::new(GxROM::new(cartridge))),
+        69 => Ok(Box::new(FME7::new(cartridge))),
+        85 => Ok(Box::new(VRC7::new(cartridge))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported mapper type {}", cartridge.mapper_type),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge::test_cartridge;
+
+    #[test]
+    fn it_applies_bus_conflicts() {
+        let mut cartridge = test_cartridge(2, 8, 0);
+        cartridge.submapper = 2;
+        let mut mapper = new_mapper(cartridge).unwrap();
+        // $8000 holds bank 0, which masks out the bank number.
+        mapper.write(0x8000, 0x03);
+        assert_eq!(mapper.read(0x8000), 0);
+        // $C000 holds the last bank (7), which lets it through.
+        mapper.write(0xC000, 0x03);
+        assert_eq!(mapper.read(0x8000), 3);
+    }
+
+    /// Load an MMC1 register with five serial writes, a few cycles apart.
+    fn load_mmc1(mapper: &mut Box<dyn Mapper>, address: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.step();
+            mapper.step();
+            mapper.write(address, value >> bit & 1);
+        }
+    }
+
+    #[test]
+    fn it_loads_mmc1_registers_serially() {
+        let mut mapper = new_mapper(test_cartridge(1, 8, 1)).unwrap();
+        // Starts out with the last bank fixed at $C000.
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (0, 7));
+        load_mmc1(&mut mapper, 0xE000, 2);
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (2, 7));
+
+        // Bit 7 throws away the bits shifted in so far.
+        for _ in 0..3 {
+            mapper.step();
+            mapper.step();
+            mapper.write(0xE000, 1);
+        }
+        mapper.step();
+        mapper.step();
+        mapper.write(0xE000, 0x80);
+        load_mmc1(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.read(0x8000), 5);
+
+        // A write on the cycle after another write is ignored, like the
+        // second write of a read-modify-write instruction.
+        mapper.step();
+        mapper.step();
+        mapper.write(0xE000, 0x80);
+        mapper.step();
+        mapper.write(0xE000, 0x01);
+        load_mmc1(&mut mapper, 0xE000, 3);
+        assert_eq!(mapper.read(0x8000), 3);
+        mapper.step();
+        mapper.step();
+        mapper.write(0xE000, 0x80);
+        mapper.write(0xE000, 0x01);
+        load_mmc1(&mut mapper, 0xE000, 6);
+        assert_eq!(mapper.read(0x8000), 6);
+    }
+
+    #[test]
+    fn it_switches_mmc1_banks() {
+        let mut cartridge = test_cartridge(1, 8, 4);
+        for (i, byte) in cartridge.chr.iter_mut().enumerate() {
+            *byte = (i / 0x1000) as u8;
+        }
+        let mut mapper = new_mapper(cartridge).unwrap();
+        load_mmc1(&mut mapper, 0xE000, 3);
+
+        // 16 KiB at $8000 with the last bank fixed, 16 KiB at $C000 with
+        // the first bank fixed, or 32 KiB ignoring the low bit.
+        load_mmc1(&mut mapper, 0x8000, 0x0C);
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (3, 7));
+        load_mmc1(&mut mapper, 0x8000, 0x08);
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (0, 3));
+        load_mmc1(&mut mapper, 0x8000, 0x00);
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (2, 3));
+
+        // 8 KiB of CHR ignoring the low bit, or two 4 KiB banks.
+        load_mmc1(&mut mapper, 0xA000, 5);
+        load_mmc1(&mut mapper, 0xC000, 1);
+        assert_eq!((mapper.read(0x0000), mapper.read(0x1000)), (4, 5));
+        load_mmc1(&mut mapper, 0x8000, 0x10);
+        assert_eq!((mapper.read(0x0000), mapper.read(0x1000)), (5, 1));
+    }
+
+    #[test]
+    fn it_selects_mmc1_outer_banks() {
+        // SUROM: CHR bank bit 4 selects the 256 KiB half of PRG ROM.
+        let mut mapper = new_mapper(test_cartridge(1, 32, 0)).unwrap();
+        load_mmc1(&mut mapper, 0xE000, 1);
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (1, 15));
+        load_mmc1(&mut mapper, 0xA000, 0x10);
+        assert_eq!((mapper.read(0x8000), mapper.read(0xC000)), (17, 31));
+
+        // SOROM: bit 3 selects one of two 8 KiB PRG RAM banks.
+        let mut cartridge = test_cartridge(1, 8, 0);
+        cartridge.sram = vec![0; 0x4000];
+        let mut mapper = new_mapper(cartridge).unwrap();
+        mapper.write(0x6000, 0xAA);
+        load_mmc1(&mut mapper, 0xA000, 0x08);
+        assert_eq!(mapper.read(0x6000), 0x00);
+        mapper.write(0x6000, 0xBB);
+        load_mmc1(&mut mapper, 0xA000, 0x00);
+        assert_eq!(mapper.read(0x6000), 0xAA);
+
+        // SXROM: bits 2-3 select one of four, with bit 4 still selecting
+        // the PRG ROM half.
+        let mut cartridge = test_cartridge(1, 32, 0);
+        cartridge.sram = vec![0; 0x8000];
+        let mut mapper = new_mapper(cartridge).unwrap();
+        load_mmc1(&mut mapper, 0xA000, 0x1C);
+        mapper.write(0x6000, 0xCC);
+        assert_eq!(mapper.read(0xC000), 31);
+        load_mmc1(&mut mapper, 0xA000, 0x18);
+        assert_eq!((mapper.read(0x6000), mapper.read(0xC000)), (0x00, 31));
+        load_mmc1(&mut mapper, 0xA000, 0x0C);
+        assert_eq!((mapper.read(0x6000), mapper.read(0xC000)), (0xCC, 15));
+    }
+
+    #[test]
+    fn it_switches_chr_banks_on_latch_tiles() {
+        let mut cartridge = test_cartridge(9, 8, 2);
+        // Mark each 4 KiB CHR bank with its bank number.
+        for (i, byte) in cartridge.chr.iter_mut().enumerate() {
+            *byte = (i / 0x1000) as u8;
+        }
+        let mut mapper = new_mapper(cartridge).unwrap();
+        mapper.write(0xB000, 1);
+        mapper.write(0xC000, 2);
+        // The latches start out selecting the $FE banks.
+        assert_eq!(mapper.read(0x0000), 2);
+        // The fetch that flips the latch still sees the old bank.
+        assert_eq!(mapper.read(0x0FD8), 2);
+        mapper.ppu_address(0x0FD8);
+        assert_eq!(mapper.read(0x0000), 1);
+        // MMC2 only watches the exact address for the first latch.
+        mapper.ppu_address(0x0FE9);
+        assert_eq!(mapper.read(0x0000), 1);
+        mapper.ppu_address(0x0FE8);
+        assert_eq!(mapper.read(0x0000), 2);
+    }
+
+    #[test]
+    fn it_counts_scan_lines_from_name_table_fetches() {
+        let mut mapper = new_mapper(test_cartridge(5, 8, 1)).unwrap();
+        mapper.write(0x5203, 2);
+        mapper.write(0x5204, 0x80);
+        // Each line ends with the same name table address read three times.
+        for line in 0..3 {
+            assert!(!mapper.irq());
+            for _ in 0..3 {
+                mapper.ppu_address(0x2000 + line);
+            }
+            mapper.ppu_address(0x0000);
+        }
+        assert!(mapper.irq());
+        assert_eq!(mapper.cpu_read(0x5204), 0xC0);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn it_counts_scan_lines_from_a12_rises() {
+        for &submapper in &[0, 4] {
+            let mut cartridge = test_cartridge(4, 8, 1);
+            cartridge.submapper = submapper;
+            let mut mapper = new_mapper(cartridge).unwrap();
+            // Background fetches from $0000, then sprite fetches from $1000
+            // after A12 has been low for long enough.
+            let scan_line = |mapper: &mut Box<dyn Mapper>| {
+                mapper.ppu_address(0x0000);
+                for _ in 0..4 {
+                    mapper.step();
+                }
+                mapper.ppu_address(0x1000);
+            };
+            mapper.write(0xC000, 2);
+            mapper.write(0xC001, 0);
+            mapper.write(0xE001, 0);
+            // The first line reloads the counter, the next two count down.
+            for _ in 0..2 {
+                scan_line(&mut mapper);
+                assert!(!mapper.irq());
+            }
+            scan_line(&mut mapper);
+            assert!(mapper.irq());
+            mapper.write(0xE000, 0);
+            mapper.write(0xE001, 0);
+            assert!(!mapper.irq());
+
+            // Both revisions fire when $C001 reloads a latch of 0, but only
+            // MMC3B keeps firing as the counter keeps reloading 0.
+            mapper.write(0xC000, 0);
+            mapper.write(0xC001, 0);
+            scan_line(&mut mapper);
+            assert!(mapper.irq());
+            mapper.write(0xE000, 0);
+            mapper.write(0xE001, 0);
+            scan_line(&mut mapper);
+            assert_eq!(mapper.irq(), submapper != 4);
+        }
+    }
+
+    #[test]
+    fn it_decodes_vrc4_register_lines() {
+        // VRC4c selects registers with A6 and A7.
+        let mut cartridge = test_cartridge(21, 8, 1);
+        cartridge.submapper = 2;
+        let mut mapper = new_mapper(cartridge).unwrap();
+        mapper.write(0xF000, 0x0E);
+        mapper.write(0xF040, 0x0F);
+        // Enable the counter in CPU cycle mode.
+        mapper.write(0xF080, 0x06);
+        mapper.step();
+        assert!(!mapper.irq());
+        mapper.step();
+        assert!(mapper.irq());
+        mapper.write(0xF0C0, 0);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn it_counts_cpu_cycles_on_the_fme7() {
+        let mut mapper = new_mapper(test_cartridge(69, 8, 1)).unwrap();
+        // Load 3 into the counter, then enable it and the IRQ.
+        mapper.write(0x8000, 0x0E);
+        mapper.write(0xA000, 3);
+        mapper.write(0x8000, 0x0F);
+        mapper.write(0xA000, 0);
+        mapper.write(0x8000, 0x0D);
+        mapper.write(0xA000, 0x81);
+        // The IRQ fires when the counter wraps from 0 to $FFFF.
+        for _ in 0..3 {
+            mapper.step();
+        }
+        assert!(!mapper.irq());
+        mapper.step();
+        assert!(mapper.irq());
+        // Any write to the IRQ control acknowledges it.
+        mapper.write(0xA000, 0x81);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn it_counts_cpu_cycles_on_the_n163() {
+        let mut mapper = new_mapper(test_cartridge(19, 8, 1)).unwrap();
+        // Load $7FFC into the counter and enable it.
+        mapper.write(0x5000, 0xFC);
+        mapper.write(0x5800, 0xFF);
+        // The IRQ fires when the counter counts up to $7FFF.
+        for _ in 0..2 {
+            mapper.step();
+        }
+        assert!(!mapper.irq());
+        mapper.step();
+        assert!(mapper.irq());
+        assert_eq!(mapper.read(0x5000), 0xFF);
+        // Writing either half of the counter acknowledges it.
+        mapper.write(0x5800, 0x80);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn it_maps_console_vram_into_the_pattern_tables() {
+        let mut mapper = new_mapper(test_cartridge(19, 8, 1)).unwrap();
+        // Pattern page 0 and name table 0 both select VRAM page 0.
+        mapper.write(0x8000, 0xE0);
+        mapper.write(0xC000, 0xE0);
+        mapper.write(0x0010, 0x42);
+        assert_eq!(mapper.read_name_table(0x2010), Some(0x42));
+        // $E800 bit 6 hands the low pattern table back to CHR ROM.
+        mapper.write(0xE800, 0x40);
+        assert_eq!(mapper.read(0x0010), 0);
+    }
+
+    #[test]
+    fn it_multiplies() {
+        let mut mapper = new_mapper(test_cartridge(5, 8, 1)).unwrap();
+        mapper.write(0x5205, 200);
+        mapper.write(0x5206, 100);
+        assert_eq!(mapper.read(0x5205), 0x20);
+        assert_eq!(mapper.read(0x5206), 0x4E);
+    }
+
+    #[test]
+    fn it_rejects_unsupported_mappers() {
+        assert!(new_mapper(test_cartridge(255, 2, 1)).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::audio::N163Audio;
+use mapper::Mapper;
+
+/// Where a 1 KiB pattern or name table page is read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Page {
+    Chr(usize),
+    Vram(usize),
+}
+
+/// Namco 129 and 163 (mapper 19).
+///
+/// Three switchable 8 KiB PRG banks, eight 1 KiB CHR banks and four name
+/// table banks. Bank numbers $E0-$FF select one of the two console VRAM
+/// pages instead of CHR ROM, so the pattern tables can be RAM and the name
+/// tables can be CHR ROM. The chip also has 128 bytes of internal RAM,
+/// shared with its sound channels on the 163, and a 15-bit IRQ counter that
+/// counts up every CPU cycle.
+///
+/// The board decides for every PPU access whether console VRAM is used, so
+/// it keeps the console's 2 KiB of VRAM itself.
+///
+/// See https://wiki.nesdev.com/w/index.php/INES_Mapper_019
+pub struct Namco163 {
+    pub cartridge: Cartridge,
+    pub vram: [u8; 2048],
+    pub audio: N163Audio,
+    pub ram_address: u8,
+    pub prg_banks: [u8; 3],
+    pub chr_banks: [u8; 8],
+    pub name_table_banks: [u8; 4],
+    pub vram_chr_disabled: [bool; 2],
+    pub write_protect: u8,
+
+    pub irq_counter: u16,
+    pub irq_enabled: bool,
+    pub irq_pending: bool,
+}
+
+impl Namco163 {
+    pub fn new(cartridge: Cartridge) -> Namco163 {
+        Namco163 {
+            cartridge,
+            vram: [0; 2048],
+            audio: N163Audio::new(),
+            ram_address: 0,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            name_table_banks: [0xE0, 0xE1, 0xE0, 0xE1],
+            vram_chr_disabled: [false; 2],
+            write_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn page(&self, bank: u8, vram_allowed: bool, address: u16) -> Page {
+        let offset = (address % 0x0400) as usize;
+        if bank >= 0xE0 && vram_allowed {
+            Page::Vram((bank & 1) as usize * 0x0400 + offset)
+        } else {
+            let len = self.cartridge.chr.len();
+            Page::Chr((bank as usize * 0x0400) % len + offset)
+        }
+    }
+
+    fn chr_page(&self, address: u16) -> Page {
+        let bank = self.chr_banks[(address / 0x0400) as usize];
+        let vram_allowed = !self.vram_chr_disabled[(address / 0x1000) as usize];
+        self.page(bank, vram_allowed, address)
+    }
+
+    fn name_table_page(&self, address: u16) -> Page {
+        let bank = self.name_table_banks[((address - 0x2000) / 0x0400) as usize];
+        self.page(bank, true, address)
+    }
+
+    fn read_page(&self, page: Page) -> u8 {
+        match page {
+            Page::Chr(index) => self.cartridge.chr[index],
+            Page::Vram(index) => self.vram[index],
+        }
+    }
+
+    fn write_page(&mut self, page: Page, value: u8) {
+        match page {
+            Page::Chr(index) => {
+                if self.cartridge.chr_ram {
+                    self.cartridge.chr[index] = value;
+                }
+            }
+            Page::Vram(index) => self.vram[index] = value,
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let banks = self.cartridge.prg.len() / 0x2000;
+        let window = ((address - 0x8000) / 0x2000) as usize;
+        let bank = if window < 3 {
+            (self.prg_banks[window] & 0x3F) as usize % banks
+        } else {
+            banks - 1
+        };
+        bank * 0x2000 + (address % 0x2000) as usize
+    }
+
+    /// Bit 7 of the internal RAM address enables auto-increment after
+    /// each access through $4800.
+    fn increment_ram_address(&mut self) {
+        if self.ram_address & 0x80 == 0x80 {
+            self.ram_address = 0x80 | (self.ram_address.wrapping_add(1) & 0x7F);
+        }
+    }
+
+    /// PRG RAM is split into four 2 KiB pages that are each writable when
+    /// $F800 holds $4x with the page's bit clear.
+    fn prg_ram_writable(&self, address: u16) -> bool {
+        let page = (address - 0x6000) / 0x0800;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << page) == 0
+    }
+}
+
+impl Mapper for Namco163 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.read_page(self.chr_page(address)),
+            0x4800..=0x4FFF => self.audio.ram[(self.ram_address & 0x7F) as usize],
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_enabled as u8) << 7 | (self.irq_counter >> 8) as u8,
+            0x6000..=0x7FFF => self.cartridge.sram[(address - 0x6000) as usize],
+            0x8000..=0xFFFF => self.cartridge.prg[self.prg_offset(address)],
+            _ => 0xFF,
+        }
+    }
+
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        let value = self.read(address);
+        if let 0x4800..=0x4FFF = address {
+            self.increment_ram_address();
+        }
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let page = self.chr_page(address);
+                self.write_page(page, value);
+            }
+            0x4800..=0x4FFF => {
+                self.audio.ram[(self.ram_address & 0x7F) as usize] = value;
+                self.increment_ram_address();
+            }
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((value & 0x7F) as u16) << 8;
+                self.irq_enabled = value & 0x80 == 0x80;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(address) => {
+                self.cartridge.sram[(address - 0x6000) as usize] = value;
+            }
+            0x8000..=0xBFFF => self.chr_banks[((address - 0x8000) / 0x0800) as usize] = value,
+            0xC000..=0xDFFF => {
+                self.name_table_banks[((address - 0xC000) / 0x0800) as usize] = value;
+            }
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = value;
+                self.audio.disabled = value & 0x40 == 0x40;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = value;
+                self.vram_chr_disabled = [value & 0x40 == 0x40, value & 0x80 == 0x80];
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = value,
+            0xF800..=0xFFFF => {
+                // The same register sets the internal RAM address.
+                self.write_protect = value;
+                self.ram_address = value;
+            }
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.cartridge.mirror_mode
+    }
+
+    fn step(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.step();
+    }
+
+    fn read_name_table(&mut self, address: u16) -> Option<u8> {
+        Some(self.read_page(self.name_table_page(address)))
+    }
+
+    fn write_name_table(&mut self, address: u16, value: u8) -> bool {
+        let page = self.name_table_page(address);
+        self.write_page(page, value);
+        true
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_channels(&self) -> &'static [&'static str] {
+        self.audio.channel_names()
+    }
+
+    fn audio_levels(&self, levels: &mut [f32]) {
+        self.audio.channel_levels(levels)
+    }
+}
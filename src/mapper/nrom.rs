@@ -0,0 +1,42 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::Mapper;
+
+/// NROM (mapper 0): 16 or 32 KiB of PRG ROM and 8 KiB of CHR, no bank
+/// switching. A 16 KiB PRG ROM is mirrored at $8000 and $C000.
+pub struct NROM {
+    pub cartridge: Cartridge,
+}
+
+impl NROM {
+    pub fn new(cartridge: Cartridge) -> NROM {
+        NROM { cartridge }
+    }
+}
+
+impl Mapper for NROM {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.cartridge.chr[address as usize],
+            0x6000..=0x7FFF => self.cartridge.sram[(address - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let index = (address - 0x8000) as usize % self.cartridge.prg.len();
+                self.cartridge.prg[index]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                self.cartridge.chr[address as usize] = value;
+            }
+            0x6000..=0x7FFF => self.cartridge.sram[(address - 0x6000) as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.cartridge.mirror_mode
+    }
+}
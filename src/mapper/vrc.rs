@@ -0,0 +1,72 @@
+/// The IRQ counter shared by VRC4, VRC6 and VRC7.
+///
+/// An 8-bit counter counts up from the latch and raises an IRQ when it
+/// overflows. In cycle mode it is clocked every CPU cycle. In scan line mode
+/// a prescaler divides the CPU clock by 113.667 (341 PPU dots), so the IRQ
+/// works even with rendering disabled.
+///
+/// See https://wiki.nesdev.com/w/index.php/VRC_IRQ
+pub struct VRCIrq {
+    pub latch: u8,
+    pub counter: u8,
+    pub prescaler: i16,
+    pub enabled: bool,
+    pub enable_after_ack: bool,
+    pub cycle_mode: bool,
+    pub pending: bool,
+}
+
+impl VRCIrq {
+    pub fn new() -> VRCIrq {
+        VRCIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0x01 == 0x01;
+        self.enabled = value & 0x02 == 0x02;
+        self.cycle_mode = value & 0x04 == 0x04;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    fn clock(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    /// Called once per CPU cycle.
+    pub fn step(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.clock();
+            }
+        }
+    }
+}
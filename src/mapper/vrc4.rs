@@ -0,0 +1,170 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::vrc::VRCIrq;
+use mapper::Mapper;
+
+/// Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25).
+///
+/// Two switchable 8 KiB PRG banks, eight 1 KiB CHR banks written a nibble
+/// at a time, and on VRC4 a PRG swap mode and the VRC IRQ counter. Each of
+/// the four mappers covers boards that connect the chip's two register
+/// select lines to different CPU address lines. NES 2.0 submappers tell
+/// them apart; without one both wirings are decoded at once, which works
+/// because no game writes to the other variant's addresses.
+///
+/// | Mapper | Submapper 1   | Submapper 2   | Submapper 3    |
+/// |--------|---------------|---------------|----------------|
+/// | 21     | VRC4a (A1 A2) | VRC4c (A6 A7) |                |
+/// | 22     | VRC2a (A1 A0) |               |                |
+/// | 23     | VRC4f (A0 A1) | VRC4e (A2 A3) | VRC2b (A0 A1)  |
+/// | 25     | VRC4b (A1 A0) | VRC4d (A3 A2) | VRC2c (A1 A0)  |
+///
+/// See https://wiki.nesdev.com/w/index.php/VRC2_and_VRC4
+pub struct VRC4 {
+    pub cartridge: Cartridge,
+    pub vrc2: bool,
+    pub a0_mask: u16,
+    pub a1_mask: u16,
+    pub chr_shift: u8,
+    pub prg_banks: [u8; 2],
+    pub prg_swap: bool,
+    pub chr_banks: [u16; 8],
+    pub mirror: Mirror,
+    pub irq: VRCIrq,
+}
+
+impl VRC4 {
+    pub fn new(cartridge: Cartridge) -> VRC4 {
+        let (vrc2, a0_mask, a1_mask) = match (cartridge.mapper_type, cartridge.submapper) {
+            (21, 1) => (false, 0x02, 0x04),
+            (21, 2) => (false, 0x40, 0x80),
+            (21, _) => (false, 0x42, 0x84),
+            (22, _) => (true, 0x02, 0x01),
+            (23, 1) => (false, 0x01, 0x02),
+            (23, 2) => (false, 0x04, 0x08),
+            (23, 3) => (true, 0x01, 0x02),
+            (23, _) => (false, 0x05, 0x0A),
+            (25, 1) => (false, 0x02, 0x01),
+            (25, 2) => (false, 0x08, 0x04),
+            (25, 3) => (true, 0x02, 0x01),
+            (_, _) => (false, 0x0A, 0x05),
+        };
+        // VRC2a ignores the lowest CHR bank bit.
+        let chr_shift = if cartridge.mapper_type == 22 { 1 } else { 0 };
+        VRC4 {
+            cartridge,
+            vrc2,
+            a0_mask,
+            a1_mask,
+            chr_shift,
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirror: Mirror::Vertical,
+            irq: VRCIrq::new(),
+        }
+    }
+
+    /// Register address with the board's wiring undone: $x000-$x003.
+    fn register(&self, address: u16) -> u16 {
+        let a0 = (address & self.a0_mask != 0) as u16;
+        let a1 = (address & self.a1_mask != 0) as u16;
+        (address & 0xF000) | a1 << 1 | a0
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let banks = self.cartridge.prg.len() / 0x2000;
+        let second_last = banks - 2;
+        let bank = match (address, self.prg_swap) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => {
+                self.prg_banks[0] as usize % banks
+            }
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => second_last,
+            (0xA000..=0xBFFF, _) => self.prg_banks[1] as usize % banks,
+            _ => banks - 1,
+        };
+        bank * 0x2000 + (address % 0x2000) as usize
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = (self.chr_banks[(address / 0x0400) as usize] >> self.chr_shift) as usize;
+        (bank * 0x0400) % self.cartridge.chr.len() + (address % 0x0400) as usize
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match self.register(address) {
+            0x8000..=0x8003 => self.prg_banks[0] = value & 0x1F,
+            0x9000..=0x9001 if !self.vrc2 => {
+                self.mirror = match value & 3 {
+                    0 => Mirror::Vertical,
+                    1 => Mirror::Horizontal,
+                    2 => Mirror::SingleScreenA,
+                    _ => Mirror::SingleScreenB,
+                };
+            }
+            0x9002..=0x9003 if !self.vrc2 => self.prg_swap = value & 0x02 == 0x02,
+            0x9000..=0x9003 => {
+                self.mirror = if value & 1 == 0 {
+                    Mirror::Vertical
+                } else {
+                    Mirror::Horizontal
+                };
+            }
+            0xA000..=0xA003 => self.prg_banks[1] = value & 0x1F,
+            register @ 0xB000..=0xE003 => {
+                // Two registers per bank, low nibble first.
+                let index = ((register - 0xB000) >> 12) * 2 + (register & 3) / 2;
+                let high_mask = if self.vrc2 { 0x0F } else { 0x1F };
+                let bank = &mut self.chr_banks[index as usize];
+                if register & 1 == 0 {
+                    *bank = (*bank & 0x1F0) | (value & 0x0F) as u16;
+                } else {
+                    *bank = (*bank & 0x0F) | ((value & high_mask) as u16) << 4;
+                }
+            }
+            0xF000 if !self.vrc2 => {
+                self.irq.latch = (self.irq.latch & 0xF0) | (value & 0x0F);
+            }
+            0xF001 if !self.vrc2 => {
+                self.irq.latch = (self.irq.latch & 0x0F) | (value & 0x0F) << 4;
+            }
+            0xF002 if !self.vrc2 => self.irq.write_control(value),
+            0xF003 if !self.vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for VRC4 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.cartridge.chr[self.chr_offset(address)],
+            0x6000..=0x7FFF => self.cartridge.sram[(address - 0x6000) as usize],
+            0x8000..=0xFFFF => self.cartridge.prg[self.prg_offset(address)],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let index = self.chr_offset(address);
+                self.cartridge.chr[index] = value;
+            }
+            0x6000..=0x7FFF => self.cartridge.sram[(address - 0x6000) as usize] = value,
+            0x8000..=0xFFFF => self.write_register(address, value),
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.mirror
+    }
+
+    fn step(&mut self) {
+        self.irq.step();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending
+    }
+}
@@ -0,0 +1,160 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::audio::VRC6Audio;
+use mapper::vrc::VRCIrq;
+use mapper::Mapper;
+
+/// Konami VRC6 (mappers 24 and 26).
+///
+/// A switchable 16 KiB PRG bank at $8000, an 8 KiB bank at $C000, eight CHR
+/// bank registers used in one of four layouts, the VRC IRQ counter and
+/// three extra sound channels.
+/// VRC6b (mapper 26) swaps the register select lines A0 and A1.
+///
+/// Name tables always come from console VRAM, using the mirroring
+/// in $B003. No game is known to map CHR ROM into the name tables.
+///
+/// See https://wiki.nesdev.com/w/index.php/VRC6
+pub struct VRC6 {
+    pub cartridge: Cartridge,
+    pub swap_lines: bool,
+    pub prg_banks: [u8; 2],
+    pub chr_banks: [u8; 8],
+    pub banking_mode: u8,
+    pub prg_ram_enabled: bool,
+    pub irq: VRCIrq,
+    pub audio: VRC6Audio,
+}
+
+impl VRC6 {
+    pub fn new(cartridge: Cartridge) -> VRC6 {
+        let swap_lines = cartridge.mapper_type == 26;
+        VRC6 {
+            cartridge,
+            swap_lines,
+            prg_banks: [0; 2],
+            chr_banks: [0; 8],
+            banking_mode: 0x20,
+            prg_ram_enabled: false,
+            irq: VRCIrq::new(),
+            audio: VRC6Audio::new(),
+        }
+    }
+
+    /// Register address with the board's wiring undone: $x000-$x003.
+    fn register(&self, address: u16) -> u16 {
+        let lines = address & 3;
+        let lines = if self.swap_lines {
+            (lines & 1) << 1 | (lines & 2) >> 1
+        } else {
+            lines
+        };
+        (address & 0xF000) | lines
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let len = self.cartridge.prg.len();
+        match address {
+            0x8000..=0xBFFF => {
+                let bank = (self.prg_banks[0] & 0x0F) as usize;
+                (bank * 0x4000) % len + (address - 0x8000) as usize
+            }
+            0xC000..=0xDFFF => {
+                let bank = (self.prg_banks[1] & 0x1F) as usize;
+                (bank * 0x2000) % len + (address - 0xC000) as usize
+            }
+            _ => len - 0x2000 + (address - 0xE000) as usize,
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let page = (address / 0x0400) as usize;
+        // Banking modes 1-3 use 2 KiB banks for some of the pattern table.
+        // They take CHR A10 from the PPU when bit 5 of $B003 is set.
+        let (register, two_kib) = match (self.banking_mode & 3, page) {
+            (0, _) => (page, false),
+            (1, _) => (page / 2, true),
+            (_, 0..=3) => (page, false),
+            (_, _) => (4 + (page - 4) / 2, true),
+        };
+        let bank = self.chr_banks[register] as usize;
+        let offset = if two_kib && self.banking_mode & 0x20 == 0x20 {
+            bank * 0x0800 + (address % 0x0800) as usize
+        } else {
+            bank * 0x0400 + (address % 0x0400) as usize
+        };
+        offset % self.cartridge.chr.len()
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match self.register(address) {
+            0x8000..=0x8003 => self.prg_banks[0] = value,
+            0xB003 => {
+                self.banking_mode = value;
+                self.prg_ram_enabled = value & 0x80 == 0x80;
+            }
+            0xC000..=0xC003 => self.prg_banks[1] = value,
+            register @ 0xD000..=0xE003 => {
+                let index = ((register - 0xD000) >> 12) * 4 + (register & 3);
+                self.chr_banks[index as usize] = value;
+            }
+            0xF000 => self.irq.latch = value,
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            register @ 0x9000..=0xB002 => self.audio.write(register, value),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for VRC6 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.cartridge.chr[self.chr_offset(address)],
+            0x6000..=0x7FFF if self.prg_ram_enabled => {
+                self.cartridge.sram[(address - 0x6000) as usize]
+            }
+            0x8000..=0xFFFF => self.cartridge.prg[self.prg_offset(address)],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let index = self.chr_offset(address);
+                self.cartridge.chr[index] = value;
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled => {
+                self.cartridge.sram[(address - 0x6000) as usize] = value;
+            }
+            0x8000..=0xFFFF => self.write_register(address, value),
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        match (self.banking_mode >> 2) & 3 {
+            0 => Mirror::Vertical,
+            1 => Mirror::Horizontal,
+            2 => Mirror::SingleScreenA,
+            _ => Mirror::SingleScreenB,
+        }
+    }
+
+    fn step(&mut self) {
+        self.irq.step();
+        self.audio.step();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn audio_channels(&self) -> &'static [&'static str] {
+        self.audio.channel_names()
+    }
+
+    fn audio_levels(&self, levels: &mut [f32]) {
+        self.audio.channel_levels(levels)
+    }
+}
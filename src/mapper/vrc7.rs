@@ -0,0 +1,148 @@
+use cartridge::{Cartridge, Mirror};
+use mapper::audio::VRC7Audio;
+use mapper::vrc::VRCIrq;
+use mapper::Mapper;
+
+/// Konami VRC7 (mapper 85).
+///
+/// Three switchable 8 KiB PRG banks, eight 1 KiB CHR banks, the VRC IRQ
+/// counter and a six channel FM sound chip. Each register page has two
+/// registers, told apart by A4 on VRC7a (submapper 2) and A3 on VRC7b
+/// (submapper 1).
+///
+/// See https://wiki.nesdev.com/w/index.php/VRC7
+pub struct VRC7 {
+    pub cartridge: Cartridge,
+    pub select_mask: u16,
+    pub prg_banks: [u8; 3],
+    pub chr_banks: [u8; 8],
+    pub mirror: Mirror,
+    pub prg_ram_enabled: bool,
+    pub irq: VRCIrq,
+    pub audio: VRC7Audio,
+}
+
+impl VRC7 {
+    pub fn new(cartridge: Cartridge) -> VRC7 {
+        let select_mask = match cartridge.submapper {
+            1 => 0x08,
+            2 => 0x10,
+            _ => 0x18,
+        };
+        VRC7 {
+            cartridge,
+            select_mask,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            mirror: Mirror::Vertical,
+            prg_ram_enabled: false,
+            irq: VRCIrq::new(),
+            audio: VRC7Audio::new(),
+        }
+    }
+
+    /// Register address with the board's wiring undone: $x000 or $x010.
+    fn register(&self, address: u16) -> u16 {
+        if address & self.select_mask != 0 {
+            (address & 0xF000) | 0x10
+        } else {
+            address & 0xF000
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let banks = self.cartridge.prg.len() / 0x2000;
+        let window = ((address - 0x8000) / 0x2000) as usize;
+        let bank = if window < 3 {
+            self.prg_banks[window] as usize % banks
+        } else {
+            banks - 1
+        };
+        bank * 0x2000 + (address % 0x2000) as usize
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.chr_banks[(address / 0x0400) as usize] as usize;
+        (bank * 0x0400) % self.cartridge.chr.len() + (address % 0x0400) as usize
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        // The sound chip decodes A4 and A5 itself on both board variants.
+        match address & 0xF030 {
+            0x9010 => return self.audio.select(value),
+            0x9030 => return self.audio.write(value),
+            _ => {}
+        }
+        match self.register(address) {
+            0x8000 => self.prg_banks[0] = value & 0x3F,
+            0x8010 => self.prg_banks[1] = value & 0x3F,
+            0x9000 => self.prg_banks[2] = value & 0x3F,
+            register @ 0xA000..=0xD010 => {
+                let index = ((register - 0xA000) >> 12) * 2 + (register & 0x10) / 0x10;
+                self.chr_banks[index as usize] = value;
+            }
+            0xE000 => {
+                self.mirror = match value & 3 {
+                    0 => Mirror::Vertical,
+                    1 => Mirror::Horizontal,
+                    2 => Mirror::SingleScreenA,
+                    _ => Mirror::SingleScreenB,
+                };
+                self.audio.set_silenced(value & 0x40 == 0x40);
+                self.prg_ram_enabled = value & 0x80 == 0x80;
+            }
+            0xE010 => self.irq.latch = value,
+            0xF000 => self.irq.write_control(value),
+            0xF010 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for VRC7 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.cartridge.chr[self.chr_offset(address)],
+            0x6000..=0x7FFF if self.prg_ram_enabled => {
+                self.cartridge.sram[(address - 0x6000) as usize]
+            }
+            0x8000..=0xFFFF => self.cartridge.prg[self.prg_offset(address)],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF if self.cartridge.chr_ram => {
+                let index = self.chr_offset(address);
+                self.cartridge.chr[index] = value;
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled => {
+                self.cartridge.sram[(address - 0x6000) as usize] = value;
+            }
+            0x8000..=0xFFFF => self.write_register(address, value),
+            _ => {}
+        }
+    }
+
+    fn mirror_mode(&self) -> Mirror {
+        self.mirror
+    }
+
+    fn step(&mut self) {
+        self.irq.step();
+        self.audio.step();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn audio_channels(&self) -> &'static [&'static str] {
+        self.audio.channel_names()
+    }
+
+    fn audio_levels(&self, levels: &mut [f32]) {
+        self.audio.channel_levels(levels)
+    }
+}
@@ -1,4 +1,4 @@
-use bus::Bus;
+use bus::{palette_index, Bus};
 
 const PALETTE: [u32; 64] = [
     0x666666, 0x002A88, 0x1412A7, 0x3B00A4, 0x5C007E, 0x6E0040, 0x6C0600, 0x561D00, 0x333500,
@@ -11,6 +11,46 @@ const PALETTE: [u32; 64] = [
     0x000000,
 ];
 
+bitflags! {
+    /// PPUCTRL ($2000)
+    #[derive(Default)]
+    pub struct Control: u8 {
+        const NAME_TABLE_X     = 1 << 0;
+        const NAME_TABLE_Y     = 1 << 1;
+        const INCREMENT_32     = 1 << 2;
+        const SPRITE_TABLE     = 1 << 3;
+        const BACKGROUND_TABLE = 1 << 4;
+        const SPRITE_SIZE_16   = 1 << 5;
+        const MASTER_SLAVE     = 1 << 6;
+        const NMI_ENABLE       = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// PPUMASK ($2001)
+    #[derive(Default)]
+    pub struct Mask: u8 {
+        const GRAYSCALE            = 1 << 0;
+        const SHOW_LEFT_BACKGROUND = 1 << 1;
+        const SHOW_LEFT_SPRITES    = 1 << 2;
+        const SHOW_BACKGROUND      = 1 << 3;
+        const SHOW_SPRITES         = 1 << 4;
+        const RED_TINT             = 1 << 5;
+        const GREEN_TINT           = 1 << 6;
+        const BLUE_TINT            = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// PPUSTATUS ($2002). The low five bits are open bus.
+    #[derive(Default)]
+    pub struct Status: u8 {
+        const SPRITE_OVERFLOW = 1 << 5;
+        const SPRITE_ZERO_HIT = 1 << 6;
+        const VERTICAL_BLANK  = 1 << 7;
+    }
+}
+
 pub struct PPU {
     // Cycle Counters
     pub cycle: u32,
@@ -20,89 +60,346 @@ pub struct PPU {
     // Registers
     pub even_odd: bool,
 
+    // Background temporary variables
+    pub name_table_byte: u8,
+    pub attribute_table_byte: u8,
+    pub low_tile_byte: u8,
+    pub high_tile_byte: u8,
+    pub tile_data: u64,
+
     // Sprite variables
     pub sprite_count: u32,
     pub sprite_patterns: [u32; 8],
     pub sprite_positions: [u8; 8],
-
-    // PPUMASK ($2001) Flags
-    pub grayscale_flag: u8,
-    pub show_left_background_flag: u8,
-    pub show_left_sprites_flag: u8,
-    pub show_background_flag: u8,
-    pub show_sprites_flag: u8,
-    pub red_tint_flag: u8,
-    pub green_tint_flag: u8,
-    pub blue_tint_flag: u8,
-
-    pub tile_data: u64,
-    pub palette_data: [u8; 32],
+    pub sprite_priorities: [u8; 8],
+    pub sprite_indexes: [u8; 8],
 }
 
 impl PPU {
     pub fn new() -> PPU {
         PPU {
-            cycle: 0,
-            scan_line: 0,
+            cycle: 340,
+            scan_line: 240,
             frame: 0,
             even_odd: false,
+
+            name_table_byte: 0,
+            attribute_table_byte: 0,
+            low_tile_byte: 0,
+            high_tile_byte: 0,
+            tile_data: 0,
+
             sprite_count: 0,
             sprite_patterns: [0; 8],
             sprite_positions: [0; 8],
+            sprite_priorities: [0; 8],
+            sprite_indexes: [0; 8],
+        }
+    }
 
-            grayscale_flag: 0,
-            show_left_background_flag: 0,
-            show_left_sprites_flag: 0,
-            show_background_flag: 0,
-            show_sprites_flag: 0,
-            red_tint_flag: 0,
-            green_tint_flag: 0,
-            blue_tint_flag: 0,
+    pub fn rendering_enabled(&self, bus: &Bus) -> bool {
+        bus.ppu_mask
+            .intersects(Mask::SHOW_BACKGROUND | Mask::SHOW_SPRITES)
+    }
 
-            tile_data: 0,
-            palette_data: [0; 32],
+    //// Scrolling ////
+    // See https://wiki.nesdev.com/w/index.php/PPU_scrolling
+
+    /// Increment coarse X, switching horizontal name table on wrap-around.
+    pub fn increment_x(&self, bus: &mut Bus) {
+        if bus.ppu_v & 0x001F == 31 {
+            bus.ppu_v &= 0xFFE0;
+            bus.ppu_v ^= 0x0400;
+        } else {
+            bus.ppu_v += 1;
+        }
+    }
+
+    /// Increment fine Y, carrying over into coarse Y. Coarse Y wraps at row
+    /// 29 into the vertical name table; rows 30 and 31 wrap without switching.
+    pub fn increment_y(&self, bus: &mut Bus) {
+        if bus.ppu_v & 0x7000 != 0x7000 {
+            bus.ppu_v += 0x1000;
+        } else {
+            bus.ppu_v &= 0x8FFF;
+            let mut y = (bus.ppu_v & 0x03E0) >> 5;
+            if y == 29 {
+                y = 0;
+                bus.ppu_v ^= 0x0800;
+            } else if y == 31 {
+                y = 0;
+            } else {
+                y += 1;
+            }
+            bus.ppu_v = (bus.ppu_v & 0xFC1F) | (y << 5);
+        }
+    }
+
+    /// v: ....F.. ...EDCBA = t: ....F.. ...EDCBA
+    pub fn copy_x(&self, bus: &mut Bus) {
+        bus.ppu_v = (bus.ppu_v & 0xFBE0) | (bus.ppu_t & 0x041F);
+    }
+
+    /// v: IHGF.ED CBA..... = t: IHGF.ED CBA.....
+    pub fn copy_y(&self, bus: &mut Bus) {
+        bus.ppu_v = (bus.ppu_v & 0x841F) | (bus.ppu_t & 0x7BE0);
+    }
+
+    //// Background fetches ////
+
+    pub fn fetch_name_table_byte(&mut self, bus: &mut Bus) {
+        let address = 0x2000 | (bus.ppu_v & 0x0FFF);
+        self.name_table_byte = bus.ppu_read(address);
+    }
+
+    pub fn fetch_attribute_table_byte(&mut self, bus: &mut Bus) {
+        let v = bus.ppu_v;
+        let address = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+        let shift = ((v >> 4) & 4) | (v & 2);
+        self.attribute_table_byte = ((bus.ppu_read(address) >> shift) & 3) << 2;
+    }
+
+    pub fn background_pattern_address(&self, bus: &Bus) -> u16 {
+        let fine_y = (bus.ppu_v >> 12) & 7;
+        let table = if bus.ppu_ctrl.contains(Control::BACKGROUND_TABLE) {
+            0x1000
+        } else {
+            0
+        };
+        table + self.name_table_byte as u16 * 16 + fine_y
+    }
+
+    pub fn fetch_low_tile_byte(&mut self, bus: &mut Bus) {
+        let address = self.background_pattern_address(bus);
+        self.low_tile_byte = bus.ppu_read(address);
+    }
+
+    pub fn fetch_high_tile_byte(&mut self, bus: &mut Bus) {
+        let address = self.background_pattern_address(bus) + 8;
+        self.high_tile_byte = bus.ppu_read(address);
+    }
+
+    /// Pack the fetched tile into the low 32 bits of the tile data as eight
+    /// 4-bit pixels: two attribute bits followed by two pattern bits.
+    pub fn store_tile_data(&mut self) {
+        let mut data: u32 = 0;
+        for _ in 0..8 {
+            let a = self.attribute_table_byte;
+            let p1 = (self.low_tile_byte & 0x80) >> 7;
+            let p2 = (self.high_tile_byte & 0x80) >> 6;
+            self.low_tile_byte <<= 1;
+            self.high_tile_byte <<= 1;
+            data <<= 4;
+            data |= (a | p1 | p2) as u32;
         }
+        self.tile_data |= data as u64;
     }
 
     pub fn fetch_tile_data(&self) -> u32 {
         (self.tile_data >> 32) as u32
     }
 
-    pub fn background_pixel(&mut self) -> u8 {
-        if self.show_background_flag == 0 {
+    //// Rendering ////
+
+    pub fn background_pixel(&mut self, bus: &Bus) -> u8 {
+        if !bus.ppu_mask.contains(Mask::SHOW_BACKGROUND) {
             return 0;
         }
-        let data = self.fetch_tile_data(); // FIXME >> ((7 - self.x) * 4)
+        let data = self.fetch_tile_data() >> ((7 - bus.ppu_x) * 4);
         (data & 0x0F) as u8
     }
 
-    pub fn sprite_pixel(&mut self) -> (u8, u8) {
-        if self.show_sprites_flag == 0 {
+    //// Sprites ////
+
+    pub fn sprite_height(&self, bus: &Bus) -> i32 {
+        if bus.ppu_ctrl.contains(Control::SPRITE_SIZE_16) {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Find the sprites on the next scan line and copy them to the
+    /// secondary OAM (the first eight sprite slots).
+    pub fn evaluate_sprites(&mut self, bus: &mut Bus) {
+        let height = self.sprite_height(bus);
+        let mut count = 0;
+        let mut n = 0;
+        while n < 64 && count < 8 {
+            let y = bus.ppu_oam[n * 4] as i32;
+            let row = self.scan_line as i32 - y;
+            if row >= 0 && row < height {
+                self.sprite_positions[count] = bus.ppu_oam[n * 4 + 3];
+                self.sprite_priorities[count] = (bus.ppu_oam[n * 4 + 2] >> 5) & 1;
+                self.sprite_indexes[count] = n as u8;
+                count += 1;
+            }
+            n += 1;
+        }
+        self.sprite_count = count as u32;
+
+        // Once eight sprites are found the hardware keeps looking for a
+        // ninth to set the overflow flag, but it increments the byte
+        // offset along with the sprite index. It ends up treating tile,
+        // attribute and X bytes as Y coordinates, which causes both false
+        // positives and false negatives.
+        // See https://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+        let mut m = 0;
+        while n < 64 {
+            let y = bus.ppu_oam[n * 4 + m] as i32;
+            let row = self.scan_line as i32 - y;
+            if row >= 0 && row < height {
+                bus.ppu_status.insert(Status::SPRITE_OVERFLOW);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 3;
+        }
+    }
+
+    /// Address of the given row of a sprite tile. Tile $FF is fetched for
+    /// empty slots, which keeps the pattern table bus activity identical to
+    /// the hardware.
+    pub fn sprite_pattern_address(&self, bus: &Bus, tile: u8, attributes: u8, row: i32) -> u16 {
+        let mut row = row;
+        if bus.ppu_ctrl.contains(Control::SPRITE_SIZE_16) {
+            if attributes & 0x80 == 0x80 {
+                row = 15 - row;
+            }
+            let table = (tile & 1) as u16 * 0x1000;
+            let mut tile = tile & 0xFE;
+            if row > 7 {
+                tile += 1;
+                row -= 8;
+            }
+            table + tile as u16 * 16 + row as u16
+        } else {
+            if attributes & 0x80 == 0x80 {
+                row = 7 - row;
+            }
+            let table = if bus.ppu_ctrl.contains(Control::SPRITE_TABLE) {
+                0x1000
+            } else {
+                0
+            };
+            table + tile as u16 * 16 + row as u16
+        }
+    }
+
+    /// Fetch the pattern of the sprite in the given secondary OAM slot and
+    /// pack it into eight 4-bit pixels, like the background tile data.
+    pub fn fetch_sprite_pattern(&mut self, bus: &mut Bus, slot: usize) {
+        if slot >= self.sprite_count as usize {
+            let address = self.sprite_pattern_address(bus, 0xFF, 0, 0);
+            bus.ppu_read(address);
+            bus.ppu_read(address + 8);
+            return;
+        }
+
+        let i = self.sprite_indexes[slot] as usize;
+        let tile = bus.ppu_oam[i * 4 + 1];
+        let attributes = bus.ppu_oam[i * 4 + 2];
+        let row = self.scan_line as i32 - bus.ppu_oam[i * 4] as i32;
+        let address = self.sprite_pattern_address(bus, tile, attributes, row);
+        let mut low_tile_byte = bus.ppu_read(address);
+        let mut high_tile_byte = bus.ppu_read(address + 8);
+
+        let a = (attributes & 3) << 2;
+        let mut data: u32 = 0;
+        for _ in 0..8 {
+            let p1;
+            let p2;
+            if attributes & 0x40 == 0x40 {
+                p1 = low_tile_byte & 1;
+                p2 = (high_tile_byte & 1) << 1;
+                low_tile_byte >>= 1;
+                high_tile_byte >>= 1;
+            } else {
+                p1 = (low_tile_byte & 0x80) >> 7;
+                p2 = (high_tile_byte & 0x80) >> 6;
+                low_tile_byte <<= 1;
+                high_tile_byte <<= 1;
+            }
+            data <<= 4;
+            data |= (a | p1 | p2) as u32;
+        }
+        self.sprite_patterns[slot] = data;
+    }
+
+    /// Returns the secondary OAM slot and color of the first opaque sprite
+    /// pixel at the current dot.
+    pub fn sprite_pixel(&mut self, bus: &Bus) -> (u8, u8) {
+        if !bus.ppu_mask.contains(Mask::SHOW_SPRITES) {
             return (0, 0);
         }
+        for i in 0..self.sprite_count as usize {
+            let offset = (self.cycle as i32 - 1) - self.sprite_positions[i] as i32;
+            if !(0..=7).contains(&offset) {
+                continue;
+            }
+            let offset = 7 - offset;
+            let color = ((self.sprite_patterns[i] >> (offset * 4) as u32) & 0x0F) as u8;
+            if color & 3 == 0 {
+                continue;
+            }
+            return (i as u8, color);
+        }
         (0, 0)
     }
 
-    pub fn read_palette(&self, address: u16) -> u8 {
-        let address = if address >= 16 && address % 4 == 0 {
-            address - 16
-        } else {
-            address
-        };
-        self.palette_data[address as usize]
+    pub fn read_palette(&self, bus: &Bus, address: u16) -> u8 {
+        bus.ppu_palette[palette_index(address)]
     }
 
     pub fn render_pixel(&mut self, bus: &mut Bus) {
         let x = self.cycle - 1;
         let y = self.scan_line;
-        let background = self.background_pixel();
-        let color = background;
-        let c = PALETTE[self.read_palette(color as u16 % 64) as usize];
-        println!("render_pixel {} {} = {}", x, y, c);
-        bus.ppu_pixels[((y * 256) + x) as usize] = c;
+        let mut background = self.background_pixel(bus);
+        let (i, mut sprite) = self.sprite_pixel(bus);
+        if x < 8 && !bus.ppu_mask.contains(Mask::SHOW_LEFT_BACKGROUND) {
+            background = 0;
+        }
+        if x < 8 && !bus.ppu_mask.contains(Mask::SHOW_LEFT_SPRITES) {
+            sprite = 0;
+        }
+        let b = background & 3 != 0;
+        let s = sprite & 3 != 0;
+        let color = match (b, s) {
+            // Transparent pixels show the universal background color.
+            (false, false) => 0,
+            (false, true) => sprite | 0x10,
+            (true, false) => background,
+            (true, true) => {
+                if self.sprite_indexes[i as usize] == 0 && x < 255 {
+                    bus.ppu_status.insert(Status::SPRITE_ZERO_HIT);
+                }
+                if self.sprite_priorities[i as usize] == 0 {
+                    sprite | 0x10
+                } else {
+                    background
+                }
+            }
+        };
+        let mut index = self.read_palette(bus, color as u16) % 64;
+        if bus.ppu_mask.contains(Mask::GRAYSCALE) {
+            index &= 0x30;
+        }
+        bus.ppu_pixels[((y * 256) + x) as usize] = PALETTE[index as usize];
     }
 
-    pub fn tick(&mut self) {
+    pub fn tick(&mut self, bus: &Bus) {
+        // The pre-render line is one dot shorter on odd frames when
+        // rendering is enabled.
+        if self.rendering_enabled(bus) && self.even_odd && self.scan_line == 261
+            && self.cycle == 339
+        {
+            self.cycle = 0;
+            self.scan_line = 0;
+            self.frame += 1;
+            self.even_odd = !self.even_odd;
+            return;
+        }
+
         self.cycle += 1;
         if self.cycle > 340 {
             self.cycle = 0;
@@ -116,14 +413,180 @@ impl PPU {
     }
 
     pub fn step(&mut self, bus: &mut Bus) {
-        self.tick();
+        self.tick(bus);
+        bus.ppu_scan_line = self.scan_line;
+        bus.ppu_cycle = self.cycle;
+        let rendering_enabled = self.rendering_enabled(bus);
         let pre_line = self.scan_line == 261;
         let visible_line = self.scan_line < 240;
         let render_line = pre_line || visible_line;
+        let pre_fetch_cycle = self.cycle >= 321 && self.cycle <= 336;
+        let visible_cycle = self.cycle >= 1 && self.cycle <= 256;
+        let fetch_cycle = pre_fetch_cycle || visible_cycle;
 
-        if visible_line {
-            // FIXME: render_pixel is currently not complete and will fail.
-            //self.render_pixel(bus);
+        // Background logic
+        if rendering_enabled {
+            if visible_line && visible_cycle {
+                self.render_pixel(bus);
+            }
+            if render_line && fetch_cycle {
+                self.tile_data <<= 4;
+                match self.cycle % 8 {
+                    1 => self.fetch_name_table_byte(bus),
+                    3 => self.fetch_attribute_table_byte(bus),
+                    5 => self.fetch_low_tile_byte(bus),
+                    7 => self.fetch_high_tile_byte(bus),
+                    0 => self.store_tile_data(),
+                    _ => {}
+                }
+            }
+            // Two unused name table fetches end the line. MMC5 watches for
+            // them to detect scan lines.
+            if render_line && (self.cycle == 337 || self.cycle == 339) {
+                self.fetch_name_table_byte(bus);
+            }
+            if pre_line && self.cycle >= 280 && self.cycle <= 304 {
+                self.copy_y(bus);
+            }
+            if render_line {
+                if fetch_cycle && self.cycle & 7 == 0 {
+                    self.increment_x(bus);
+                }
+                if self.cycle == 256 {
+                    self.increment_y(bus);
+                }
+                if self.cycle == 257 {
+                    self.copy_x(bus);
+                }
+            }
+        } else if visible_line && visible_cycle {
+            // With rendering disabled the PPU outputs the backdrop color.
+            let x = self.cycle - 1;
+            let index = self.read_palette(bus, 0) % 64;
+            bus.ppu_pixels[((self.scan_line * 256) + x) as usize] = PALETTE[index as usize];
+        }
+
+        // Sprite logic
+        if rendering_enabled && render_line {
+            if self.cycle == 257 {
+                if visible_line {
+                    self.evaluate_sprites(bus);
+                } else {
+                    self.sprite_count = 0;
+                }
+            }
+            if self.cycle >= 257 && self.cycle <= 320 {
+                // OAMADDR is cleared during sprite tile loading.
+                bus.ppu_oam_address = 0;
+                // Each slot starts with two garbage name table fetches.
+                match (self.cycle - 257) % 8 {
+                    0 | 2 => self.fetch_name_table_byte(bus),
+                    4 => {
+                        let slot = ((self.cycle - 257) / 8) as usize;
+                        self.fetch_sprite_pattern(bus, slot);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Vertical blank starts on the second dot of line 241 and ends on
+        // the second dot of the pre-render line.
+        if self.scan_line == 241 && self.cycle == 1 {
+            bus.ppu_status.insert(Status::VERTICAL_BLANK);
+            bus.update_nmi_line();
+        }
+        if pre_line && self.cycle == 1 {
+            bus.ppu_status.remove(
+                Status::VERTICAL_BLANK | Status::SPRITE_ZERO_HIT | Status::SPRITE_OVERFLOW,
+            );
+            bus.update_nmi_line();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::test_bus;
+    use cartridge::test_cartridge;
+    use mapper::new_mapper;
+
+    /// Bus with a solid tile 1 in CHR RAM, the background filled with the
+    /// given tile, and sprite 0 showing tile 1 at the given position.
+    fn sprite_zero_bus(background_tile: u8, x: u8, y: u8) -> Bus {
+        let cartridge = test_cartridge(0, 2, 0);
+        let mut bus = Bus::new(new_mapper(cartridge).unwrap(), vec![0; 2048]);
+        for address in 0x0010..0x0018 {
+            bus.ppu_write(address, 0xFF);
         }
+        for address in 0x2000..0x23C0 {
+            bus.ppu_write(address, background_tile);
+        }
+        bus.ppu_oam = [0xFF; 256];
+        bus.ppu_oam[..4].copy_from_slice(&[y, 0x01, 0x00, x]);
+        bus.ppu_mask = Mask::from_bits_truncate(0x1E);
+        bus
+    }
+
+    /// Run the PPU through the visible lines of the next frame.
+    fn render_frame(bus: &mut Bus) {
+        let mut ppu = PPU::new();
+        while ppu.frame == 0 || ppu.scan_line < 240 {
+            ppu.step(bus);
+        }
+    }
+
+    #[test]
+    fn it_detects_sprite_zero_hits() {
+        let mut bus = sprite_zero_bus(0x01, 40, 30);
+        render_frame(&mut bus);
+        assert!(bus.ppu_status.contains(Status::SPRITE_ZERO_HIT));
+
+        // Sprite 0 only hits opaque background pixels.
+        let mut bus = sprite_zero_bus(0x00, 40, 30);
+        render_frame(&mut bus);
+        assert!(!bus.ppu_status.contains(Status::SPRITE_ZERO_HIT));
+
+        // Nor does it hit at X=255.
+        let mut bus = sprite_zero_bus(0x01, 255, 30);
+        render_frame(&mut bus);
+        assert!(!bus.ppu_status.contains(Status::SPRITE_ZERO_HIT));
+
+        // Or in the leftmost 8 pixels when they are clipped.
+        let mut bus = sprite_zero_bus(0x01, 0, 30);
+        bus.ppu_mask.remove(Mask::SHOW_LEFT_SPRITES);
+        render_frame(&mut bus);
+        assert!(!bus.ppu_status.contains(Status::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn it_sets_sprite_overflow() {
+        let overflow = |sprites: &[(usize, usize, u8)]| {
+            let mut bus = test_bus();
+            bus.ppu_oam = [0xF0; 256];
+            for &(n, m, value) in sprites {
+                bus.ppu_oam[n * 4 + m] = value;
+            }
+            let mut ppu = PPU::new();
+            ppu.scan_line = 12;
+            ppu.evaluate_sprites(&mut bus);
+            assert_eq!(ppu.sprite_count, 8.min(sprites.len() as u32));
+            bus.ppu_status.contains(Status::SPRITE_OVERFLOW)
+        };
+        let eight: Vec<_> = (0..8).map(|n| (n, 0, 10)).collect();
+        assert!(!overflow(&eight));
+        let nine: Vec<_> = (0..9).map(|n| (n, 0, 10)).collect();
+        assert!(overflow(&nine));
+
+        // After eight sprites the hardware reads the tile byte of sprite
+        // 9 as a Y coordinate, so it misses sprite 9 on the line but sees
+        // a false one in its tile.
+        let mut missed = eight.clone();
+        missed.push((9, 0, 10));
+        assert!(!overflow(&missed));
+        let mut false_positive = eight.clone();
+        false_positive.push((9, 1, 10));
+        assert!(overflow(&false_positive));
     }
 }
@@ -0,0 +1,175 @@
+use device::{key_position, Buttons, Input};
+
+/// One change to the input.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Buttons(usize, Buttons),
+    Cursor(Option<(i32, i32)>),
+    LeftButton(bool),
+    RightButton(bool),
+    MouseMotion(i32, i32),
+    PowerPad(u16),
+    FamilyKeys([u8; 9]),
+    Microphone(bool),
+}
+
+/// Input for headless runs. Each line of a script is a frame number
+/// followed by changes that take effect at the start of that frame:
+///
+/// ```text
+/// # frame  changes
+/// 30       p1=start
+/// 32       p1=
+/// 60       p1=right,a cursor=128,100 left=1
+/// 61       motion=-12,0 powerpad=1,5,12
+/// 90       keys=lshift,a mic=1
+/// ```
+///
+/// `p1`-`p4` set the buttons held on a player's controller, `cursor` the
+/// mouse position in picture coordinates (or `none`), `left` and `right`
+/// the mouse buttons, `powerpad` and `keys` the Power Pad buttons and
+/// Family BASIC keyboard keys held, and `mic` the microphone. All of these
+/// stay until changed again. `motion` moves the mouse during that frame
+/// only.
+pub struct Script {
+    // Changes ordered by frame
+    pub changes: Vec<(u64, Change)>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut changes = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| format!("Line {}: {}", number + 1, message);
+            let mut words = line.split_whitespace();
+            let frame = words
+                .next()
+                .and_then(|word| word.parse().ok())
+                .ok_or_else(|| error("expected a frame number"))?;
+            for word in words {
+                let change =
+                    parse_change(word).ok_or_else(|| error(&format!("bad change {}", word)))?;
+                changes.push((frame, change));
+            }
+        }
+        // Keep changes to the same frame in the order they were written.
+        changes.sort_by_key(|&(frame, _)| frame);
+        Ok(Script { changes })
+    }
+
+    /// Update the input for the start of `frame`.
+    pub fn apply(&self, frame: u64, input: &mut Input) {
+        input.mouse_motion = (0, 0);
+        for (_, change) in self.changes.iter().filter(|&&(at, _)| at == frame) {
+            match *change {
+                Change::Buttons(player, buttons) => input.buttons[player] = buttons,
+                Change::Cursor(cursor) => input.cursor = cursor,
+                Change::LeftButton(pressed) => input.left_button = pressed,
+                Change::RightButton(pressed) => input.right_button = pressed,
+                Change::MouseMotion(dx, dy) => {
+                    input.mouse_motion.0 += dx;
+                    input.mouse_motion.1 += dy;
+                }
+                Change::PowerPad(buttons) => input.power_pad = buttons,
+                Change::FamilyKeys(keys) => input.family_keys = keys,
+                Change::Microphone(on) => input.microphone = on,
+            }
+        }
+    }
+}
+
+fn parse_change(word: &str) -> Option<Change> {
+    let (name, value) = word.split_once('=')?;
+    let list = || value.split(',').filter(|item| !item.is_empty());
+    let pair = || -> Option<(i32, i32)> {
+        let (x, y) = value.split_once(',')?;
+        Some((x.parse().ok()?, y.parse().ok()?))
+    };
+    match name {
+        "p1" | "p2" | "p3" | "p4" => {
+            let player = (name.as_bytes()[1] - b'1') as usize;
+            let mut buttons = Buttons::empty();
+            for button in list() {
+                buttons.insert(Buttons::from_name(button)?);
+            }
+            Some(Change::Buttons(player, buttons))
+        }
+        "cursor" if value == "none" => Some(Change::Cursor(None)),
+        "cursor" => Some(Change::Cursor(Some(pair()?))),
+        "left" => Some(Change::LeftButton(value == "1")),
+        "right" => Some(Change::RightButton(value == "1")),
+        "motion" => {
+            let (dx, dy) = pair()?;
+            Some(Change::MouseMotion(dx, dy))
+        }
+        "powerpad" => {
+            let mut buttons = 0;
+            for button in list() {
+                match button.parse::<u16>().ok()? {
+                    button @ 1..=12 => buttons |= 1 << (button - 1),
+                    _ => return None,
+                }
+            }
+            Some(Change::PowerPad(buttons))
+        }
+        "keys" => {
+            let mut keys = [0; 9];
+            for key in list() {
+                let (row, bit) = key_position(key)?;
+                keys[row] |= 1 << bit;
+            }
+            Some(Change::FamilyKeys(keys))
+        }
+        "mic" => Some(Change::Microphone(value == "1")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_applies_scripted_input() {
+        let script = Script::parse(
+            "# Start the game, then aim\n\
+             30 p1=start\n\
+             32 p1=   # release\n\
+             60 p2=right,a cursor=128,100 left=1\n\
+             61 motion=-12,0 powerpad=1,5,12\n\
+             62 keys=lshift,a mic=1\n",
+        ).unwrap();
+
+        let mut input = Input::default();
+        script.apply(30, &mut input);
+        assert_eq!(input.buttons[0], Buttons::START);
+        script.apply(31, &mut input);
+        assert_eq!(input.buttons[0], Buttons::START);
+        script.apply(32, &mut input);
+        assert_eq!(input.buttons[0], Buttons::empty());
+
+        script.apply(60, &mut input);
+        assert_eq!(input.buttons[1], Buttons::RIGHT | Buttons::A);
+        assert_eq!(input.cursor, Some((128, 100)));
+        assert!(input.left_button);
+        script.apply(61, &mut input);
+        assert_eq!(input.mouse_motion, (-12, 0));
+        assert_eq!(input.power_pad, 1 << 0 | 1 << 4 | 1 << 11);
+        // Motion only lasts a frame.
+        script.apply(62, &mut input);
+        assert_eq!(input.mouse_motion, (0, 0));
+        assert_eq!(input.power_pad, 1 << 0 | 1 << 4 | 1 << 11);
+        assert_eq!(input.family_keys[6], 1 << 0);
+        assert_eq!(input.family_keys[7], 1 << 7);
+        assert!(input.microphone);
+
+        assert!(Script::parse("soon p1=a").is_err());
+        assert!(Script::parse("10 p1=turbo").is_err());
+        assert!(Script::parse("10 powerpad=13").is_err());
+        assert!(Script::parse("10 keys=meta").is_err());
+    }
+}